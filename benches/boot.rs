@@ -0,0 +1,30 @@
+//! Boot-time throughput benchmark.
+//!
+//! Boots each firmware version and free-runs the CPU for a fixed
+//! number of instructions, reporting instructions per second. Run
+//! with `cargo bench`.
+
+use dmd_core::dmd::Dmd;
+
+use std::time::Instant;
+
+const STEPS: usize = 20_000_000;
+
+fn main() {
+    for version in 1..=2 {
+        let mut dmd = Dmd::new();
+        dmd.reset(version).expect("could not reset DMD");
+
+        let start = Instant::now();
+        dmd.run(STEPS);
+        let elapsed = start.elapsed();
+
+        println!(
+            "firmware v{}: {} instructions in {:.3}s ({:.0} instructions/sec)",
+            version,
+            STEPS,
+            elapsed.as_secs_f64(),
+            STEPS as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
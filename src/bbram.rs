@@ -0,0 +1,393 @@
+//! Battery-backed RAM, holding the terminal's setup options.
+//!
+//! The BBRAM occupies an 8K window of the address space, but the part
+//! is only eight bits wide and is wired to a single byte lane: only
+//! the byte at offset 2 of each word holds data, for 2048 bytes in
+//! all. Writes to the other lanes are lost, and reads from them see
+//! an undriven bus, which we return as zero.
+//!
+//! The contents are kept as an image of the whole 8K window, which is
+//! the layout exchanged with the host by `get_nvram` and `set_nvram`.
+//! The image may be bound to a file, which is loaded immediately and
+//! then rewritten shortly after the terminal modifies the BBRAM, and
+//! again when the BBRAM is dropped if changes are still unsaved. The
+//! file is replaced atomically, so that a crash never leaves a
+//! partially written image behind.
+
+use crate::bus::{AccessCode, Device};
+use crate::err::BusError;
+use crate::sched::{Event, Scheduler};
+
+use log::{debug, error};
+use std::fmt::Debug;
+use std::fmt::Error;
+use std::fmt::Formatter;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const START_ADDR: usize = 0x600000;
+const END_ADDR: usize = 0x602000;
+const ADDRESS_RANGE: Range<usize> = START_ADDR..END_ADDR;
+
+pub const BBRAM_SIZE: usize = END_ADDR - START_ADDR;
+
+/// The byte lane, within each word, that the part is wired to.
+pub const DATA_LANE: usize = 2;
+
+/// Emulated time to wait after the first unsaved change before
+/// writing the image back to its file. The firmware updates the
+/// setup options and their checksum in quick bursts, so this saves
+/// rewriting the file for every byte.
+const FLUSH_DELAY: u64 = 100_000_000;
+
+pub struct Bbram {
+    image: Vec<u8>,
+    dirty: bool,
+    path: Option<PathBuf>,
+    flush_at: Option<u64>,
+}
+
+impl Default for Bbram {
+    fn default() -> Self {
+        Bbram::new()
+    }
+}
+
+impl Bbram {
+    pub fn new() -> Bbram {
+        Bbram {
+            image: vec![0; BBRAM_SIZE],
+            dirty: false,
+            path: None,
+            flush_at: None,
+        }
+    }
+
+    /// The 8K image of the BBRAM window.
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// Replace the contents of the BBRAM with an image of the 8K
+    /// window. Images shorter than 8K replace only the start of the
+    /// window.
+    pub fn set_image(&mut self, image: &[u8]) {
+        let len = image.len().min(BBRAM_SIZE);
+        if self.image[..len] != image[..len] {
+            self.image[..len].copy_from_slice(&image[..len]);
+            self.dirty = true;
+        }
+    }
+
+    /// True if the contents have changed since they were last loaded
+    /// or flushed.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Bind the BBRAM to a file. If the file exists, its image is
+    /// loaded, and must be exactly 8K long. Otherwise, the file is
+    /// created from the current contents.
+    pub fn bind<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref().to_path_buf();
+
+        match fs::read(&path) {
+            Ok(image) => {
+                if image.len() != BBRAM_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("BBRAM image must be {} bytes, found {}", BBRAM_SIZE, image.len()),
+                    ));
+                }
+                self.image.copy_from_slice(&image);
+                self.path = Some(path);
+                self.dirty = false;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.path = Some(path);
+                self.flush()?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        self.flush_at = None;
+
+        Ok(())
+    }
+
+    /// Write the image to the bound file, if there is one. The image
+    /// is written to a temporary file alongside it, which is then
+    /// renamed over the original.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(&self.image)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+
+        debug!("BBRAM flushed to {}", path.display());
+
+        self.dirty = false;
+        self.flush_at = None;
+
+        Ok(())
+    }
+
+    /// Register the time at which unsaved changes will be flushed.
+    pub fn schedule(&mut self, scheduler: &mut Scheduler) {
+        if self.dirty && self.path.is_some() {
+            let at = *self.flush_at.get_or_insert(scheduler.now() + FLUSH_DELAY);
+            scheduler.schedule(Event::BbramFlush, at);
+        } else {
+            self.flush_at = None;
+            scheduler.cancel(Event::BbramFlush);
+        }
+    }
+
+    /// Handle a scheduled event that has come due.
+    pub fn service(&mut self, event: Event) {
+        if event == Event::BbramFlush {
+            if let Err(e) = self.flush() {
+                // Leave the image dirty, so that the flush is retried
+                // after another delay.
+                error!("Unable to flush BBRAM: {}", e);
+                self.flush_at = None;
+            }
+        }
+    }
+
+    fn offset(address: usize) -> usize {
+        address - START_ADDR
+    }
+
+    fn read_lane(&self, offset: usize) -> u8 {
+        if offset % 4 == DATA_LANE {
+            self.image[offset]
+        } else {
+            0
+        }
+    }
+
+    fn write_lane(&mut self, offset: usize, val: u8) {
+        if offset % 4 == DATA_LANE && self.image[offset] != val {
+            self.image[offset] = val;
+            self.dirty = true;
+        }
+    }
+}
+
+// Changes made within the flush delay of the terminal being dropped
+// would otherwise never reach the file.
+impl Drop for Bbram {
+    fn drop(&mut self) {
+        if self.dirty {
+            if let Err(e) = self.flush() {
+                error!("Unable to flush BBRAM: {}", e);
+            }
+        }
+    }
+}
+
+impl Debug for Bbram {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "[BBRAM]")
+    }
+}
+
+impl Device for Bbram {
+    fn address_range(&self) -> &Range<usize> {
+        &ADDRESS_RANGE
+    }
+
+    fn name(&self) -> &str {
+        "BBRAM"
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read_byte(&mut self, address: usize, _access: AccessCode) -> Result<u8, BusError> {
+        Ok(self.read_lane(Bbram::offset(address)))
+    }
+
+    fn read_half(&mut self, address: usize, _access: AccessCode) -> Result<u16, BusError> {
+        let offset = Bbram::offset(address);
+        Ok(u16::from(self.read_lane(offset)) << 8 | u16::from(self.read_lane(offset + 1)))
+    }
+
+    fn read_word(&mut self, address: usize, _access: AccessCode) -> Result<u32, BusError> {
+        let offset = Bbram::offset(address);
+        Ok((0..4).fold(0, |word, i| word << 8 | u32::from(self.read_lane(offset + i))))
+    }
+
+    fn write_byte(&mut self, address: usize, val: u8, _access: AccessCode) -> Result<(), BusError> {
+        self.write_lane(Bbram::offset(address), val);
+        Ok(())
+    }
+
+    fn write_half(
+        &mut self,
+        address: usize,
+        val: u16,
+        _access: AccessCode,
+    ) -> Result<(), BusError> {
+        let offset = Bbram::offset(address);
+        self.write_lane(offset, (val >> 8) as u8);
+        self.write_lane(offset + 1, val as u8);
+        Ok(())
+    }
+
+    fn write_word(
+        &mut self,
+        address: usize,
+        val: u32,
+        _access: AccessCode,
+    ) -> Result<(), BusError> {
+        let offset = Bbram::offset(address);
+        for (i, b) in val.to_be_bytes().iter().enumerate() {
+            self.write_lane(offset + i, *b);
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, address: usize, data: &[u8]) -> Result<(), BusError> {
+        let offset = Bbram::offset(address);
+        match self.image.get_mut(offset..offset + data.len()) {
+            Some(dest) => {
+                dest.copy_from_slice(data);
+                self.dirty = true;
+                Ok(())
+            }
+            None => Err(BusError::Range),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("dmd_bbram_{}_{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn stores_only_the_data_lane() {
+        let mut bbram = Bbram::new();
+
+        bbram.write_word(0x600000, 0x11223344, AccessCode::Write).unwrap();
+        bbram.write_byte(0x600005, 0x55, AccessCode::Write).unwrap();
+        bbram.write_byte(0x600006, 0x66, AccessCode::Write).unwrap();
+
+        assert_eq!(0x00003300, bbram.read_word(0x600000, AccessCode::AddressFetch).unwrap());
+        assert_eq!(0x3300, bbram.read_half(0x600002, AccessCode::AddressFetch).unwrap());
+        assert_eq!(0, bbram.read_byte(0x600005, AccessCode::AddressFetch).unwrap());
+        assert_eq!(0x66, bbram.read_byte(0x600006, AccessCode::AddressFetch).unwrap());
+        assert_eq!(&[0, 0, 0x33, 0, 0, 0, 0x66, 0], &bbram.image()[0..8]);
+    }
+
+    #[test]
+    fn tracks_changes() {
+        let mut bbram = Bbram::new();
+
+        bbram.write_byte(0x600001, 0x5a, AccessCode::Write).unwrap();
+        bbram.write_byte(0x600002, 0x00, AccessCode::Write).unwrap();
+        assert!(!bbram.is_dirty());
+
+        bbram.write_byte(0x600002, 0x5a, AccessCode::Write).unwrap();
+        assert!(bbram.is_dirty());
+
+        bbram.dirty = false;
+        let unchanged = bbram.image().to_vec();
+        bbram.set_image(&unchanged);
+        assert!(!bbram.is_dirty());
+    }
+
+    #[test]
+    fn creates_and_loads_bound_files() {
+        let path = temp_path("create");
+
+        let mut bbram = Bbram::new();
+        bbram.write_byte(0x600012, 0xa5, AccessCode::Write).unwrap();
+        bbram.bind(&path).unwrap();
+        assert!(!bbram.is_dirty());
+        assert_eq!(BBRAM_SIZE, fs::metadata(&path).unwrap().len() as usize);
+
+        let mut other = Bbram::new();
+        other.bind(&path).unwrap();
+        assert_eq!(0xa5, other.read_byte(0x600012, AccessCode::AddressFetch).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_images_of_the_wrong_size() {
+        let path = temp_path("short");
+        fs::write(&path, [0u8; 100]).unwrap();
+
+        let mut bbram = Bbram::new();
+        assert!(bbram.bind(&path).is_err());
+        assert!(bbram.path.is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flushes_changes_after_a_delay() {
+        let path = temp_path("flush");
+        let mut scheduler = Scheduler::new();
+        let mut bbram = Bbram::new();
+        bbram.bind(&path).unwrap();
+
+        bbram.write_byte(0x601ffe, 0x42, AccessCode::Write).unwrap();
+        bbram.schedule(&mut scheduler);
+        assert!(!scheduler.advance(FLUSH_DELAY - 1));
+
+        // Further changes do not postpone the flush
+        bbram.write_byte(0x601ffa, 0x24, AccessCode::Write).unwrap();
+        bbram.schedule(&mut scheduler);
+        assert!(scheduler.advance(1));
+        assert_eq!(Some(Event::BbramFlush), scheduler.pop_due());
+
+        bbram.service(Event::BbramFlush);
+        assert!(!bbram.is_dirty());
+
+        let image = fs::read(&path).unwrap();
+        assert_eq!(0x42, image[0x1ffe]);
+        assert_eq!(0x24, image[0x1ffa]);
+        assert!(!path
+            .with_file_name(format!("dmd_bbram_{}_flush.tmp", std::process::id()))
+            .exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flushes_changes_when_dropped() {
+        let path = temp_path("drop");
+        let mut bbram = Bbram::new();
+        bbram.bind(&path).unwrap();
+        bbram.write_byte(0x600002, 0x42, AccessCode::Write).unwrap();
+        drop(bbram);
+
+        assert_eq!(0x42, fs::read(&path).unwrap()[2]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
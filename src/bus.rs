@@ -1,14 +1,28 @@
 #![allow(clippy::unreadable_literal)]
 
+use crate::bbram::Bbram;
+use crate::display::{
+    region_hash, screen_hash, DirtyTiles, Display, FramePublisher, FrameReader, Polarity, Rect,
+    FRAMEBUFFER_LEN,
+};
 use crate::duart::Duart;
 use crate::err::BusError;
+use crate::input::{Input, InputQueue, TimedInput};
+use crate::keyboard::{Key, Keyboard, KeyboardEvent, Leds, Modifiers};
 use crate::mem::Mem;
-use crate::mouse::Mouse;
+use crate::mouse::{Acceleration, Mouse, MouseEvent};
+use crate::printer::Printer;
+use crate::recorder::{Recorder, VideoFormat};
+use crate::scc::Scc;
+use crate::sched::{Event, Scheduler, INSTRUCTION_NS};
+use crate::session::{Session, SessionEvent, SessionLog, SessionWriter};
 
 use std::fmt::Debug;
+use std::io;
+use std::mem;
 use std::ops::Range;
-
-const NVRAM_SIZE: usize = 8192;
+use std::path::Path;
+use std::sync::Arc;
 
 /// Access Status Code
 pub enum AccessCode {
@@ -56,163 +70,909 @@ pub trait Device: Send + Sync + Debug {
 //  0x700000..0x7fffff     RAM (256K or 1M)
 //
 
+// Devices are located through a page table with one entry per 4KB
+// page, so that finding the device for an address is a single
+// lookup rather than a chain of range comparisons.
+const PAGE_SHIFT: usize = 12;
+const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+const PAGE_COUNT: usize = 0x800000 >> PAGE_SHIFT;
+
+const ROM_RANGE: Range<usize> = 0..0x20000;
+const DUART_RANGE: Range<usize> = 0x200000..0x200040;
+const SCC_RANGE: Range<usize> = 0x300000..0x300100;
+const MOUSE_RANGE: Range<usize> = 0x400000..0x400004;
+const DISPLAY_RANGE: Range<usize> = 0x500000..0x500002;
+const BBRAM_RANGE: Range<usize> = 0x600000..0x602000;
+const RAM_RANGE: Range<usize> = 0x700000..0x800000;
+
+/// Identifies which device occupies a page of the address space.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Slot {
+    Rom,
+    Duart,
+    Scc,
+    Mouse,
+    Display,
+    Bbram,
+    Ram,
+}
+
+impl Slot {
+    fn range(self) -> Range<usize> {
+        match self {
+            Slot::Rom => ROM_RANGE,
+            Slot::Duart => DUART_RANGE,
+            Slot::Scc => SCC_RANGE,
+            Slot::Mouse => MOUSE_RANGE,
+            Slot::Display => DISPLAY_RANGE,
+            Slot::Bbram => BBRAM_RANGE,
+            Slot::Ram => RAM_RANGE,
+        }
+    }
+}
+
+// The SCC is on an optional I/O board, and is not mapped until the
+// board is installed.
+const SLOTS: [Slot; 6] =
+    [Slot::Rom, Slot::Duart, Slot::Mouse, Slot::Display, Slot::Bbram, Slot::Ram];
+
+/// The state of the devices and memory, as saved in a snapshot. What
+/// belongs to the host, such as output waiting to be taken, the
+/// recorder, and the NVRAM's file, is left out. RAM is held a page at
+/// a time, and pages not written between two snapshots are shared by
+/// them rather than copied.
+#[derive(Clone)]
+pub struct BusState {
+    duart: Duart,
+    scc: Scc,
+    mouse: Mouse,
+    keyboard: Keyboard,
+    display: Display,
+    nvram: Vec<u8>,
+    ram: Vec<Arc<[u8]>>,
+    pages: [Option<Slot>; PAGE_COUNT],
+    scheduler: Scheduler,
+    interrupt: Option<u8>,
+    framebuffer: Range<usize>,
+}
+
+/// The devices replaced by a restored state, holding the output that
+/// was waiting to be taken when it was restored.
+pub struct PendingOutput {
+    duart: Duart,
+    scc: Scc,
+    keyboard: Keyboard,
+}
+
 pub struct Bus {
     rom: Mem,
     duart: Duart,
+    scc: Scc,
     mouse: Mouse,
-    vid: Mem,   // TODO: Figure out what device this really is
-    bbram: Mem, // TODO: change to BBRAM when implemented
+    keyboard: Keyboard,
+    display: Display,
+    bbram: Bbram,
     ram: Mem,
+    pages: [Option<Slot>; PAGE_COUNT],
+    // Bumped whenever a page is written, so that cached decoded
+    // instructions can tell when their bytes have changed.
+    page_generations: [u32; PAGE_COUNT],
+    scheduler: Scheduler,
+    interrupt: Option<u8>,
+    // Absolute addresses of the framebuffer, as last set by the
+    // display controller.
+    framebuffer: Range<usize>,
     video_ram_dirty: bool,
+    dirty_tiles: DirtyTiles,
+    // Set when the framebuffer changes, and cleared when a frame is
+    // published at vertical blank.
+    frame_dirty: bool,
+    frames: FramePublisher,
+    recorder: Option<Recorder>,
+    printer: Option<Printer>,
+    inputs: InputQueue,
+    session: Option<SessionLog>,
+    // Every input applied, while time travel is enabled.
+    input_log: Option<Vec<TimedInput>>,
+    // Set while re-executing after a state is restored, to keep what
+    // has already happened from reaching the host again.
+    quiet: bool,
+    // The RAM pages in the last state saved or restored, each with its
+    // write generation at the time.
+    saved_ram: Vec<(u32, Arc<[u8]>)>,
 }
 
 impl Bus {
     pub fn new(mem_size: usize) -> Bus {
-        Bus {
+        Bus::with_frame_publisher(mem_size, FramePublisher::new())
+    }
+
+    /// Create a bus that publishes its frames through `frames`, so that
+    /// readers can be handed out before the bus exists.
+    pub fn with_frame_publisher(mem_size: usize, frames: FramePublisher) -> Bus {
+        let mut bus = Bus {
             rom: Mem::new(0, 0x20000, true),
             duart: Duart::new(),
+            scc: Scc::new(),
             mouse: Mouse::new(),
-            vid: Mem::new(0x500000, 0x2, false),
-            bbram: Mem::new(0x600000, 0x2000, false),
+            keyboard: Keyboard::new(),
+            display: Display::new(),
+            bbram: Bbram::new(),
             ram: Mem::new(0x700000, mem_size, false),
+            pages: [None; PAGE_COUNT],
+            page_generations: [0; PAGE_COUNT],
+            scheduler: Scheduler::new(),
+            interrupt: None,
+            framebuffer: RAM_RANGE.start..RAM_RANGE.start + FRAMEBUFFER_LEN.min(mem_size),
             video_ram_dirty: false,
+            dirty_tiles: DirtyTiles::new(),
+            frame_dirty: true,
+            frames,
+            recorder: None,
+            printer: None,
+            inputs: InputQueue::new(),
+            session: None,
+            input_log: None,
+            quiet: false,
+            saved_ram: Vec::new(),
+        };
+
+        for slot in SLOTS.iter() {
+            bus.map(*slot, true);
         }
+
+        bus.sync_devices();
+
+        bus
     }
 
-    fn get_device(&mut self, address: usize) -> Result<&mut dyn Device, BusError> {
-        if address < 0x20000 {
-            return Ok(&mut self.rom);
+    /// Map or unmap the pages occupied by a device.
+    fn map(&mut self, slot: Slot, present: bool) {
+        let range = slot.range();
+        let first = range.start >> PAGE_SHIFT;
+        let last = (range.end - 1) >> PAGE_SHIFT;
+        for page in &mut self.pages[first..=last] {
+            *page = if present {
+                Some(slot)
+            } else {
+                None
+            };
         }
+    }
 
-        if (0x200000..0x200040).contains(&address) {
-            return Ok(&mut self.duart);
+    /// Install or remove the optional I/O board. The firmware probes
+    /// for the board at boot, so this should be set before reset.
+    pub fn set_io_board(&mut self, present: bool) {
+        self.map(Slot::Scc, present);
+        self.sync_devices();
+    }
+
+    pub fn io_board(&self) -> bool {
+        self.pages[SCC_RANGE.start >> PAGE_SHIFT].is_some()
+    }
+
+    /// Look up the device occupying the page that holds an address.
+    #[inline]
+    fn slot(&self, address: usize) -> Option<Slot> {
+        self.pages.get(address >> PAGE_SHIFT).copied().flatten()
+    }
+
+    /// Return the write generation of the page holding an address, or
+    /// `None` if the page is not ROM or RAM. Instructions decoded from
+    /// a page remain valid for as long as its generation is unchanged.
+    pub fn code_generation(&self, address: usize) -> Option<u32> {
+        match self.slot(address) {
+            Some(Slot::Rom) | Some(Slot::Ram) => Some(self.page_generations[address >> PAGE_SHIFT]),
+            _ => None,
         }
+    }
 
-        if (0x400000..0x400004).contains(&address) {
-            return Ok(&mut self.mouse);
+    fn touch_page(&mut self, page: usize) {
+        self.page_generations[page] = self.page_generations[page].wrapping_add(1);
+    }
+
+    fn get_device(&mut self, address: usize) -> Result<&mut dyn Device, BusError> {
+        let slot = match self.slot(address) {
+            Some(slot) if slot.range().contains(&address) => slot,
+            _ => return Err(BusError::NoDevice(address)),
+        };
+
+        match slot {
+            Slot::Rom => Ok(&mut self.rom),
+            Slot::Duart => Ok(&mut self.duart),
+            Slot::Scc => Ok(&mut self.scc),
+            Slot::Mouse => Ok(&mut self.mouse),
+            Slot::Display => Ok(&mut self.display),
+            Slot::Bbram => Ok(&mut self.bbram),
+            Slot::Ram => Ok(&mut self.ram),
         }
+    }
+
+    /// Perform an access on a device other than ROM or RAM, then give
+    /// the devices a chance to update their scheduled events and
+    /// interrupts, since the access may have changed either.
+    fn device_access<T, F>(&mut self, address: usize, f: F) -> Result<T, BusError>
+    where
+        F: FnOnce(&mut dyn Device) -> Result<T, BusError>,
+    {
+        let result = f(self.get_device(address)?);
+        self.sync_devices();
+        result
+    }
 
-        if (0x500000..0x500002).contains(&address) {
-            return Ok(&mut self.vid);
+    fn sync_devices(&mut self) {
+        self.sync_framebuffer();
+        self.deliver_mouse_event();
+        self.sync_keyboard();
+        self.sync_printer();
+        match self.inputs.next_due() {
+            Some(at) => self.scheduler.schedule(Event::Input, at),
+            None => self.scheduler.cancel(Event::Input),
         }
+        self.duart.schedule(&mut self.scheduler);
+        self.scc.schedule(&mut self.scheduler);
+        self.bbram.schedule(&mut self.scheduler);
 
-        if (0x600000..0x602000).contains(&address) {
-            return Ok(&mut self.bbram);
+        let scc_interrupt = if self.io_board() {
+            self.scc.get_interrupt()
+        } else {
+            None
+        };
+
+        self.interrupt = match (self.duart.get_interrupt(), scc_interrupt) {
+            (Some(a), Some(b)) => Some(a | b),
+            (a, b) => a.or(b),
+        };
+    }
+
+    /// Deliver the next queued mouse event, once the firmware has seen
+    /// the last one.
+    fn deliver_mouse_event(&mut self) {
+        let button_seen = self.duart.mouse_button_seen() || self.mouse.stalled();
+        if !self.mouse.motion_seen() || !button_seen {
+            return;
         }
+        match self.mouse.next_event() {
+            Some(MouseEvent::Down(button)) => self.duart.mouse_button(button, true),
+            Some(MouseEvent::Up(button)) => self.duart.mouse_button(button, false),
+            _ => {}
+        }
+    }
 
-        if (0x700000..0x800000).contains(&address) {
-            return Ok(&mut self.ram);
+    /// Pass commands from the firmware to the keyboard, and what the
+    /// keyboard sends back to the firmware.
+    fn sync_keyboard(&mut self) {
+        while let Some(command) = self.duart.keyboard_command() {
+            self.keyboard.command(command);
         }
+        while let Some((c, status)) = self.keyboard.next_sent() {
+            if status {
+                self.duart.keyboard_status(c);
+            } else {
+                self.duart.keyboard_rx(c);
+            }
+        }
+    }
 
-        Err(BusError::NoDevice(address))
+    /// Pass printer output to the capture file, if there is one.
+    /// Otherwise it is left for `printer_tx`.
+    fn sync_printer(&mut self) {
+        if self.quiet {
+            return;
+        }
+        if let Some(printer) = &mut self.printer {
+            while let Some(c) = self.duart.printer_tx() {
+                printer.print(c);
+            }
+        }
     }
 
-    fn video_ram_range(&self) -> Range<usize> {
-        let vid_register = (u16::from(self.vid[0]) << 8 | u16::from(self.vid[1])) as usize;
-        let start = vid_register * 4;
-        let end = start + 0x19000;
-        start..end
+    /// Follow the display controller if it has moved the framebuffer.
+    /// The whole of the display has changed when it moves.
+    fn sync_framebuffer(&mut self) {
+        let ram = self.ram.address_range().clone();
+        let framebuffer = self.display.framebuffer();
+        let start = (ram.start + framebuffer.start).min(ram.end);
+        let end = (ram.start + framebuffer.end).min(ram.end);
+
+        if self.framebuffer != (start..end) {
+            self.framebuffer = start..end;
+            self.video_ram_dirty = true;
+            self.frame_dirty = true;
+            self.dirty_tiles.mark_all();
+        }
     }
 
-    fn is_video_ram(&self, address: usize) -> bool {
-        (0x700000..0x800000).contains(&address)
-            && self.video_ram_range().contains(&(address - 0x700000))
+    /// Note a write to RAM, if it falls in the framebuffer.
+    #[inline]
+    fn mark_video_ram(&mut self, address: usize) {
+        if self.framebuffer.contains(&address) {
+            self.video_ram_dirty = true;
+            self.frame_dirty = true;
+            self.dirty_tiles.mark(address - self.framebuffer.start);
+        }
     }
 
     pub fn read_byte(&mut self, address: usize, access: AccessCode) -> Result<u8, BusError> {
-        self.get_device(address)?.read_byte(address, access)
+        match self.slot(address) {
+            Some(Slot::Rom) => self.rom.read_byte(address, access),
+            Some(Slot::Ram) => self.ram.read_byte(address, access),
+            _ => self.device_access(address, |d| d.read_byte(address, access)),
+        }
     }
 
     pub fn read_half(&mut self, address: usize, access: AccessCode) -> Result<u16, BusError> {
         if address & 1 != 0 {
             return Err(BusError::Alignment(address));
         }
-        self.get_device(address)?.read_half(address, access)
+        match self.slot(address) {
+            Some(Slot::Rom) => self.rom.read_half(address, access),
+            Some(Slot::Ram) => self.ram.read_half(address, access),
+            _ => self.device_access(address, |d| d.read_half(address, access)),
+        }
     }
 
     pub fn read_word(&mut self, address: usize, access: AccessCode) -> Result<u32, BusError> {
         if address & 3 != 0 {
             return Err(BusError::Alignment(address));
         }
-        self.get_device(address)?.read_word(address, access)
+        match self.slot(address) {
+            Some(Slot::Rom) => self.rom.read_word(address, access),
+            Some(Slot::Ram) => self.ram.read_word(address, access),
+            _ => self.device_access(address, |d| d.read_word(address, access)),
+        }
     }
 
     pub fn read_op_half(&mut self, address: usize) -> Result<u16, BusError> {
-        let m = self.get_device(address)?;
+        match self.slot(address) {
+            Some(Slot::Rom) => return self.rom.read_op_half(address),
+            Some(Slot::Ram) => return self.ram.read_op_half(address),
+            _ => {}
+        }
 
-        Ok(u16::from(m.read_byte(address, AccessCode::OperandFetch)?)
-            | u16::from(m.read_byte(address + 1, AccessCode::OperandFetch)?).wrapping_shl(8))
+        self.device_access(address, |m| {
+            Ok(u16::from(m.read_byte(address, AccessCode::OperandFetch)?)
+                | u16::from(m.read_byte(address + 1, AccessCode::OperandFetch)?).wrapping_shl(8))
+        })
     }
 
     pub fn read_op_word(&mut self, address: usize) -> Result<u32, BusError> {
-        let m = self.get_device(address)?;
+        match self.slot(address) {
+            Some(Slot::Rom) => return self.rom.read_op_word(address),
+            Some(Slot::Ram) => return self.ram.read_op_word(address),
+            _ => {}
+        }
 
-        Ok(u32::from(m.read_byte(address, AccessCode::OperandFetch)?)
-            | u32::from(m.read_byte(address + 1, AccessCode::OperandFetch)?).wrapping_shl(8)
-            | u32::from(m.read_byte(address + 2, AccessCode::OperandFetch)?).wrapping_shl(16)
-            | u32::from(m.read_byte(address + 3, AccessCode::OperandFetch)?).wrapping_shl(24))
+        self.device_access(address, |m| {
+            Ok(u32::from(m.read_byte(address, AccessCode::OperandFetch)?)
+                | u32::from(m.read_byte(address + 1, AccessCode::OperandFetch)?).wrapping_shl(8)
+                | u32::from(m.read_byte(address + 2, AccessCode::OperandFetch)?).wrapping_shl(16)
+                | u32::from(m.read_byte(address + 3, AccessCode::OperandFetch)?).wrapping_shl(24))
+        })
     }
 
     pub fn write_byte(&mut self, address: usize, val: u8) -> Result<(), BusError> {
-        if self.is_video_ram(address) {
-            self.video_ram_dirty = true;
+        match self.slot(address) {
+            Some(Slot::Ram) => {
+                self.mark_video_ram(address);
+                self.touch_page(address >> PAGE_SHIFT);
+                self.ram.write_byte(address, val, AccessCode::Write)
+            }
+            _ => self.device_access(address, |d| d.write_byte(address, val, AccessCode::Write)),
         }
-        self.get_device(address)?.write_byte(address, val, AccessCode::Write)
     }
 
     pub fn write_half(&mut self, address: usize, val: u16) -> Result<(), BusError> {
         if address & 1 != 0 {
             return Err(BusError::Alignment(address));
         }
-        if self.is_video_ram(address) {
-            self.video_ram_dirty = true;
+        match self.slot(address) {
+            Some(Slot::Ram) => {
+                self.mark_video_ram(address);
+                self.touch_page(address >> PAGE_SHIFT);
+                self.ram.write_half(address, val, AccessCode::Write)
+            }
+            _ => self.device_access(address, |d| d.write_half(address, val, AccessCode::Write)),
         }
-        self.get_device(address)?.write_half(address, val, AccessCode::Write)
     }
 
     pub fn write_word(&mut self, address: usize, val: u32) -> Result<(), BusError> {
         if address & 3 != 0 {
             return Err(BusError::Alignment(address));
         }
-        if self.is_video_ram(address) {
-            self.video_ram_dirty = true;
+        match self.slot(address) {
+            Some(Slot::Ram) => {
+                self.mark_video_ram(address);
+                self.touch_page(address >> PAGE_SHIFT);
+                self.ram.write_word(address, val, AccessCode::Write)
+            }
+            _ => self.device_access(address, |d| d.write_word(address, val, AccessCode::Write)),
         }
-        self.get_device(address)?.write_word(address, val, AccessCode::Write)
     }
 
     pub fn load(&mut self, address: usize, data: &[u8]) -> Result<(), BusError> {
-        self.get_device(address)?.load(address, data)
+        self.get_device(address)?.load(address, data)?;
+        if !data.is_empty() {
+            let last = (address + data.len() - 1) >> PAGE_SHIFT;
+            for page in (address >> PAGE_SHIFT)..=last.min(PAGE_COUNT - 1) {
+                self.touch_page(page);
+            }
+        }
+        Ok(())
     }
 
     pub fn video_ram(&mut self) -> &[u8] {
         self.video_ram_dirty = false;
-        let range = self.video_ram_range();
-        self.ram.as_slice(range)
+        let ram_start = self.ram.address_range().start;
+        self.ram.as_slice(self.framebuffer.start - ram_start..self.framebuffer.end - ram_start)
     }
 
     pub fn video_ram_dirty(&self) -> bool {
         self.video_ram_dirty
     }
 
+    /// Publish a copy of the framebuffer, if it or the video inversion
+    /// has changed since the last one, and pass it to the recorder.
+    fn publish_frame(&mut self) {
+        let dirty = self.frame_dirty;
+        let inverted = self.video_inverted();
+        let now = self.scheduler.now();
+        let ram_start = self.ram.address_range().start;
+        let framebuffer = self.framebuffer.start - ram_start..self.framebuffer.end - ram_start;
+
+        if dirty || inverted != self.frames.inverted() {
+            self.frame_dirty = false;
+            self.frames.publish(self.ram.as_slice(framebuffer.clone()), inverted);
+            if let Some(session) = &mut self.session {
+                let hash = screen_hash(self.ram.as_slice(framebuffer.clone()), inverted);
+                session.log(now, SessionEvent::Frame(hash));
+            }
+        }
+
+        if self.quiet {
+            return;
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.vblank(self.ram.as_slice(framebuffer), inverted, dirty, now);
+        }
+    }
+
+    /// Start recording the display to a file. Any recording already
+    /// in progress is finished first.
+    pub fn start_recording(&mut self, path: &Path, format: VideoFormat) -> io::Result<()> {
+        self.stop_recording()?;
+        let inverted = self.video_inverted();
+        let now = self.scheduler.now();
+        let ram_start = self.ram.address_range().start;
+        let framebuffer = self.framebuffer.start - ram_start..self.framebuffer.end - ram_start;
+        let recorder =
+            Recorder::create(path, format, self.ram.as_slice(framebuffer), inverted, now)?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    /// Finish the recording in progress, if there is one.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(self.scheduler.now()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// A handle on the frames published at each vertical blank.
+    pub fn frame_reader(&self) -> FrameReader {
+        self.frames.reader()
+    }
+
+    /// Return the parts of the screen written since the last call.
+    pub fn dirty_rects(&mut self) -> Vec<Rect> {
+        self.dirty_tiles.take_rects()
+    }
+
+    /// If the display has moved since the last call, return the new
+    /// offset of the framebuffer from the start of RAM.
+    pub fn display_moved(&mut self) -> Option<usize> {
+        self.display.take_moved()
+    }
+
+    /// Advance emulated time by one instruction, and service any
+    /// device events that have come due.
+    #[inline]
     pub fn service(&mut self) {
-        self.duart.service();
+        if self.scheduler.advance(INSTRUCTION_NS) {
+            let now = self.scheduler.now();
+            while let Some(event) = self.scheduler.pop_due() {
+                match event {
+                    Event::SccTxA | Event::SccRxA | Event::SccTxB | Event::SccRxB => {
+                        self.scc.service(event, now)
+                    }
+                    Event::BbramFlush => self.bbram.service(event),
+                    Event::Input => {
+                        while let Some(queued) = self.inputs.pop_due(now) {
+                            // Logged as a direct input, which an input
+                            // queued for a time already passed was
+                            // when the last instruction finished.
+                            let at = queued.at.max(now - INSTRUCTION_NS);
+                            self.input_at(at, queued.input);
+                        }
+                    }
+                    Event::VerticalBlank => {
+                        self.duart.service(event, now);
+                        self.mouse.vertical_blank();
+                        self.publish_frame();
+                    }
+                    _ => {
+                        if let Some(c) = self.duart.service(event, now) {
+                            if let Some(session) = &mut self.session {
+                                session.log(now, SessionEvent::Rs232Tx(c));
+                            }
+                        }
+                    }
+                }
+            }
+            self.sync_devices();
+        }
+    }
+
+    /// The current emulated time, in nanoseconds since reset.
+    pub fn now(&self) -> u64 {
+        self.scheduler.now()
+    }
+
+    /// Queue an input to be applied at the given emulated time.
+    pub fn queue_input(&mut self, at: u64, input: Input) {
+        self.inputs.push(at, input);
+        self.sync_devices();
+    }
+
+    /// The number of queued inputs not yet applied.
+    pub fn inputs_pending(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /// Drop all queued inputs not yet applied.
+    pub fn clear_inputs(&mut self) {
+        self.inputs.clear();
+        self.sync_devices();
+    }
+
+    /// Pass an input to its device, as if made at the given time, and
+    /// log it if a session is being recorded. Mouse motion is logged
+    /// as the counters it produced, since how far relative motion goes
+    /// depends on the acceleration. Returns false if the input did
+    /// nothing, as for a key with no code.
+    fn input_at(&mut self, at: u64, input: Input) -> bool {
+        let applied = match input {
+            Input::Rs232Rx(c) => {
+                self.duart.rs232_rx(c);
+                true
+            }
+            Input::KeyboardRx(keycode) => {
+                self.duart.keyboard_rx(keycode);
+                true
+            }
+            Input::KeyPress(key, modifiers) => self.keyboard.press(key, modifiers),
+            Input::CapsLock(on) => {
+                self.keyboard.set_caps_lock(on);
+                true
+            }
+            Input::MouseMove(x, y) => {
+                self.mouse.set_counters(x, y);
+                true
+            }
+            Input::MouseMoveTo(x, y) => {
+                self.mouse.move_to(x, y);
+                true
+            }
+            Input::MouseMoveBy(dx, dy) => {
+                self.mouse.move_by(dx, dy);
+                true
+            }
+            Input::MouseDown(button) => {
+                self.mouse.press(button);
+                true
+            }
+            Input::MouseUp(button) => {
+                self.mouse.release(button);
+                true
+            }
+            Input::SccRx(channel, c) => {
+                self.scc.rx(usize::from(channel & 1), c);
+                true
+            }
+        };
+
+        let input = match input {
+            Input::MouseMoveTo(..) | Input::MouseMoveBy(..) => {
+                let (x, y) = self.mouse.target();
+                Input::MouseMove(x, y)
+            }
+            _ => input,
+        };
+        if let Some(session) = &mut self.session {
+            session.log(at, SessionEvent::Input(input));
+        }
+        if let Some(log) = &mut self.input_log {
+            log.push(TimedInput {
+                at,
+                input,
+            });
+        }
+
+        self.sync_devices();
+        applied
+    }
+
+    /// Apply an input straight away.
+    fn input_now(&mut self, input: Input) -> bool {
+        self.input_at(self.scheduler.now(), input)
+    }
+
+    /// Start logging a session: every input, and what the terminal
+    /// does in response. The log is kept in memory, or written to the
+    /// file at `path` as the session runs. Any session already being
+    /// logged is dropped.
+    pub fn start_session(&mut self, version: u8, path: Option<&Path>) -> io::Result<()> {
+        let session = Session::new(version, self.io_board(), self.bbram.image());
+        self.session = Some(match path {
+            Some(path) => SessionLog::File(SessionWriter::create(path, &session)?),
+            None => SessionLog::Memory(session),
+        });
+        Ok(())
     }
 
-    pub fn get_interrupts(&mut self) -> Option<u8> {
-        self.duart.get_interrupt()
+    /// Throw away what has been logged of the session in progress, if
+    /// there is one, and start its log again from here.
+    pub fn restart_session(&mut self, version: u8) {
+        let session = Session::new(version, self.io_board(), self.bbram.image());
+        match &mut self.session {
+            Some(SessionLog::Memory(memory)) => *memory = session,
+            Some(SessionLog::File(writer)) => writer.restart(&session),
+            None => {}
+        }
+    }
+
+    /// Finish logging the session, if there is one. A session kept in
+    /// memory is returned. One written to a file is finished there,
+    /// reporting the first error met while writing it.
+    pub fn stop_session(&mut self) -> io::Result<Option<Session>> {
+        let end = self.scheduler.now();
+        let screen = self.screen_hash();
+        match self.session.take() {
+            Some(SessionLog::Memory(mut session)) => {
+                session.end = end;
+                session.screen = screen;
+                Ok(Some(session))
+            }
+            Some(SessionLog::File(writer)) => writer.finish(end, screen).map(|_| None),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_logging_session(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Start or stop logging every input applied, so that they can be
+    /// applied again after a state is restored.
+    pub fn set_input_log(&mut self, enabled: bool) {
+        self.input_log = if enabled {
+            Some(Vec::new())
+        } else {
+            None
+        };
+    }
+
+    /// The number of inputs in the log.
+    pub fn input_log_len(&self) -> usize {
+        self.input_log.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Drop the oldest `count` inputs from the log.
+    pub fn trim_input_log(&mut self, count: usize) {
+        if let Some(log) = &mut self.input_log {
+            log.drain(..count.min(log.len()));
+        }
+    }
+
+    /// Return the devices, memory and emulated time to how they were
+    /// at power-on, dropping any queued inputs. What belongs to the
+    /// host is kept: output waiting to be taken, the NVRAM, the mouse
+    /// acceleration, the I/O board, and any capture in progress.
+    pub fn power_on(&mut self) {
+        let mut duart = mem::take(&mut self.duart);
+        self.duart.swap_output(&mut duart);
+        let mut scc = mem::take(&mut self.scc);
+        self.scc.swap_output(&mut scc);
+        let mut keyboard = mem::take(&mut self.keyboard);
+        self.keyboard.swap_events(&mut keyboard);
+        let acceleration = self.mouse.acceleration;
+        self.mouse = Mouse::new();
+        self.mouse.acceleration = acceleration;
+        self.display = Display::new();
+
+        let ram = self.ram.address_range().clone();
+        self.ram = Mem::new(ram.start, ram.len(), false);
+        for page in ram.start >> PAGE_SHIFT..=(ram.end - 1) >> PAGE_SHIFT {
+            self.touch_page(page);
+        }
+        self.framebuffer = ram.start..ram.start + FRAMEBUFFER_LEN.min(ram.len());
+
+        // A pending NVRAM flush was timed by the old clock, so write
+        // out unsaved changes now.
+        if self.bbram.is_dirty() {
+            self.bbram.service(Event::BbramFlush);
+        }
+        self.scheduler = Scheduler::new();
+        self.interrupt = None;
+        self.inputs.clear();
+
+        self.video_ram_dirty = true;
+        self.frame_dirty = true;
+        self.dirty_tiles.mark_all();
+        self.sync_devices();
+    }
+
+    /// The pages of the address space RAM occupies.
+    fn ram_pages(&self) -> Range<usize> {
+        let ram = self.ram.address_range();
+        ram.start >> PAGE_SHIFT..(ram.end + PAGE_SIZE - 1) >> PAGE_SHIFT
+    }
+
+    /// Save the state of the devices and memory. Only the RAM pages
+    /// written since the last state saved or restored are copied.
+    pub fn save_state(&mut self) -> BusState {
+        let ram_len = self.ram.address_range().len();
+        let saved = mem::take(&mut self.saved_ram);
+        self.saved_ram = self
+            .ram_pages()
+            .enumerate()
+            .map(|(i, page)| {
+                let generation = self.page_generations[page];
+                match saved.get(i) {
+                    Some((saved, data)) if *saved == generation => (generation, Arc::clone(data)),
+                    _ => {
+                        let start = i << PAGE_SHIFT;
+                        let data = self.ram.as_slice(start..(start + PAGE_SIZE).min(ram_len));
+                        (generation, Arc::from(data))
+                    }
+                }
+            })
+            .collect();
+
+        let mut duart = self.duart.clone();
+        duart.swap_output(&mut Duart::new());
+        let mut scc = self.scc.clone();
+        scc.swap_output(&mut Scc::new());
+        let mut keyboard = self.keyboard.clone();
+        keyboard.swap_events(&mut Keyboard::new());
+        BusState {
+            duart,
+            scc,
+            mouse: self.mouse.clone(),
+            keyboard,
+            display: self.display.clone(),
+            nvram: self.bbram.image().to_vec(),
+            ram: self.saved_ram.iter().map(|(_, data)| Arc::clone(data)).collect(),
+            pages: self.pages,
+            scheduler: self.scheduler.clone(),
+            interrupt: self.interrupt,
+            framebuffer: self.framebuffer.clone(),
+        }
+    }
+
+    /// Restore a saved state. The inputs in the log from `replay_from`
+    /// on are taken out of it and queued to be applied again, ahead of
+    /// any inputs already queued. Everything from here on is quiet
+    /// until `finish_restore`, which must be given the output returned.
+    pub fn restore_state(&mut self, state: &BusState, replay_from: usize) -> PendingOutput {
+        let pending = PendingOutput {
+            duart: mem::replace(&mut self.duart, state.duart.clone()),
+            scc: mem::replace(&mut self.scc, state.scc.clone()),
+            keyboard: mem::replace(&mut self.keyboard, state.keyboard.clone()),
+        };
+        let acceleration = self.mouse.acceleration;
+        self.mouse = state.mouse.clone();
+        self.mouse.acceleration = acceleration;
+        self.display = state.display.clone();
+        self.bbram.set_image(&state.nvram);
+        for (i, (page, data)) in self.ram_pages().zip(state.ram.iter()).enumerate() {
+            let start = i << PAGE_SHIFT;
+            self.ram.as_mut_slice(start..start + data.len()).copy_from_slice(data);
+            self.touch_page(page);
+        }
+        self.saved_ram = self
+            .ram_pages()
+            .zip(state.ram.iter())
+            .map(|(page, data)| (self.page_generations[page], Arc::clone(data)))
+            .collect();
+        self.pages = state.pages;
+        self.scheduler = state.scheduler.clone();
+        self.interrupt = state.interrupt;
+        self.framebuffer = state.framebuffer.clone();
+
+        let mut inputs = match &mut self.input_log {
+            Some(log) => log.split_off(replay_from.min(log.len())),
+            None => Vec::new(),
+        };
+        inputs.extend(self.inputs.drain());
+        for input in inputs {
+            self.inputs.push(input.at, input.input);
+        }
+
+        self.quiet = true;
+        self.sync_devices();
+        pending
+    }
+
+    /// Finish restoring a state, once any re-execution is done. Output
+    /// made since the state was restored has already been made once,
+    /// and is dropped in favour of what was waiting before, and the
+    /// whole screen is taken as changed.
+    pub fn finish_restore(&mut self, mut pending: PendingOutput) {
+        self.duart.swap_output(&mut pending.duart);
+        self.scc.swap_output(&mut pending.scc);
+        self.keyboard.swap_events(&mut pending.keyboard);
+        self.quiet = false;
+        self.video_ram_dirty = true;
+        self.frame_dirty = true;
+        self.dirty_tiles.mark_all();
+        self.sync_devices();
+    }
+
+    /// A hash of the screen as it appears now.
+    pub fn screen_hash(&self) -> u32 {
+        let ram_start = self.ram.address_range().start;
+        let framebuffer = self.framebuffer.start - ram_start..self.framebuffer.end - ram_start;
+        screen_hash(self.ram.as_slice(framebuffer), self.video_inverted())
+    }
+
+    /// A hash of a region of the screen as it appears now.
+    pub fn region_hash(&self, region: &Rect) -> u32 {
+        let ram_start = self.ram.address_range().start;
+        let framebuffer = self.framebuffer.start - ram_start..self.framebuffer.end - ram_start;
+        region_hash(self.ram.as_slice(framebuffer), self.video_inverted(), region)
+    }
+
+    pub fn get_interrupts(&self) -> Option<u8> {
+        self.interrupt
     }
 
     pub fn mouse_move(&mut self, x: u16, y: u16) {
-        self.mouse.x = x;
-        self.mouse.y = y;
+        self.input_now(Input::MouseMove(x, y));
+    }
+
+    pub fn mouse_move_to(&mut self, x: i32, y: i32) {
+        self.input_now(Input::MouseMoveTo(x, y));
+    }
+
+    pub fn mouse_move_by(&mut self, dx: i32, dy: i32) {
+        self.input_now(Input::MouseMoveBy(dx, dy));
+    }
+
+    pub fn mouse_position(&self) -> (u16, u16) {
+        self.mouse.position()
+    }
+
+    pub fn set_mouse_acceleration(&mut self, acceleration: Acceleration) {
+        self.mouse.acceleration = acceleration;
     }
 
     pub fn mouse_down(&mut self, button: u8) {
-        self.duart.mouse_down(button);
+        self.input_now(Input::MouseDown(button));
     }
 
     pub fn mouse_up(&mut self, button: u8) {
-        self.duart.mouse_up(button);
+        self.input_now(Input::MouseUp(button));
+    }
+
+    /// The number of mouse events waiting to be delivered.
+    pub fn mouse_events_pending(&self) -> usize {
+        self.mouse.pending()
     }
 
     pub fn rs232_tx(&mut self) -> Option<u8> {
@@ -223,26 +983,100 @@ impl Bus {
         self.duart.keyboard_tx()
     }
 
+    pub fn printer_tx(&mut self) -> Option<u8> {
+        self.duart.printer_tx()
+    }
+
+    /// Start capturing printer output to a file. Any capture already
+    /// in progress is finished first.
+    pub fn start_printer_capture(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_printer_capture()?;
+        self.printer = Some(Printer::create(path)?);
+        self.sync_printer();
+        Ok(())
+    }
+
+    /// Finish the printer capture in progress, if there is one.
+    pub fn stop_printer_capture(&mut self) -> io::Result<()> {
+        match self.printer.take() {
+            Some(printer) => printer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_capturing_printer(&self) -> bool {
+        self.printer.is_some()
+    }
+
     pub fn rs232_rx(&mut self, c: u8) {
-        self.duart.rs232_rx(c);
+        self.input_now(Input::Rs232Rx(c));
     }
 
     pub fn keyboard_rx(&mut self, keycode: u8) {
-        self.duart.keyboard_rx(keycode);
+        self.input_now(Input::KeyboardRx(keycode));
+    }
+
+    pub fn key_press(&mut self, key: Key, modifiers: Modifiers) -> bool {
+        self.input_now(Input::KeyPress(key, modifiers))
+    }
+
+    pub fn set_caps_lock(&mut self, on: bool) {
+        self.input_now(Input::CapsLock(on));
+    }
+
+    pub fn keyboard_leds(&self) -> Leds {
+        self.keyboard.leds()
+    }
+
+    pub fn keyboard_event(&mut self) -> Option<KeyboardEvent> {
+        self.keyboard.next_event()
+    }
+
+    pub fn scc_tx(&mut self, channel: usize) -> Option<u8> {
+        self.scc.tx(channel)
+    }
+
+    pub fn scc_rx(&mut self, channel: usize, c: u8) {
+        self.input_now(Input::SccRx(channel as u8, c));
     }
 
     pub fn duart_output(&self) -> u8 {
         self.duart.output_port()
     }
 
+    /// The display polarity selected by the DUART output port.
+    pub fn polarity(&self) -> Polarity {
+        Polarity::from_output_port(self.duart_output())
+    }
+
+    /// True if the display is showing inverse video.
+    pub fn video_inverted(&self) -> bool {
+        self.polarity().is_inverse()
+    }
+
     pub fn get_nvram(&self) -> &[u8] {
-        self.bbram.as_slice(0..NVRAM_SIZE)
+        self.bbram.image()
     }
 
     pub fn set_nvram(&mut self, nvram: &[u8]) {
-        for (i, b) in nvram.iter().enumerate() {
-            self.bbram[i] = *b;
-        }
+        self.bbram.set_image(nvram);
+        self.sync_devices();
+    }
+
+    pub fn nvram_dirty(&self) -> bool {
+        self.bbram.is_dirty()
+    }
+
+    pub fn bind_nvram(&mut self, path: &Path) -> io::Result<()> {
+        let result = self.bbram.bind(path);
+        self.sync_devices();
+        result
+    }
+
+    pub fn flush_nvram(&mut self) -> io::Result<()> {
+        let result = self.bbram.flush();
+        self.sync_devices();
+        result
     }
 }
 
@@ -264,4 +1098,354 @@ mod tests {
         assert!(bus.write_word(0x700003, 0x1f1f1f1f).is_err());
         assert!(bus.write_word(0x700004, 0x1f1f1f1f).is_ok());
     }
+
+    #[test]
+    fn should_fail_on_unmapped_addresses() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        assert!(matches!(
+            bus.read_byte(0x100000, AccessCode::AddressFetch),
+            Err(BusError::NoDevice(0x100000))
+        ));
+        assert!(matches!(
+            bus.read_byte(0x200040, AccessCode::AddressFetch),
+            Err(BusError::NoDevice(0x200040))
+        ));
+        assert!(matches!(bus.write_byte(0x400004, 0), Err(BusError::NoDevice(0x400004))));
+        assert!(matches!(bus.write_half(0x500002, 0), Err(BusError::NoDevice(0x500002))));
+        assert!(matches!(bus.write_word(0x800000, 0), Err(BusError::NoDevice(0x800000))));
+    }
+
+    #[test]
+    fn reads_operands_little_endian() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.load(0x700000, &[0x11, 0x22, 0x33, 0x44]).unwrap();
+
+        assert_eq!(0x2211, bus.read_op_half(0x700000).unwrap());
+        assert_eq!(0x3322, bus.read_op_half(0x700001).unwrap());
+        assert_eq!(0x44332211, bus.read_op_word(0x700000).unwrap());
+        assert_eq!(0x11223344, bus.read_word(0x700000, AccessCode::AddressFetch).unwrap());
+    }
+
+    #[test]
+    fn reads_operands_past_end_of_memory_are_range_errors() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        assert!(bus.read_op_word(0x70fffc).is_ok());
+        assert!(matches!(bus.read_op_word(0x70fffd), Err(BusError::Range)));
+        assert!(matches!(bus.read_op_half(0x70ffff), Err(BusError::Range)));
+    }
+
+    #[test]
+    fn raises_vertical_blank_interrupt_when_due() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        assert_eq!(None, bus.get_interrupts());
+
+        // 60Hz, at one microsecond per instruction
+        for _ in 0..16666 {
+            bus.service();
+        }
+        assert_eq!(None, bus.get_interrupts());
+
+        bus.service();
+        assert_eq!(Some(0x02), bus.get_interrupts());
+    }
+
+    #[test]
+    fn receives_queued_characters_when_due() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.write_byte(0x20000b, 0x01).unwrap(); // Enable RX on port A
+        bus.rs232_rx(0x41);
+        assert_eq!(None, bus.get_interrupts());
+
+        bus.service();
+        assert_eq!(Some(0x20), bus.get_interrupts());
+        assert_eq!(0x41, bus.read_byte(0x20000f, AccessCode::AddressFetch).unwrap());
+    }
+
+    #[test]
+    fn applies_queued_input_when_due() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.write_byte(0x20000b, 0x01).unwrap(); // Enable RX on port A
+        bus.queue_input(5_000, Input::Rs232Rx(0x41));
+        for _ in 0..5 {
+            bus.service();
+        }
+        assert_eq!(None, bus.get_interrupts());
+        assert_eq!(1, bus.inputs_pending());
+
+        // Just as if it had been received now, at 5us.
+        bus.service();
+        assert_eq!(0, bus.inputs_pending());
+        assert_eq!(Some(0x20), bus.get_interrupts());
+        assert_eq!(0x41, bus.read_byte(0x20000f, AccessCode::AddressFetch).unwrap());
+    }
+
+    #[test]
+    fn transmits_characters_after_character_delay() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.write_byte(0x20000b, 0x04).unwrap(); // Enable TX on port A
+        bus.write_byte(0x20000f, 0x42).unwrap();
+
+        // Moved into the shift register on the next instruction, then
+        // shifted out one character time (1ms by default) later.
+        bus.service();
+        for _ in 0..999 {
+            bus.service();
+            assert_eq!(None, bus.rs232_tx());
+        }
+        bus.service();
+        assert_eq!(Some(0x42), bus.rs232_tx());
+    }
+
+    /// Send a character on port B and wait for it to be shifted out.
+    fn send_on_port_b(bus: &mut Bus, c: u8) {
+        bus.write_byte(0x20002f, c).unwrap();
+        for _ in 0..1001 {
+            bus.service();
+        }
+    }
+
+    #[test]
+    fn routes_port_b_to_the_printer_while_op3_is_low() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.write_byte(0x20002b, 0x04).unwrap(); // Enable TX on port B
+        send_on_port_b(&mut bus, 0x10);
+        bus.write_byte(0x20003b, 0x08).unwrap(); // Set OP3 low
+        send_on_port_b(&mut bus, 0x50);
+        send_on_port_b(&mut bus, 0x52);
+        bus.write_byte(0x20003f, 0x08).unwrap(); // Set OP3 high
+        send_on_port_b(&mut bus, 0x08);
+
+        assert_eq!(Some(0x10), bus.keyboard_tx());
+        assert_eq!(Some(0x08), bus.keyboard_tx());
+        assert_eq!(None, bus.keyboard_tx());
+        assert_eq!(Some(0x50), bus.printer_tx());
+        assert_eq!(Some(0x52), bus.printer_tx());
+        assert_eq!(None, bus.printer_tx());
+    }
+
+    #[test]
+    fn captures_printer_output_to_a_file() {
+        let path = std::env::temp_dir().join(format!("dmd_printer_{}", std::process::id()));
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.write_byte(0x20002b, 0x04).unwrap(); // Enable TX on port B
+        bus.write_byte(0x20003b, 0x08).unwrap(); // Set OP3 low
+        send_on_port_b(&mut bus, b'h');
+        bus.start_printer_capture(&path).unwrap();
+        send_on_port_b(&mut bus, b'i');
+        bus.stop_printer_capture().unwrap();
+        send_on_port_b(&mut bus, b'!');
+
+        assert_eq!(b"hi".to_vec(), std::fs::read(&path).unwrap());
+        assert_eq!(Some(b'!'), bus.printer_tx());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Handle an input port change as the firmware does, returning
+    /// the changes flagged and the buttons held.
+    fn handle_port_change(bus: &mut Bus) -> (u8, u8) {
+        let ipcr = bus.read_byte(0x200013, AccessCode::AddressFetch).unwrap();
+        let ip = bus.read_byte(0x200037, AccessCode::AddressFetch).unwrap();
+        (ipcr & 0xb0, !ip & 0x0b)
+    }
+
+    #[test]
+    fn delivers_rapid_clicks_one_change_at_a_time() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.mouse_down(0);
+        bus.mouse_up(0);
+        bus.mouse_down(0);
+        bus.mouse_up(0);
+        assert_eq!(Some(0x02), bus.get_interrupts());
+        assert_eq!(3, bus.mouse_events_pending());
+
+        assert_eq!((0x80, 0x08), handle_port_change(&mut bus));
+        assert_eq!(Some(0x02), bus.get_interrupts());
+        assert_eq!((0x80, 0x00), handle_port_change(&mut bus));
+        assert_eq!((0x80, 0x08), handle_port_change(&mut bus));
+        assert_eq!((0x80, 0x00), handle_port_change(&mut bus));
+        assert_eq!(None, bus.get_interrupts());
+        assert_eq!((0x00, 0x00), handle_port_change(&mut bus));
+    }
+
+    #[test]
+    fn delivers_chords() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.mouse_down(0);
+        bus.mouse_down(2);
+        bus.mouse_up(0);
+        bus.mouse_down(1);
+        bus.mouse_up(2);
+        bus.mouse_up(1);
+
+        let changes: Vec<(u8, u8)> = (0..6).map(|_| handle_port_change(&mut bus)).collect();
+        assert_eq!(
+            vec![
+                (0x80, 0x08),
+                (0x10, 0x09),
+                (0x80, 0x01),
+                (0x20, 0x03),
+                (0x10, 0x02),
+                (0x20, 0x00)
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn delivers_clicks_after_the_motion_before_them() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        bus.mouse_move_to(100, 1000);
+        bus.mouse_down(0);
+        bus.mouse_move_to(200, 900);
+        bus.mouse_up(0);
+
+        // The click waits until the firmware has read the motion
+        assert_eq!((0x00, 0x00), handle_port_change(&mut bus));
+        assert_eq!(0x00170064, bus.read_word(0x400000, AccessCode::AddressFetch).unwrap());
+        assert_eq!((0x80, 0x08), handle_port_change(&mut bus));
+        assert_eq!((0x00, 0x08), handle_port_change(&mut bus));
+        assert_eq!(0x007b00c8, bus.read_word(0x400000, AccessCode::AddressFetch).unwrap());
+        assert_eq!((0x80, 0x00), handle_port_change(&mut bus));
+        assert_eq!(0, bus.mouse_events_pending());
+    }
+
+    #[test]
+    fn maps_scc_only_when_io_board_is_installed() {
+        let mut bus: Bus = Bus::new(0x10000);
+
+        // The firmware's probe: a bus error means no board
+        assert!(matches!(
+            bus.read_byte(0x300033, AccessCode::AddressFetch),
+            Err(BusError::NoDevice(0x300033))
+        ));
+
+        bus.set_io_board(true);
+        assert_eq!(0, bus.read_byte(0x300033, AccessCode::AddressFetch).unwrap() & 0xc0);
+
+        bus.set_io_board(false);
+        assert!(bus.read_byte(0x300033, AccessCode::AddressFetch).is_err());
+    }
+
+    #[test]
+    fn raises_scc_interrupt_on_receive() {
+        let mut bus: Bus = Bus::new(0x10000);
+        bus.set_io_board(true);
+
+        bus.write_byte(0x30000b, 0x09).unwrap(); // WR9: Master Interrupt Enable
+        bus.write_byte(0x30000b, 0x08).unwrap();
+        bus.write_byte(0x30000b, 0x01).unwrap(); // WR1: Rx Int On All Characters
+        bus.write_byte(0x30000b, 0x10).unwrap();
+        bus.write_byte(0x30000b, 0x03).unwrap(); // WR3: Rx Enable
+        bus.write_byte(0x30000b, 0x01).unwrap();
+
+        bus.scc_rx(0, 0x61);
+        assert_eq!(None, bus.get_interrupts());
+
+        bus.service();
+        assert_eq!(Some(0x08), bus.get_interrupts());
+        assert_eq!(0x61, bus.read_byte(0x30000f, AccessCode::AddressFetch).unwrap());
+        assert_eq!(None, bus.get_interrupts());
+    }
+
+    #[test]
+    fn follows_the_display_when_it_moves() {
+        let mut bus: Bus = Bus::new(0x100000);
+
+        bus.video_ram();
+        bus.write_byte(0x719000, 0xff).unwrap();
+        assert!(!bus.video_ram_dirty());
+
+        bus.write_half(0x500000, 0x6400).unwrap();
+        assert!(bus.video_ram_dirty());
+        assert_eq!(Some(0x19000), bus.display_moved());
+        assert_eq!(0xff, bus.video_ram()[0]);
+
+        bus.write_byte(0x731fff, 0xff).unwrap();
+        assert!(bus.video_ram_dirty());
+    }
+
+    #[test]
+    fn tracks_dirty_rectangles() {
+        let mut bus: Bus = Bus::new(0x100000);
+
+        assert!(bus.dirty_rects().is_empty());
+
+        // Outside the framebuffer
+        bus.write_word(0x720000, 0xffffffff).unwrap();
+        assert!(bus.dirty_rects().is_empty());
+
+        // Scanline 1023, last word
+        bus.write_word(0x718ffc, 0xffffffff).unwrap();
+        assert_eq!(
+            vec![Rect {
+                x: 768,
+                y: 1008,
+                width: 32,
+                height: 16
+            }],
+            bus.dirty_rects()
+        );
+        assert!(bus.dirty_rects().is_empty());
+    }
+
+    #[test]
+    fn publishes_frames_at_vertical_blank() {
+        let mut bus: Bus = Bus::new(0x100000);
+        let reader = bus.frame_reader();
+
+        bus.write_byte(0x700000, 0x81).unwrap();
+        for _ in 0..16666 {
+            bus.service();
+        }
+        assert_eq!(0, reader.latest().sequence);
+
+        bus.service();
+        assert_eq!(1, reader.latest().sequence);
+        assert_eq!(0x81, reader.latest().data[0]);
+
+        // Nothing is published when nothing has changed
+        for _ in 0..16667 {
+            bus.service();
+        }
+        assert_eq!(1, reader.latest().sequence);
+    }
+
+    #[test]
+    fn shares_unwritten_pages_between_states() {
+        let mut bus: Bus = Bus::new(0x10000);
+        bus.write_byte(0x20000b, 0x04).unwrap(); // Enable TX on port A
+        bus.write_byte(0x20000f, b'!').unwrap();
+        for _ in 0..1001 {
+            bus.service();
+        }
+
+        bus.write_byte(0x700000, 0x5a).unwrap();
+        let first = bus.save_state();
+        bus.write_byte(0x703000, 0xa5).unwrap();
+        let second = bus.save_state();
+        assert_eq!(16, second.ram.len());
+        let shared = first.ram.iter().zip(second.ram.iter()).filter(|(a, b)| Arc::ptr_eq(a, b));
+        assert_eq!(15, shared.count());
+        // Output waiting for the host is not part of the state
+        assert_eq!(None, first.duart.clone().rs232_tx());
+
+        let pending = bus.restore_state(&first, 0);
+        bus.finish_restore(pending);
+        assert_eq!(0x5a, bus.read_byte(0x700000, AccessCode::AddressFetch).unwrap());
+        assert_eq!(0, bus.read_byte(0x703000, AccessCode::AddressFetch).unwrap());
+        assert_eq!(Some(b'!'), bus.rs232_tx());
+        assert_eq!(None, bus.rs232_tx());
+    }
 }
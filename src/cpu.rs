@@ -49,6 +49,7 @@ const IPL_TABLE: [u32; 64] = [
 
 const WE32100_VERSION: u32 = 0x1a;
 const HALFWORD_MNEMONIC_COUNT: usize = 11;
+const DECODE_CACHE_SIZE: usize = 4096;
 
 pub enum ExceptionType {
     ExternalMemory,
@@ -175,7 +176,7 @@ struct Mnemonic {
     ops: [OpType; 4],
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Instruction {
     pub opcode: u16,
     pub name: &'static str,
@@ -626,6 +627,24 @@ static HALFWORD_MNEMONICS: [Option<Mnemonic>; HALFWORD_MNEMONIC_COUNT] = [
 
 static NULL_MNEMONIC: Option<Mnemonic> = None;
 
+/// A previously decoded instruction, tagged with its address and the
+/// write generations of the first and last pages it was decoded from.
+#[derive(Clone)]
+struct DecodedInstruction {
+    pc: u32,
+    first_generation: u32,
+    last_generation: u32,
+    ir: Instruction,
+}
+
+/// The processor state that carries from one instruction to the
+/// next, as saved in a snapshot.
+#[derive(Clone, Debug)]
+pub struct CpuState {
+    r: [u32; 16],
+    error_context: ErrorContext,
+}
+
 pub struct Cpu {
     //
     // Note that we store registers as an array of type u32 because
@@ -635,6 +654,7 @@ pub struct Cpu {
     pub r: [u32; 16],
     error_context: ErrorContext,
     ir: Instruction,
+    decode_cache: Vec<Option<DecodedInstruction>>,
 }
 
 impl Default for Cpu {
@@ -661,6 +681,24 @@ impl Cpu {
                     Operand::new(0, AddrMode::None, Data::None, None, None, 0),
                 ],
             },
+            decode_cache: vec![None; DECODE_CACHE_SIZE],
+        }
+    }
+
+    pub fn save_state(&self) -> CpuState {
+        CpuState {
+            r: self.r,
+            error_context: self.error_context,
+        }
+    }
+
+    /// Restore a saved state. Memory may have changed under the cached
+    /// decoded instructions, so they are dropped.
+    pub fn restore_state(&mut self, state: &CpuState) {
+        self.r = state.r;
+        self.error_context = state.error_context;
+        for cached in self.decode_cache.iter_mut() {
+            *cached = None;
         }
     }
 
@@ -1042,7 +1080,7 @@ impl Cpu {
             }
         }
 
-        self.decode_instruction(bus)?;
+        self.fetch_instruction(bus)?;
         let mut pc_increment: i32 = i32::from(self.ir.len);
 
         match self.ir.opcode {
@@ -2438,6 +2476,45 @@ impl Cpu {
         }
     }
 
+    /// Load the instruction currently pointed at by the Program Counter
+    /// into the instruction register.
+    ///
+    /// Decoded instructions from ROM and RAM are cached by address. A
+    /// cached decoding is reused for as long as the pages it was read
+    /// from have not been written to, which makes ROM entries permanent
+    /// and drops RAM entries as soon as their code is modified.
+    fn fetch_instruction(&mut self, bus: &mut Bus) -> Result<(), CpuError> {
+        let pc = self.r[R_PC];
+        let index = pc as usize % DECODE_CACHE_SIZE;
+
+        if let Some(cached) = &self.decode_cache[index] {
+            let last = pc as usize + cached.ir.len as usize - 1;
+            if cached.pc == pc
+                && bus.code_generation(pc as usize) == Some(cached.first_generation)
+                && bus.code_generation(last) == Some(cached.last_generation)
+            {
+                self.ir.clone_from(&cached.ir);
+                return Ok(());
+            }
+        }
+
+        self.decode_instruction(bus)?;
+
+        let last = pc as usize + self.ir.len as usize - 1;
+        if let (Some(first_generation), Some(last_generation)) =
+            (bus.code_generation(pc as usize), bus.code_generation(last))
+        {
+            self.decode_cache[index] = Some(DecodedInstruction {
+                pc,
+                first_generation,
+                last_generation,
+                ir: self.ir.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
     /// Decode the instruction currently pointed at by the Program Counter.
     fn decode_instruction(&mut self, bus: &mut Bus) -> Result<(), CpuError> {
         self.ir.len = 0;
@@ -3285,4 +3362,52 @@ mod tests {
             assert_eq!(0x12345678, cpu.read_op(bus, 0).unwrap());
         });
     }
+
+    #[test]
+    fn executes_instructions_modified_by_a_bus_write() {
+        let program = [0x84, 0x01, 0x40]; // MOVW $1,%r0
+        do_with_program(&program, |cpu, bus| {
+            cpu.step_with_error(bus).unwrap();
+            assert_eq!(1, cpu.r[0]);
+
+            // Re-execute after patching the literal operand
+            bus.write_byte(BASE + 1, 0x02).unwrap();
+            cpu.set_pc(BASE as u32);
+            cpu.step_with_error(bus).unwrap();
+            assert_eq!(2, cpu.r[0]);
+        });
+    }
+
+    #[test]
+    fn executes_instructions_modified_by_the_program_itself() {
+        let program = [
+            0x84, 0x01, 0x40, // MOVW $1,%r0
+            0x87, 0x05, 0x7f, 0x01, 0x00, 0x70, 0x00, // MOVB $5,$0x700001
+            0x7b, 0xf6, // BRB -10
+        ];
+        do_with_program(&program, |cpu, bus| {
+            cpu.step_with_error(bus).unwrap();
+            assert_eq!(1, cpu.r[0]);
+            cpu.step_with_error(bus).unwrap();
+            cpu.step_with_error(bus).unwrap();
+            assert_eq!(BASE as u32, cpu.get_pc());
+            cpu.step_with_error(bus).unwrap();
+            assert_eq!(5, cpu.r[0]);
+        });
+    }
+
+    #[test]
+    fn executes_instructions_replaced_by_a_load() {
+        let program = [0x84, 0x01, 0x40]; // MOVW $1,%r0
+        do_with_program(&program, |cpu, bus| {
+            cpu.step_with_error(bus).unwrap();
+            assert_eq!(1, cpu.r[0]);
+
+            bus.load(BASE, &[0x84, 0x03, 0x41]).unwrap(); // MOVW $3,%r1
+            cpu.set_pc(BASE as u32);
+            cpu.step_with_error(bus).unwrap();
+            assert_eq!(1, cpu.r[0]);
+            assert_eq!(3, cpu.r[1]);
+        });
+    }
 }
@@ -0,0 +1,652 @@
+//! The display controller.
+//!
+//! The 5620's display is an 800x1024 monochrome bitmap, scanned out of
+//! main RAM at one bit per pixel, most significant bit leftmost. The
+//! controller has a single register, the display start register at
+//! 0x500000, which holds the RAM word address of the first scanline.
+//! The firmware moves the display by rewriting it, so the framebuffer
+//! may live anywhere in RAM.
+//!
+//! Whenever the start address changes, the controller records a
+//! "display moved" event, which the bus uses to invalidate its view
+//! of the framebuffer and which frontends may poll for.
+//!
+//! Writes to the framebuffer are tracked in `DirtyTiles`, so that
+//! frontends can redraw only the parts of the screen that changed.
+//!
+//! The only other control the display has is its polarity, which the
+//! firmware selects with a DUART output port bit, according to the
+//! background set up in NVRAM. There is no blanking control on the
+//! output port: to blank the screen, the firmware points the start
+//! register at blank memory.
+//!
+//! At each vertical blank, the bus publishes a copy of the framebuffer
+//! through a `FramePublisher`. The copy never changes once published,
+//! so a render thread holding a `FrameReader` can draw it without
+//! tearing, and without stopping the emulator.
+
+use crate::bus::{AccessCode, Device};
+use crate::err::BusError;
+use crate::image::crc32;
+
+use log::trace;
+use std::fmt::Debug;
+use std::fmt::Error;
+use std::fmt::Formatter;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+const START_ADDR: usize = 0x500000;
+const END_ADDR: usize = 0x500002;
+const ADDRESS_RANGE: Range<usize> = START_ADDR..END_ADDR;
+
+/// Visible width, in pixels.
+pub const WIDTH: usize = 800;
+/// Visible height, in pixels.
+pub const HEIGHT: usize = 1024;
+/// Bytes per scanline.
+pub const STRIDE: usize = WIDTH / 8;
+/// Size of the framebuffer, in bytes.
+pub const FRAMEBUFFER_LEN: usize = STRIDE * HEIGHT;
+
+/// Width of a dirty-tracking tile, in pixels. Tiles are a whole
+/// number of words wide, so that an aligned write never spans two.
+pub const TILE_WIDTH: usize = 32;
+/// Height of a dirty-tracking tile, in scanlines.
+pub const TILE_HEIGHT: usize = 16;
+
+/// DUART output port bit that selects inverse video.
+const OP_VIDEO_INVERT: u8 = 0x02;
+
+const TILE_COLS: usize = WIDTH / TILE_WIDTH;
+const TILE_ROWS: usize = HEIGHT / TILE_HEIGHT;
+const TILE_STRIDE: usize = TILE_WIDTH / 8;
+
+/// A rectangle of the screen, in pixels.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// The smallest rectangle containing both rectangles.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// Whether lit pixels are drawn white on black, or black on white.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Polarity {
+    Normal,
+    Inverse,
+}
+
+impl Polarity {
+    /// Decode the polarity from the DUART output port pins. Before the
+    /// firmware programs the port, all of its pins are high, and the
+    /// display is inverted.
+    pub fn from_output_port(output: u8) -> Polarity {
+        if output & OP_VIDEO_INVERT != 0 {
+            Polarity::Inverse
+        } else {
+            Polarity::Normal
+        }
+    }
+
+    pub fn is_inverse(self) -> bool {
+        self == Polarity::Inverse
+    }
+}
+
+/// Tracks which tiles of the screen have been written since they were
+/// last collected.
+pub struct DirtyTiles {
+    tiles: Vec<bool>,
+    any: bool,
+}
+
+impl Default for DirtyTiles {
+    fn default() -> Self {
+        DirtyTiles::new()
+    }
+}
+
+impl DirtyTiles {
+    pub fn new() -> DirtyTiles {
+        DirtyTiles {
+            tiles: vec![false; TILE_COLS * TILE_ROWS],
+            any: false,
+        }
+    }
+
+    /// Mark the tile holding a byte of the framebuffer, given as an
+    /// offset from the start of the framebuffer.
+    #[inline]
+    pub fn mark(&mut self, offset: usize) {
+        let row = offset / STRIDE / TILE_HEIGHT;
+        let col = offset % STRIDE / TILE_STRIDE;
+        if let Some(tile) = self.tiles.get_mut(row * TILE_COLS + col) {
+            *tile = true;
+            self.any = true;
+        }
+    }
+
+    pub fn mark_all(&mut self) {
+        self.tiles.iter_mut().for_each(|t| *t = true);
+        self.any = true;
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.any
+    }
+
+    /// Collect the dirty parts of the screen as a list of rectangles,
+    /// and mark the whole screen clean. Runs of dirty tiles on a row
+    /// become one rectangle, which grows downwards for as long as the
+    /// rows below have a run of exactly the same extent.
+    pub fn take_rects(&mut self) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = Vec::new();
+
+        if !self.any {
+            return rects;
+        }
+
+        for (row, tiles) in self.tiles.chunks(TILE_COLS).enumerate() {
+            let mut col = 0;
+            while col < TILE_COLS {
+                if !tiles[col] {
+                    col += 1;
+                    continue;
+                }
+
+                let start = col;
+                while col < TILE_COLS && tiles[col] {
+                    col += 1;
+                }
+
+                let rect = Rect {
+                    x: (start * TILE_WIDTH) as u32,
+                    y: (row * TILE_HEIGHT) as u32,
+                    width: ((col - start) * TILE_WIDTH) as u32,
+                    height: TILE_HEIGHT as u32,
+                };
+
+                match rects
+                    .iter_mut()
+                    .find(|r| r.x == rect.x && r.width == rect.width && r.y + r.height == rect.y)
+                {
+                    Some(above) => above.height += rect.height,
+                    None => rects.push(rect),
+                }
+            }
+        }
+
+        self.tiles.iter_mut().for_each(|t| *t = false);
+        self.any = false;
+
+        rects
+    }
+}
+
+/// A copy of the framebuffer, taken at a vertical blank.
+pub struct Frame {
+    /// Incremented for each frame published with new contents.
+    pub sequence: u64,
+    /// `FRAMEBUFFER_LEN` bytes, `STRIDE` bytes per scanline.
+    pub data: Vec<u8>,
+    /// True if the display was showing inverse video.
+    pub inverted: bool,
+}
+
+impl Frame {
+    fn blank() -> Frame {
+        Frame {
+            sequence: 0,
+            data: vec![0; FRAMEBUFFER_LEN],
+            inverted: false,
+        }
+    }
+}
+
+/// A handle on the most recently published frame, which may be cloned
+/// and sent to other threads.
+#[derive(Clone)]
+pub struct FrameReader {
+    slot: Arc<Mutex<Arc<Frame>>>,
+}
+
+impl FrameReader {
+    /// The most recently published frame. The lock is held only long
+    /// enough to clone the reference.
+    pub fn latest(&self) -> Arc<Frame> {
+        match self.slot.lock() {
+            Ok(frame) => Arc::clone(&frame),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        }
+    }
+}
+
+/// Publishes frames to any number of `FrameReader`s. Frames are
+/// double buffered: a new frame is filled in a back buffer and then
+/// swapped in, and the buffer it replaces is reused once no reader
+/// holds it.
+pub struct FramePublisher {
+    slot: Arc<Mutex<Arc<Frame>>>,
+    back: Option<Frame>,
+    sequence: u64,
+    inverted: bool,
+}
+
+impl Default for FramePublisher {
+    fn default() -> Self {
+        FramePublisher::new()
+    }
+}
+
+impl FramePublisher {
+    pub fn new() -> FramePublisher {
+        FramePublisher {
+            slot: Arc::new(Mutex::new(Arc::new(Frame::blank()))),
+            back: None,
+            sequence: 0,
+            inverted: false,
+        }
+    }
+
+    pub fn reader(&self) -> FrameReader {
+        FrameReader {
+            slot: Arc::clone(&self.slot),
+        }
+    }
+
+    /// Whether the last frame published was showing inverse video.
+    pub fn inverted(&self) -> bool {
+        self.inverted
+    }
+
+    /// Publish a copy of the framebuffer. If it is shorter than a full
+    /// frame, the rest of the frame is blank.
+    pub fn publish(&mut self, framebuffer: &[u8], inverted: bool) {
+        let mut frame = self.back.take().unwrap_or_else(Frame::blank);
+        let len = framebuffer.len().min(FRAMEBUFFER_LEN);
+        frame.data[..len].copy_from_slice(&framebuffer[..len]);
+        frame.data[len..].fill(0);
+        self.sequence += 1;
+        frame.sequence = self.sequence;
+        frame.inverted = inverted;
+        self.inverted = inverted;
+
+        let previous = match self.slot.lock() {
+            Ok(mut slot) => std::mem::replace(&mut *slot, Arc::new(frame)),
+            Err(poisoned) => std::mem::replace(&mut *poisoned.into_inner(), Arc::new(frame)),
+        };
+
+        self.back = Arc::try_unwrap(previous).ok();
+    }
+}
+
+/// Find the region in which two screens differ, as a range of bytes
+/// across each scanline and a range of scanlines.
+pub fn changed_region(old: &[u8], new: &[u8]) -> Option<(Range<usize>, Range<usize>)> {
+    let changed = |x: usize, y: usize| old[y * STRIDE + x] != new[y * STRIDE + x];
+    let row_changed = |y: usize| (0..STRIDE).any(|x| changed(x, y));
+
+    let top = (0..HEIGHT).find(|y| row_changed(*y))?;
+    let bottom = (0..HEIGHT).rev().find(|y| row_changed(*y))? + 1;
+
+    let column_changed = |x: usize| (top..bottom).any(|y| changed(x, y));
+    let left = (0..STRIDE).find(|x| column_changed(*x))?;
+    let right = (0..STRIDE).rev().find(|x| column_changed(*x))? + 1;
+
+    Some((left..right, top..bottom))
+}
+
+/// A hash of the screen as it appears, with white pixels set, so that
+/// screens can be compared without keeping a copy of each.
+pub fn screen_hash(framebuffer: &[u8], inverted: bool) -> u32 {
+    if inverted {
+        let screen: Vec<u8> = framebuffer.iter().map(|b| !b).collect();
+        crc32(&screen)
+    } else {
+        crc32(framebuffer)
+    }
+}
+
+/// A hash of a region of the screen, clipped to the screen, with white
+/// pixels set. Pixels are packed from the left edge of the region, so
+/// a picture hashes the same wherever it is, and the whole screen
+/// hashes as it does for `screen_hash`.
+pub fn region_hash(framebuffer: &[u8], inverted: bool, region: &Rect) -> u32 {
+    let left = (region.x as usize).min(WIDTH);
+    let right = (left + region.width as usize).min(WIDTH);
+    let top = (region.y as usize).min(HEIGHT);
+    let bottom = (top + region.height as usize).min(HEIGHT);
+    let mask = if inverted {
+        0xff
+    } else {
+        0
+    };
+    let byte = |i: usize| framebuffer.get(i).copied().unwrap_or(0) ^ mask;
+
+    let shift = left % 8;
+    let row_len = (right - left + 7) / 8;
+    let mut pixels = Vec::with_capacity(row_len * (bottom - top));
+    for y in top..bottom {
+        let start = y * STRIDE + left / 8;
+        for i in 0..row_len {
+            let mut b = byte(start + i) << shift;
+            if shift != 0 {
+                b |= byte(start + i + 1) >> (8 - shift);
+            }
+            pixels.push(b);
+        }
+        // Clear the pixels past the right edge
+        if row_len > 0 {
+            let extra = row_len * 8 - (right - left);
+            if let Some(last) = pixels.last_mut() {
+                *last &= 0xff << extra;
+            }
+        }
+    }
+    crc32(&pixels)
+}
+
+#[derive(Clone)]
+pub struct Display {
+    start_register: u16,
+    moved: Option<usize>,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
+}
+
+impl Display {
+    pub fn new() -> Display {
+        Display {
+            start_register: 0,
+            moved: None,
+        }
+    }
+
+    /// Offset of the framebuffer from the start of RAM, in bytes.
+    pub fn start(&self) -> usize {
+        usize::from(self.start_register) * 4
+    }
+
+    /// The framebuffer, as a range of offsets from the start of RAM.
+    pub fn framebuffer(&self) -> Range<usize> {
+        self.start()..self.start() + FRAMEBUFFER_LEN
+    }
+
+    /// If the display has moved since the last call, return its new
+    /// start offset.
+    pub fn take_moved(&mut self) -> Option<usize> {
+        self.moved.take()
+    }
+
+    fn set_start_register(&mut self, val: u16) {
+        trace!("WRITE: DISPLAY START, val={:04x}", val);
+        if val != self.start_register {
+            self.start_register = val;
+            self.moved = Some(self.start());
+        }
+    }
+}
+
+impl Debug for Display {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "[DISPLAY]")
+    }
+}
+
+impl Device for Display {
+    fn address_range(&self) -> &Range<usize> {
+        &ADDRESS_RANGE
+    }
+
+    fn name(&self) -> &str {
+        "DISPLAY"
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read_byte(&mut self, address: usize, _access: AccessCode) -> Result<u8, BusError> {
+        let [hi, lo] = self.start_register.to_be_bytes();
+        match address - START_ADDR {
+            0 => Ok(hi),
+            _ => Ok(lo),
+        }
+    }
+
+    fn read_half(&mut self, _address: usize, _access: AccessCode) -> Result<u16, BusError> {
+        Ok(self.start_register)
+    }
+
+    fn read_word(&mut self, _address: usize, _access: AccessCode) -> Result<u32, BusError> {
+        Ok(u32::from(self.start_register) << 16)
+    }
+
+    fn write_byte(&mut self, address: usize, val: u8, _access: AccessCode) -> Result<(), BusError> {
+        let [hi, lo] = self.start_register.to_be_bytes();
+        match address - START_ADDR {
+            0 => self.set_start_register(u16::from_be_bytes([val, lo])),
+            _ => self.set_start_register(u16::from_be_bytes([hi, val])),
+        }
+        Ok(())
+    }
+
+    fn write_half(
+        &mut self,
+        _address: usize,
+        val: u16,
+        _access: AccessCode,
+    ) -> Result<(), BusError> {
+        self.set_start_register(val);
+        Ok(())
+    }
+
+    fn write_word(
+        &mut self,
+        _address: usize,
+        val: u32,
+        _access: AccessCode,
+    ) -> Result<(), BusError> {
+        self.set_start_register((val >> 16) as u16);
+        Ok(())
+    }
+
+    fn load(&mut self, address: usize, _data: &[u8]) -> Result<(), BusError> {
+        Err(BusError::Write(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_polarity_from_the_output_port() {
+        // As set by the firmware for dark and light backgrounds
+        assert_eq!(Polarity::Normal, Polarity::from_output_port(0x7c));
+        assert_eq!(Polarity::Inverse, Polarity::from_output_port(0x7e));
+        // Before the port is programmed
+        assert_eq!(Polarity::Inverse, Polarity::from_output_port(0xff));
+        assert!(!Polarity::Normal.is_inverse());
+    }
+
+    #[test]
+    fn start_register_holds_a_word_address() {
+        let mut display = Display::new();
+
+        display.write_half(0x500000, 0x1234, AccessCode::Write).unwrap();
+
+        assert_eq!(0x48d0, display.start());
+        assert_eq!(0x48d0..0x48d0 + 0x19000, display.framebuffer());
+        assert_eq!(0x12, display.read_byte(0x500000, AccessCode::AddressFetch).unwrap());
+        assert_eq!(0x34, display.read_byte(0x500001, AccessCode::AddressFetch).unwrap());
+    }
+
+    #[test]
+    fn reports_moves_once() {
+        let mut display = Display::new();
+
+        display.write_half(0x500000, 0, AccessCode::Write).unwrap();
+        assert_eq!(None, display.take_moved());
+
+        display.write_byte(0x500001, 0x10, AccessCode::Write).unwrap();
+        assert_eq!(Some(0x40), display.take_moved());
+        assert_eq!(None, display.take_moved());
+    }
+
+    #[test]
+    fn marks_the_tile_holding_a_byte() {
+        let mut tiles = DirtyTiles::new();
+
+        // Line 20, pixels 40..47
+        tiles.mark(20 * STRIDE + 5);
+
+        assert_eq!(
+            vec![Rect {
+                x: 32,
+                y: 16,
+                width: 32,
+                height: 16
+            }],
+            tiles.take_rects()
+        );
+        assert!(!tiles.is_dirty());
+        assert!(tiles.take_rects().is_empty());
+    }
+
+    #[test]
+    fn merges_adjacent_tiles_into_rectangles() {
+        let mut tiles = DirtyTiles::new();
+
+        // Two tiles wide, three tiles high, and a separate tile
+        for line in [0, 16, 47] {
+            tiles.mark(line * STRIDE);
+            tiles.mark(line * STRIDE + 4);
+        }
+        tiles.mark(FRAMEBUFFER_LEN - 1);
+
+        assert_eq!(
+            vec![
+                Rect {
+                    x: 0,
+                    y: 0,
+                    width: 64,
+                    height: 48
+                },
+                Rect {
+                    x: 768,
+                    y: 1008,
+                    width: 32,
+                    height: 16
+                }
+            ],
+            tiles.take_rects()
+        );
+    }
+
+    #[test]
+    fn marking_everything_covers_the_screen() {
+        let mut tiles = DirtyTiles::new();
+
+        tiles.mark_all();
+
+        assert_eq!(
+            vec![Rect {
+                x: 0,
+                y: 0,
+                width: WIDTH as u32,
+                height: HEIGHT as u32
+            }],
+            tiles.take_rects()
+        );
+    }
+
+    #[test]
+    fn publishes_frames_to_readers() {
+        let mut publisher = FramePublisher::new();
+        let reader = publisher.reader();
+
+        assert_eq!(0, reader.latest().sequence);
+
+        publisher.publish(&[0xff; 4], false);
+        let first = reader.latest();
+        assert_eq!(1, first.sequence);
+        assert_eq!(&[0xff, 0xff, 0xff, 0xff, 0], &first.data[0..5]);
+
+        // A frame held by a reader is never modified
+        publisher.publish(&[0x55; FRAMEBUFFER_LEN], false);
+        publisher.publish(&[0xaa; FRAMEBUFFER_LEN], true);
+        assert_eq!(0xff, first.data[0]);
+        assert_eq!(3, reader.latest().sequence);
+        assert_eq!(0xaa, reader.latest().data[FRAMEBUFFER_LEN - 1]);
+        assert!(reader.latest().inverted);
+    }
+
+    #[test]
+    fn finds_changed_regions() {
+        let old = vec![0; FRAMEBUFFER_LEN];
+        let mut new = old.clone();
+        assert_eq!(None, changed_region(&old, &new));
+
+        new[STRIDE * 10 + 3] = 1;
+        new[STRIDE * 20 + 1] = 1;
+        assert_eq!(Some((1..4, 10..21)), changed_region(&old, &new));
+    }
+
+    #[test]
+    fn hashes_regions() {
+        let mut framebuffer = vec![0; FRAMEBUFFER_LEN];
+        let mut set = |x: usize, y: usize| framebuffer[y * STRIDE + x / 8] |= 0x80 >> (x % 8);
+        // The same picture, unaligned and aligned
+        for (x, y) in [(3, 5), (16, 40)].iter() {
+            for (dx, dy) in [(0, 0), (2, 0), (9, 0), (1, 1)].iter() {
+                set(x + dx, y + dy);
+            }
+        }
+        let region = |x: u32, y: u32| Rect {
+            x,
+            y,
+            width: 10,
+            height: 2,
+        };
+
+        let hash = region_hash(&framebuffer, false, &region(3, 5));
+        assert_eq!(hash, region_hash(&framebuffer, false, &region(16, 40)));
+        assert_ne!(hash, region_hash(&framebuffer, false, &region(4, 5)));
+        assert_ne!(hash, region_hash(&framebuffer, true, &region(3, 5)));
+
+        let screen = Rect {
+            x: 0,
+            y: 0,
+            width: WIDTH as u32,
+            height: HEIGHT as u32 + 10,
+        };
+        for inverted in [false, true].iter() {
+            assert_eq!(
+                screen_hash(&framebuffer, *inverted),
+                region_hash(&framebuffer, *inverted, &screen)
+            );
+        }
+    }
+}
@@ -1,29 +1,122 @@
 #![allow(clippy::unreadable_literal)]
 
-use crate::bus::{AccessCode, Bus};
+use crate::bus::{AccessCode, Bus, PendingOutput};
 use crate::cpu::Cpu;
+use crate::display::{
+    FramePublisher, FrameReader, Polarity, Rect, FRAMEBUFFER_LEN, HEIGHT, STRIDE, WIDTH,
+};
 use crate::err::BusError;
+use crate::history::{History, Snapshot, TimeTravelError};
+use crate::image::{self, ImageFormat};
+use crate::input::Input;
+use crate::keyboard::{Key, KeyboardEvent, Layout, Leds, Modifiers};
+use crate::mouse::Acceleration;
+use crate::recorder::VideoFormat;
 use crate::rom_hi::{HI_ROM_V1, HI_ROM_V2};
 use crate::rom_lo::{LO_ROM_V1, LO_ROM_V1_LEN, LO_ROM_V2, LO_ROM_V2_LEN};
+use crate::session::{Session, SessionError};
+use crate::setup::{Setup, SetupError};
+use crate::term::{self, TextMode};
+use crate::text::{Font, TextLine};
 
 use libc::*;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::ptr;
 use std::sync::{Mutex, Once};
 
 lazy_static! {
-    pub static ref DMD: Mutex<Dmd> = Mutex::new(Dmd::new());
+    // Created before DMD and kept apart from it, so that reading frames
+    // never waits for the emulator, not even the first time.
+    static ref FRAMES: Frames = Frames::new();
+    pub static ref DMD: Mutex<Dmd> = Mutex::new(Dmd::with_frame_publisher(FRAMES.publisher()));
+}
+
+/// The publisher for DMD's frames, until DMD takes it, and its reader.
+struct Frames {
+    publisher: Mutex<Option<FramePublisher>>,
+    reader: FrameReader,
+}
+
+impl Frames {
+    fn new() -> Frames {
+        let publisher = FramePublisher::new();
+        Frames {
+            reader: publisher.reader(),
+            publisher: Mutex::new(Some(publisher)),
+        }
+    }
+
+    fn publisher(&self) -> FramePublisher {
+        let publisher = match self.publisher.lock() {
+            Ok(mut publisher) => publisher.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        // DMD is only created once, so it always finds the publisher
+        publisher.unwrap_or_default()
+    }
 }
 
 // Return values for the C library
-const SUCCESS: c_int = 0;
-const ERROR: c_int = 1;
-const BUSY: c_int = 2;
+pub(crate) const SUCCESS: c_int = 0;
+pub(crate) const ERROR: c_int = 1;
+pub(crate) const BUSY: c_int = 2;
+
+// Image formats for the C library
+const IMAGE_PBM: u8 = 0;
+const IMAGE_PNG: u8 = 1;
+
+// Video formats for the C library
+const VIDEO_GIF: u8 = 0;
+const VIDEO_APNG: u8 = 1;
+
+// Text rendering modes for the C library
+const TEXT_BRAILLE: u8 = 0;
+const TEXT_SIXEL: u8 = 1;
+
+// Display polarities for the C library
+const POLARITY_NORMAL: u8 = 0;
+const POLARITY_INVERSE: u8 = 1;
+
+// Key modifiers for the C library
+const MODIFIER_SHIFT: u8 = 0x01;
+const MODIFIER_CONTROL: u8 = 0x02;
+
+// Keyboard events for the C library
+const KEYBOARD_BELL: u8 = 0;
+const KEYBOARD_CLICK: u8 = 1;
+const KEYBOARD_KEY_CLICK: u8 = 2;
+const KEYBOARD_LEDS_CHANGED: u8 = 3;
+
+// Keyboard lamps for the C library
+const LED_CAPS_LOCK: u8 = 0x01;
+
+// Input kinds for the C library
+const INPUT_RS232_RX: u8 = 0;
+const INPUT_KEYBOARD_RX: u8 = 1;
+const INPUT_CAPS_LOCK: u8 = 2;
+const INPUT_MOUSE_MOVE: u8 = 3;
+const INPUT_MOUSE_MOVE_TO: u8 = 4;
+const INPUT_MOUSE_MOVE_BY: u8 = 5;
+const INPUT_MOUSE_DOWN: u8 = 6;
+const INPUT_MOUSE_UP: u8 = 7;
+const INPUT_SCC_RX: u8 = 8;
 
 static INIT: Once = Once::new();
 
 pub struct Dmd {
     cpu: Cpu,
     bus: Bus,
+    version: u8,
+    layout: Layout,
+    // Instructions executed since reset.
+    steps: u64,
+    history: Option<History>,
+    breakpoints: HashSet<u32>,
 }
 
 impl Default for Dmd {
@@ -34,20 +127,36 @@ impl Default for Dmd {
 
 impl Dmd {
     pub fn new() -> Dmd {
+        Dmd::with_frame_publisher(FramePublisher::new())
+    }
+
+    /// Create a terminal that publishes its frames through `frames`,
+    /// so that readers can be handed out before it exists.
+    pub fn with_frame_publisher(frames: FramePublisher) -> Dmd {
         // Never re-init logging
         INIT.call_once(|| {
             env_logger::init();
         });
 
         let cpu = Cpu::new();
-        let bus = Bus::new(0x100000);
+        let bus = Bus::with_frame_publisher(0x100000, frames);
         Dmd {
             cpu,
             bus,
+            version: 2,
+            layout: Layout::default(),
+            steps: 0,
+            history: None,
+            breakpoints: HashSet::new(),
         }
     }
 
+    /// Load the ROMs for a firmware version, and start the terminal
+    /// as at power-on, with the NVRAM as it was. A session being
+    /// logged starts again from here, since a log can only be replayed
+    /// from power-on.
     pub fn reset(&mut self, version: u8) -> Result<(), BusError> {
+        self.bus.power_on();
         match version {
             1 => {
                 self.bus.load(0, &LO_ROM_V1)?;
@@ -60,6 +169,10 @@ impl Dmd {
         }
 
         self.cpu.reset(&mut self.bus)?;
+        self.version = version;
+        self.steps = 0;
+        self.clear_history();
+        self.bus.restart_session(version);
 
         Ok(())
     }
@@ -72,6 +185,136 @@ impl Dmd {
         self.bus.video_ram_dirty()
     }
 
+    /// A handle on the framebuffer as it was at the most recent
+    /// vertical blank. Unlike `video_ram`, it never shows a frame
+    /// that is partly drawn, and may be read from another thread
+    /// while the emulator runs.
+    pub fn frame_reader(&self) -> FrameReader {
+        self.bus.frame_reader()
+    }
+
+    /// The display polarity, decoded from the DUART output port.
+    pub fn polarity(&self) -> Polarity {
+        self.bus.polarity()
+    }
+
+    /// True if the display is showing inverse video.
+    pub fn video_inverted(&self) -> bool {
+        self.bus.video_inverted()
+    }
+
+    /// Capture the visible display as an image file.
+    pub fn screenshot(&mut self, format: ImageFormat) -> Vec<u8> {
+        let inverted = self.video_inverted();
+        image::encode(self.bus.video_ram(), inverted, format)
+    }
+
+    /// Render the visible display as braille text or sixel graphics,
+    /// for showing in a text terminal. At a scale of `n`, each dot
+    /// covers `n` by `n` pixels.
+    pub fn render_text(&mut self, mode: TextMode, scale: usize) -> String {
+        let inverted = self.video_inverted();
+        term::render(self.bus.video_ram(), inverted, mode, scale)
+    }
+
+    /// Read the text drawn on the display in the firmware's font, with
+    /// the pixel position of each line.
+    pub fn screen_text(&mut self) -> Vec<TextLine> {
+        Font::builtin(self.version).read(self.bus.video_ram())
+    }
+
+    /// Start recording the display to a file, adding a frame at each
+    /// vertical blank where the screen has changed. Any recording
+    /// already in progress is finished first.
+    pub fn start_recording<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        format: VideoFormat,
+    ) -> io::Result<()> {
+        self.bus.start_recording(path.as_ref(), format)
+    }
+
+    /// Finish the recording in progress, if there is one.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        self.bus.stop_recording()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.bus.is_recording()
+    }
+
+    /// Start writing everything sent to the printer to a file,
+    /// including any output not yet taken with `printer_tx`. Any
+    /// capture already in progress is finished first.
+    pub fn start_printer_capture<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.bus.start_printer_capture(path.as_ref())
+    }
+
+    /// Finish the printer capture in progress, if there is one.
+    pub fn stop_printer_capture(&mut self) -> io::Result<()> {
+        self.bus.stop_printer_capture()
+    }
+
+    pub fn is_capturing_printer(&self) -> bool {
+        self.bus.is_capturing_printer()
+    }
+
+    /// Start logging a session in memory, so that it can be replayed.
+    /// A session must start at power-on, after `reset` and before
+    /// running.
+    pub fn start_session(&mut self) -> Result<(), SessionError> {
+        self.start_session_at(None)
+    }
+
+    /// Start logging a session to a file, writing each entry as it
+    /// happens. A session must start at power-on, after `reset` and
+    /// before running.
+    pub fn start_session_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SessionError> {
+        self.start_session_at(Some(path.as_ref()))
+    }
+
+    fn start_session_at(&mut self, path: Option<&Path>) -> Result<(), SessionError> {
+        if self.now() != 0 {
+            return Err(SessionError::NotAtPowerOn);
+        }
+        self.bus.start_session(self.version, path)?;
+        Ok(())
+    }
+
+    /// Finish logging the session, if there is one. A session logged
+    /// in memory is returned; one logged to a file is finished there.
+    pub fn stop_session(&mut self) -> Result<Option<Session>, SessionError> {
+        Ok(self.bus.stop_session()?)
+    }
+
+    pub fn is_logging_session(&self) -> bool {
+        self.bus.is_logging_session()
+    }
+
+    /// A hash of the screen as it appears, with white pixels set.
+    pub fn screen_hash(&self) -> u32 {
+        self.bus.screen_hash()
+    }
+
+    /// A hash of a region of the screen, in pixels. The same picture
+    /// hashes the same wherever it is on the screen.
+    pub fn region_hash(&self, region: &Rect) -> u32 {
+        self.bus.region_hash(region)
+    }
+
+    /// Return the rectangles of the screen that have been written
+    /// since the last call, in pixels. Moving the display dirties the
+    /// whole screen.
+    pub fn dirty_rects(&mut self) -> Vec<Rect> {
+        self.bus.dirty_rects()
+    }
+
+    /// If the firmware has moved the display since the last call,
+    /// return the new offset of the framebuffer from the start of RAM.
+    pub fn display_moved(&mut self) -> Option<usize> {
+        self.bus.display_moved()
+    }
+
     pub fn get_pc(&self) -> u32 {
         self.cpu.get_pc()
     }
@@ -89,29 +332,214 @@ impl Dmd {
     }
 
     pub fn read_word(&mut self, addr: usize) -> Option<u32> {
-        match self.bus.read_word(addr, AccessCode::AddressFetch) {
-            Ok(d) => Some(d),
-            _ => None,
-        }
+        self.bus.read_word(addr, AccessCode::AddressFetch).ok()
     }
 
     pub fn read_byte(&mut self, addr: usize) -> Option<u8> {
-        match self.bus.read_byte(addr, AccessCode::AddressFetch) {
-            Ok(d) => Some(d),
-            _ => None,
-        }
+        self.bus.read_byte(addr, AccessCode::AddressFetch).ok()
     }
 
     pub fn step(&mut self) {
+        if let Some(history) = &mut self.history {
+            if history.snapshot_due(self.steps) {
+                let unneeded = history.push(Snapshot {
+                    steps: self.steps,
+                    cpu: self.cpu.save_state(),
+                    bus: self.bus.save_state(),
+                    inputs: history.inputs_logged(self.bus.input_log_len()),
+                });
+                self.bus.trim_input_log(unneeded);
+            }
+        }
         self.cpu.step(&mut self.bus);
+        self.steps += 1;
     }
 
     pub fn run(&mut self, count: usize) {
         for _ in 0..count {
-            self.cpu.step(&mut self.bus);
+            self.step();
+        }
+    }
+
+    /// The number of instructions executed since reset.
+    pub fn instruction_count(&self) -> u64 {
+        self.steps
+    }
+
+    /// Start taking a snapshot every `interval` instructions, keeping
+    /// the latest `limit` of them, so that the terminal can be rewound
+    /// as far back as the earliest. Any history already kept is lost.
+    pub fn enable_time_travel(&mut self, interval: u64, limit: usize) {
+        self.history = Some(History::new(interval, limit));
+        self.bus.set_input_log(true);
+    }
+
+    pub fn disable_time_travel(&mut self) {
+        self.history = None;
+        self.bus.set_input_log(false);
+    }
+
+    pub fn is_time_travel_enabled(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// The instruction count as far back as the terminal can rewind.
+    pub fn earliest_instruction(&self) -> Option<u64> {
+        self.history.as_ref().and_then(History::earliest)
+    }
+
+    /// Forget the history, which no longer leads to the current state
+    /// when that is changed other than by input.
+    fn clear_history(&mut self) {
+        if let Some(history) = &self.history {
+            let (interval, limit) = history.settings();
+            self.enable_time_travel(interval, limit);
+        }
+    }
+
+    /// Go back to the state the terminal was in when the given number
+    /// of instructions had been executed since reset.
+    pub fn rewind_to(&mut self, steps: u64) -> Result<(), TimeTravelError> {
+        let pending = self.restore_snapshot(steps)?;
+        while self.steps < steps {
+            self.step();
+        }
+        self.bus.finish_restore(pending);
+        Ok(())
+    }
+
+    /// Go back by one instruction.
+    pub fn reverse_step(&mut self) -> Result<(), TimeTravelError> {
+        match self.steps.checked_sub(1) {
+            Some(steps) => self.rewind_to(steps),
+            None => Err(TimeTravelError::TooFarBack(0)),
+        }
+    }
+
+    /// Go back to the last instruction executed at a breakpoint.
+    /// Returns false, leaving the terminal at the earliest instruction
+    /// it can rewind to, if no breakpoint was reached since then.
+    pub fn reverse_continue(&mut self) -> Result<bool, TimeTravelError> {
+        let history = self.history.as_ref().ok_or(TimeTravelError::NotEnabled)?;
+        let earliest = history.earliest().ok_or(TimeTravelError::TooFarBack(self.steps))?;
+        let mut end = self.steps;
+        let mut pending = None;
+
+        // Search back a snapshot at a time, running forward from each
+        // to where the search last started
+        while end > earliest {
+            let restored = self.restore_snapshot(end - 1)?;
+            // Only the output waiting before the search began is kept
+            let kept = pending.take().unwrap_or(restored);
+            let start = self.steps;
+            let mut hit = None;
+            while self.steps < end {
+                if self.breakpoints.contains(&self.get_pc()) {
+                    hit = Some(self.steps);
+                }
+                self.step();
+            }
+            if let Some(steps) = hit {
+                self.restore_snapshot(steps)?;
+                while self.steps < steps {
+                    self.step();
+                }
+                self.bus.finish_restore(kept);
+                return Ok(true);
+            }
+            pending = Some(kept);
+            end = start;
+        }
+
+        let restored = self.restore_snapshot(earliest)?;
+        self.bus.finish_restore(pending.unwrap_or(restored));
+        Ok(false)
+    }
+
+    /// Restore the latest snapshot taken at or before the given
+    /// instruction, dropping any taken after it. The terminal is quiet
+    /// until the output returned is passed to `Bus::finish_restore`.
+    fn restore_snapshot(&mut self, steps: u64) -> Result<PendingOutput, TimeTravelError> {
+        if self.bus.is_logging_session() {
+            return Err(TimeTravelError::SessionInProgress);
+        }
+        if steps > self.steps {
+            return Err(TimeTravelError::InFuture(steps));
+        }
+        let history = self.history.as_mut().ok_or(TimeTravelError::NotEnabled)?;
+        let (snapshot, replay_from) = history.rewind(steps)?;
+        self.cpu.restore_state(&snapshot.cpu);
+        self.steps = snapshot.steps;
+        Ok(self.bus.restore_state(&snapshot.bus, replay_from))
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Run until the next instruction is at a breakpoint, for at most
+    /// `count` instructions. Returns true if a breakpoint was reached.
+    pub fn run_to_breakpoint(&mut self, count: usize) -> bool {
+        for _ in 0..count {
+            self.step();
+            if self.breakpoints.contains(&self.get_pc()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The current emulated time, in nanoseconds since reset.
+    pub fn now(&self) -> u64 {
+        self.bus.now()
+    }
+
+    /// Queue an input to be applied once the CPU reaches the emulated
+    /// time `at`, rather than straight away.
+    pub fn queue_input(&mut self, at: u64, input: Input) {
+        self.bus.queue_input(at, input);
+    }
+
+    /// Queue a press of the named host key at the emulated time `at`.
+    /// Returns false, queueing nothing, if the key is not in the
+    /// keyboard layout.
+    pub fn queue_key_press(&mut self, at: u64, name: &str, modifiers: Modifiers) -> bool {
+        match self.layout.key(name) {
+            Some(key) => {
+                self.bus.queue_input(at, Input::KeyPress(key, modifiers));
+                true
+            }
+            None => false,
         }
     }
 
+    /// Queue a string of ASCII text to be typed from the emulated
+    /// time `at`. The keyboard sends the keys one at a time, as fast
+    /// as the firmware reads them.
+    pub fn queue_text(&mut self, at: u64, text: &str) {
+        for (key, modifiers) in text.chars().filter_map(Key::from_char) {
+            self.bus.queue_input(at, Input::KeyPress(key, modifiers));
+        }
+    }
+
+    /// The number of queued inputs not yet applied.
+    pub fn inputs_pending(&self) -> usize {
+        self.bus.inputs_pending()
+    }
+
+    /// Drop all queued inputs not yet applied.
+    pub fn clear_inputs(&mut self) {
+        self.bus.clear_inputs();
+    }
+
     pub fn rs232_tx(&mut self) -> Option<u8> {
         self.bus.rs232_tx()
     }
@@ -120,6 +548,12 @@ impl Dmd {
         self.bus.keyboard_tx()
     }
 
+    /// Take the oldest character sent to the printer. Nothing is left
+    /// here while printer output is being captured to a file.
+    pub fn printer_tx(&mut self) -> Option<u8> {
+        self.bus.printer_tx()
+    }
+
     pub fn rs232_rx(&mut self, c: u8) {
         self.bus.rs232_rx(c);
     }
@@ -128,10 +562,84 @@ impl Dmd {
         self.bus.keyboard_rx(keycode);
     }
 
+    /// The mapping from host key names to 5620 keys used by
+    /// `key_press`.
+    pub fn keyboard_layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn set_keyboard_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// Press the named host key with the given modifiers held.
+    /// Returns false, doing nothing, if the key is not in the keyboard
+    /// layout.
+    pub fn key_press(&mut self, name: &str, modifiers: Modifiers) -> bool {
+        match self.layout.key(name) {
+            Some(key) => self.bus.key_press(key, modifiers),
+            None => false,
+        }
+    }
+
+    /// Type a string of ASCII text on the keyboard. Letters are typed
+    /// in upper case while CAPS LOCK is on.
+    pub fn type_text(&mut self, text: &str) {
+        for (key, modifiers) in text.chars().filter_map(Key::from_char) {
+            self.bus.key_press(key, modifiers);
+        }
+    }
+
+    pub fn set_caps_lock(&mut self, on: bool) {
+        self.bus.set_caps_lock(on);
+    }
+
+    pub fn keyboard_leds(&self) -> Leds {
+        self.bus.keyboard_leds()
+    }
+
+    /// The oldest keyboard event, such as the bell sounding, that has
+    /// not yet been taken. Only the latest `keyboard::EVENT_LIMIT`
+    /// are kept.
+    pub fn keyboard_event(&mut self) -> Option<KeyboardEvent> {
+        self.bus.keyboard_event()
+    }
+
+    /// Set the mouse's raw position counters. The Y counter counts up
+    /// from the bottom of the screen. Most frontends want
+    /// `mouse_move_to` instead.
     pub fn mouse_move(&mut self, x: u16, y: u16) {
         self.bus.mouse_move(x, y);
     }
 
+    /// Move the mouse to a position in screen coordinates, with the
+    /// origin at the top left, clamped to the screen.
+    pub fn mouse_move_to(&mut self, x: i32, y: i32) {
+        self.bus.mouse_move_to(x, y);
+    }
+
+    /// Move the mouse by a relative motion in screen coordinates,
+    /// applying the mouse acceleration and stopping at the edges of
+    /// the screen.
+    pub fn mouse_move_by(&mut self, dx: i32, dy: i32) {
+        self.bus.mouse_move_by(dx, dy);
+    }
+
+    /// The mouse position in screen coordinates.
+    pub fn mouse_position(&self) -> (u16, u16) {
+        self.bus.mouse_position()
+    }
+
+    /// The number of mouse motions and button changes queued for the
+    /// firmware to see.
+    pub fn mouse_events_pending(&self) -> usize {
+        self.bus.mouse_events_pending()
+    }
+
+    pub fn set_mouse_acceleration(&mut self, acceleration: Acceleration) {
+        self.bus.set_mouse_acceleration(acceleration);
+    }
+
     pub fn mouse_down(&mut self, button: u8) {
         self.bus.mouse_down(button);
     }
@@ -144,13 +652,76 @@ impl Dmd {
         self.bus.duart_output()
     }
 
+    /// Install or remove the optional I/O board and its SCC. The
+    /// firmware only looks for the board at reset.
+    pub fn set_io_board(&mut self, present: bool) {
+        self.bus.set_io_board(present);
+        self.clear_history();
+    }
+
+    pub fn io_board(&self) -> bool {
+        self.bus.io_board()
+    }
+
+    /// Take the next character transmitted by SCC channel A (0) or
+    /// B (1), if any.
+    pub fn scc_tx(&mut self, channel: u8) -> Option<u8> {
+        self.bus.scc_tx((channel & 1) as usize)
+    }
+
+    /// Queue a character to be received by SCC channel A (0) or B (1).
+    pub fn scc_rx(&mut self, channel: u8, c: u8) {
+        self.bus.scc_rx((channel & 1) as usize, c);
+    }
+
     pub fn set_nvram(&mut self, nvram: &[u8]) {
         self.bus.set_nvram(nvram);
+        self.clear_history();
     }
 
     pub fn get_nvram(&self) -> &[u8] {
         self.bus.get_nvram()
     }
+
+    /// Decode the setup options held in NVRAM, using the layout of the
+    /// firmware version last passed to `reset`.
+    pub fn setup(&self) -> Result<Setup, SetupError> {
+        Setup::decode(self.bus.get_nvram(), self.version)
+    }
+
+    /// Write setup options into NVRAM. The firmware only reads most
+    /// options at reset, or on leaving its setup screen.
+    pub fn set_setup(&mut self, setup: &Setup) -> Result<(), SetupError> {
+        let mut image = self.bus.get_nvram().to_vec();
+        setup.encode(&mut image)?;
+        self.set_nvram(&image);
+        Ok(())
+    }
+
+    /// Restore the NVRAM to the firmware's factory defaults.
+    pub fn reset_setup(&mut self) {
+        self.set_nvram(&Setup::factory_image(self.version));
+    }
+
+    /// True if the NVRAM has changed since it was last loaded or
+    /// flushed to its file.
+    pub fn nvram_dirty(&self) -> bool {
+        self.bus.nvram_dirty()
+    }
+
+    /// Bind the NVRAM to a file, loading it if it exists and creating
+    /// it otherwise. Changes made by the terminal are written back to
+    /// the file automatically.
+    pub fn bind_nvram<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let result = self.bus.bind_nvram(path.as_ref());
+        self.clear_history();
+        result
+    }
+
+    /// Write any unsaved NVRAM changes to the bound file now.
+    pub fn flush_nvram(&mut self) -> io::Result<()> {
+        self.bus.flush_nvram()
+    }
 }
 
 //
@@ -187,6 +758,73 @@ fn dmd_video_ram_dirty() -> c_int {
     }
 }
 
+#[no_mangle]
+fn dmd_display_geometry(width: &mut u32, height: &mut u32, stride: &mut u32) -> c_int {
+    *width = WIDTH as u32;
+    *height = HEIGHT as u32;
+    *stride = STRIDE as u32;
+    SUCCESS
+}
+
+/// Copy the frame published at the most recent vertical blank into
+/// `frame`, if it is newer than `sequence`, and update `sequence`.
+/// Returns BUSY if there is no newer frame. This does not wait for
+/// the emulator, and may be called from any thread.
+#[no_mangle]
+fn dmd_latest_frame(frame: &mut [u8; FRAMEBUFFER_LEN], sequence: &mut u64) -> c_int {
+    let latest = FRAMES.reader.latest();
+    if latest.sequence == *sequence {
+        return BUSY;
+    }
+    frame.copy_from_slice(&latest.data);
+    *sequence = latest.sequence;
+    SUCCESS
+}
+
+/// Fill `rects` with up to `max` rectangles of the screen that have
+/// been written since the last call, and set `count` to the number
+/// filled. If there are more than `max`, they are combined into the
+/// single rectangle that bounds them all.
+///
+/// # Safety
+///
+/// `rects` must point to space for at least `max` rectangles.
+#[no_mangle]
+unsafe fn dmd_dirty_rects(rects: *mut Rect, max: usize, count: &mut usize) -> c_int {
+    if rects.is_null() || max == 0 {
+        return ERROR;
+    }
+
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            let mut dirty = dmd.dirty_rects();
+            if dirty.len() > max {
+                let bounds = dirty.iter().skip(1).fold(dirty[0], |b, r| b.union(r));
+                dirty = vec![bounds];
+            }
+            let out = std::slice::from_raw_parts_mut(rects, max);
+            out[..dirty.len()].copy_from_slice(&dirty);
+            *count = dirty.len();
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_display_moved(start: &mut u32) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.display_moved() {
+            Some(offset) => {
+                *start = offset as u32;
+                SUCCESS
+            }
+            None => BUSY,
+        },
+        Err(_) => ERROR,
+    }
+}
+
 #[no_mangle]
 fn dmd_step() -> c_int {
     match DMD.lock() {
@@ -210,10 +848,10 @@ fn dmd_step_loop(steps: usize) -> c_int {
 }
 
 #[no_mangle]
-fn dmd_get_pc(pc: &mut u32) -> c_int {
+fn dmd_enable_time_travel(interval: u64, limit: usize) -> c_int {
     match DMD.lock() {
-        Ok(dmd) => {
-            *pc = dmd.get_pc();
+        Ok(mut dmd) => {
+            dmd.enable_time_travel(interval, limit);
             SUCCESS
         }
         Err(_) => ERROR,
@@ -221,10 +859,10 @@ fn dmd_get_pc(pc: &mut u32) -> c_int {
 }
 
 #[no_mangle]
-fn dmd_get_register(reg: u8, val: &mut u32) -> c_int {
+fn dmd_disable_time_travel() -> c_int {
     match DMD.lock() {
-        Ok(dmd) => {
-            *val = dmd.get_register(reg);
+        Ok(mut dmd) => {
+            dmd.disable_time_travel();
             SUCCESS
         }
         Err(_) => ERROR,
@@ -232,60 +870,57 @@ fn dmd_get_register(reg: u8, val: &mut u32) -> c_int {
 }
 
 #[no_mangle]
-fn dmd_read_word(addr: u32, val: &mut u32) -> c_int {
+fn dmd_instruction_count(count: &mut u64) -> c_int {
     match DMD.lock() {
-        Ok(mut dmd) => match dmd.read_word(addr as usize) {
-            Some(word) => {
-                *val = word;
-                SUCCESS
-            }
-            None => ERROR,
-        },
+        Ok(dmd) => {
+            *count = dmd.instruction_count();
+            SUCCESS
+        }
         Err(_) => ERROR,
     }
 }
 
 #[no_mangle]
-fn dmd_read_byte(addr: u32, val: &mut u8) -> c_int {
+fn dmd_rewind_to(steps: u64) -> c_int {
     match DMD.lock() {
-        Ok(mut dmd) => match dmd.read_byte(addr as usize) {
-            Some(byte) => {
-                *val = byte;
-                SUCCESS
-            }
-            None => ERROR,
+        Ok(mut dmd) => match dmd.rewind_to(steps) {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
         },
         Err(_) => ERROR,
     }
 }
 
 #[no_mangle]
-fn dmd_get_duart_output_port(oport: &mut u8) -> c_int {
+fn dmd_reverse_step() -> c_int {
     match DMD.lock() {
-        Ok(dmd) => {
-            *oport = dmd.duart_output();
-            SUCCESS
-        }
+        Ok(mut dmd) => match dmd.reverse_step() {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
+        },
         Err(_) => ERROR,
     }
 }
 
+/// Go back to the last breakpoint reached. Returns BUSY, at the
+/// earliest instruction kept, if none was reached since then.
 #[no_mangle]
-fn dmd_mouse_move(x: u16, y: u16) -> c_int {
+fn dmd_reverse_continue() -> c_int {
     match DMD.lock() {
-        Ok(mut dmd) => {
-            dmd.mouse_move(x, y);
-            SUCCESS
-        }
+        Ok(mut dmd) => match dmd.reverse_continue() {
+            Ok(true) => SUCCESS,
+            Ok(false) => BUSY,
+            Err(_) => ERROR,
+        },
         Err(_) => ERROR,
     }
 }
 
 #[no_mangle]
-fn dmd_mouse_down(button: u8) -> c_int {
+fn dmd_add_breakpoint(addr: u32) -> c_int {
     match DMD.lock() {
         Ok(mut dmd) => {
-            dmd.mouse_down(button);
+            dmd.add_breakpoint(addr);
             SUCCESS
         }
         Err(_) => ERROR,
@@ -293,10 +928,10 @@ fn dmd_mouse_down(button: u8) -> c_int {
 }
 
 #[no_mangle]
-fn dmd_mouse_up(button: u8) -> c_int {
+fn dmd_remove_breakpoint(addr: u32) -> c_int {
     match DMD.lock() {
         Ok(mut dmd) => {
-            dmd.mouse_up(button);
+            dmd.remove_breakpoint(addr);
             SUCCESS
         }
         Err(_) => ERROR,
@@ -304,31 +939,500 @@ fn dmd_mouse_up(button: u8) -> c_int {
 }
 
 #[no_mangle]
-fn dmd_rs232_rx(c: u8) -> c_int {
+fn dmd_clear_breakpoints() -> c_int {
     match DMD.lock() {
         Ok(mut dmd) => {
-            dmd.rs232_rx(c);
+            dmd.clear_breakpoints();
             SUCCESS
         }
         Err(_) => ERROR,
     }
 }
 
+/// Run for at most `steps` instructions, stopping at a breakpoint.
+/// Returns BUSY if none was reached.
 #[no_mangle]
-fn dmd_keyboard_rx(c: u8) -> c_int {
+fn dmd_run_to_breakpoint(steps: usize) -> c_int {
     match DMD.lock() {
         Ok(mut dmd) => {
-            dmd.keyboard_rx(c);
-            SUCCESS
+            if dmd.run_to_breakpoint(steps) {
+                SUCCESS
+            } else {
+                BUSY
+            }
         }
         Err(_) => ERROR,
     }
 }
 
 #[no_mangle]
-fn dmd_rs232_tx(tx_char: &mut u8) -> c_int {
+fn dmd_get_pc(pc: &mut u32) -> c_int {
+    match DMD.lock() {
+        Ok(dmd) => {
+            *pc = dmd.get_pc();
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_get_register(reg: u8, val: &mut u32) -> c_int {
+    match DMD.lock() {
+        Ok(dmd) => {
+            *val = dmd.get_register(reg);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_read_word(addr: u32, val: &mut u32) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.read_word(addr as usize) {
+            Some(word) => {
+                *val = word;
+                SUCCESS
+            }
+            None => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_read_byte(addr: u32, val: &mut u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.read_byte(addr as usize) {
+            Some(byte) => {
+                *val = byte;
+                SUCCESS
+            }
+            None => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_get_duart_output_port(oport: &mut u8) -> c_int {
+    match DMD.lock() {
+        Ok(dmd) => {
+            *oport = dmd.duart_output();
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Get the display polarity: 0 if lit pixels are white on black, or 1
+/// if they are black on white.
+#[no_mangle]
+fn dmd_get_polarity(polarity: &mut u8) -> c_int {
+    match DMD.lock() {
+        Ok(dmd) => {
+            *polarity = match dmd.polarity() {
+                Polarity::Normal => POLARITY_NORMAL,
+                Polarity::Inverse => POLARITY_INVERSE,
+            };
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_mouse_move(x: u16, y: u16) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.mouse_move(x, y);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Move the mouse to a position in screen coordinates, with the
+/// origin at the top left.
+#[no_mangle]
+fn dmd_mouse_move_to(x: i32, y: i32) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.mouse_move_to(x, y);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Move the mouse by a relative motion in screen coordinates.
+#[no_mangle]
+fn dmd_mouse_move_by(dx: i32, dy: i32) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.mouse_move_by(dx, dy);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_get_mouse_position(x: &mut u16, y: &mut u16) -> c_int {
+    match DMD.lock() {
+        Ok(dmd) => {
+            let (mx, my) = dmd.mouse_position();
+            *x = mx;
+            *y = my;
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Scale relative motions of more than `threshold` pixels by
+/// `numerator / denominator`.
+#[no_mangle]
+fn dmd_set_mouse_acceleration(numerator: u16, denominator: u16, threshold: u16) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.set_mouse_acceleration(Acceleration {
+                numerator,
+                denominator,
+                threshold,
+            });
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_mouse_down(button: u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.mouse_down(button);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_mouse_up(button: u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.mouse_up(button);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_rs232_rx(c: u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.rs232_rx(c);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_keyboard_rx(c: u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.keyboard_rx(c);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Press the host key named `name` in the keyboard layout, with the
+/// modifiers in `modifiers` (0x01 for shift, 0x02 for control) held.
+/// Returns ERROR if the key is not in the layout.
+///
+/// # Safety
+///
+/// `name` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_key_press(name: *const c_char, modifiers: u8) -> c_int {
+    if name.is_null() {
+        return ERROR;
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return ERROR,
+    };
+    let modifiers = decode_modifiers(modifiers);
+
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            if dmd.key_press(name, modifiers) {
+                SUCCESS
+            } else {
+                ERROR
+            }
+        }
+        Err(_) => ERROR,
+    }
+}
+
+fn decode_modifiers(modifiers: u8) -> Modifiers {
+    Modifiers {
+        shift: modifiers & MODIFIER_SHIFT != 0,
+        control: modifiers & MODIFIER_CONTROL != 0,
+    }
+}
+
+/// Type a NUL-terminated string of ASCII text on the keyboard.
+///
+/// # Safety
+///
+/// `text` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_type_text(text: *const c_char) -> c_int {
+    if text.is_null() {
+        return ERROR;
+    }
+
+    let text = match CStr::from_ptr(text).to_str() {
+        Ok(text) => text,
+        Err(_) => return ERROR,
+    };
+
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.type_text(text);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Replace the keyboard layout with one read from the file at `path`.
+/// See `Layout::parse` for the format.
+///
+/// # Safety
+///
+/// `path` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_load_keyboard_layout(path: *const c_char) -> c_int {
+    if path.is_null() {
+        return ERROR;
+    }
+
+    let layout = match CStr::from_ptr(path).to_str().ok().and_then(|p| fs::read_to_string(p).ok()) {
+        Some(text) => match Layout::parse(&text) {
+            Ok(layout) => layout,
+            Err(_) => return ERROR,
+        },
+        None => return ERROR,
+    };
+
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.set_keyboard_layout(layout);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Take the oldest keyboard event. `kind` is set to 0 for the bell, 1
+/// for a key click, 2 when key click is turned on or off, with
+/// `value` set to 1 if it is on, and 3 when the keyboard's lamps
+/// change, with `value` set to the lamps that are lit (0x01 for CAPS
+/// LOCK). Returns BUSY if there is no event.
+#[no_mangle]
+fn dmd_keyboard_event(kind: &mut u8, value: &mut u8) -> c_int {
+    let event = match DMD.lock() {
+        Ok(mut dmd) => dmd.keyboard_event(),
+        Err(_) => return ERROR,
+    };
+
+    let (k, v) = match event {
+        Some(KeyboardEvent::Bell) => (KEYBOARD_BELL, 0),
+        Some(KeyboardEvent::Click) => (KEYBOARD_CLICK, 0),
+        Some(KeyboardEvent::KeyClick(on)) => (KEYBOARD_KEY_CLICK, on as u8),
+        Some(KeyboardEvent::LedsChanged(leds)) => {
+            let caps_lock = if leds.caps_lock {
+                LED_CAPS_LOCK
+            } else {
+                0
+            };
+            (KEYBOARD_LEDS_CHANGED, caps_lock)
+        }
+        None => return BUSY,
+    };
+    *kind = k;
+    *value = v;
+    SUCCESS
+}
+
+#[no_mangle]
+fn dmd_set_caps_lock(on: u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.set_caps_lock(on != 0);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_now(now: &mut u64) -> c_int {
+    match DMD.lock() {
+        Ok(dmd) => {
+            *now = dmd.now();
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Queue an input to be applied at the emulated time `at`, in
+/// nanoseconds since reset. What `a` and `b` hold depends on `kind`:
+/// the character for RS232 RX and keyboard RX, 1 or 0 for CAPS LOCK
+/// on or off, the position or distance for mouse motion, the button
+/// for mouse presses, and the channel and character for SCC RX.
+#[no_mangle]
+fn dmd_queue_input(at: u64, kind: u8, a: i32, b: i32) -> c_int {
+    let byte = |v: i32| u8::try_from(v).ok();
+    let half = |v: i32| u16::try_from(v).ok();
+
+    let input = match kind {
+        INPUT_RS232_RX => byte(a).map(Input::Rs232Rx),
+        INPUT_KEYBOARD_RX => byte(a).map(Input::KeyboardRx),
+        INPUT_CAPS_LOCK => Some(Input::CapsLock(a != 0)),
+        INPUT_MOUSE_MOVE => half(a).zip(half(b)).map(|(x, y)| Input::MouseMove(x, y)),
+        INPUT_MOUSE_MOVE_TO => Some(Input::MouseMoveTo(a, b)),
+        INPUT_MOUSE_MOVE_BY => Some(Input::MouseMoveBy(a, b)),
+        INPUT_MOUSE_DOWN => byte(a).map(Input::MouseDown),
+        INPUT_MOUSE_UP => byte(a).map(Input::MouseUp),
+        INPUT_SCC_RX => byte(a).zip(byte(b)).map(|(channel, c)| Input::SccRx(channel, c)),
+        _ => None,
+    };
+
+    let input = match input {
+        Some(input) => input,
+        None => return ERROR,
+    };
+
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.queue_input(at, input);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Queue a press of the named host key at the emulated time `at`.
+/// Returns ERROR if the key is not in the keyboard layout.
+///
+/// # Safety
+///
+/// `name` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_queue_key_press(at: u64, name: *const c_char, modifiers: u8) -> c_int {
+    if name.is_null() {
+        return ERROR;
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return ERROR,
+    };
+    let modifiers = decode_modifiers(modifiers);
+
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            if dmd.queue_key_press(at, name, modifiers) {
+                SUCCESS
+            } else {
+                ERROR
+            }
+        }
+        Err(_) => ERROR,
+    }
+}
+
+/// Queue a NUL-terminated string of ASCII text to be typed from the
+/// emulated time `at`.
+///
+/// # Safety
+///
+/// `text` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_queue_text(at: u64, text: *const c_char) -> c_int {
+    if text.is_null() {
+        return ERROR;
+    }
+
+    let text = match CStr::from_ptr(text).to_str() {
+        Ok(text) => text,
+        Err(_) => return ERROR,
+    };
+
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.queue_text(at, text);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_clear_inputs() -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.clear_inputs();
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_rs232_tx(tx_char: &mut u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.rs232_tx() {
+            Some(c) => {
+                *tx_char = c;
+                SUCCESS
+            }
+            None => BUSY,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_keyboard_tx(tx_char: &mut u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.keyboard_tx() {
+            Some(c) => {
+                *tx_char = c;
+                SUCCESS
+            }
+            None => BUSY,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_printer_tx(tx_char: &mut u8) -> c_int {
     match DMD.lock() {
-        Ok(mut dmd) => match dmd.rs232_tx() {
+        Ok(mut dmd) => match dmd.printer_tx() {
             Some(c) => {
                 *tx_char = c;
                 SUCCESS
@@ -340,9 +1444,31 @@ fn dmd_rs232_tx(tx_char: &mut u8) -> c_int {
 }
 
 #[no_mangle]
-fn dmd_keyboard_tx(tx_char: &mut u8) -> c_int {
+fn dmd_set_io_board(present: u8) -> c_int {
     match DMD.lock() {
-        Ok(mut dmd) => match dmd.keyboard_tx() {
+        Ok(mut dmd) => {
+            dmd.set_io_board(present != 0);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_scc_rx(channel: u8, c: u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => {
+            dmd.scc_rx(channel, c);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_scc_tx(channel: u8, tx_char: &mut u8) -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.scc_tx(channel) {
             Some(c) => {
                 *tx_char = c;
                 SUCCESS
@@ -375,9 +1501,355 @@ fn dmd_get_nvram(nvram: &mut [u8; 8192]) -> c_int {
     }
 }
 
+#[no_mangle]
+fn dmd_nvram_dirty() -> c_int {
+    match DMD.lock() {
+        Ok(dmd) => match dmd.nvram_dirty() {
+            true => 1,
+            false => 0,
+        },
+        Err(_) => 0,
+    }
+}
+
+/// # Safety
+///
+/// `path` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_bind_nvram(path: *const c_char) -> c_int {
+    if path.is_null() {
+        return ERROR;
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ERROR,
+    };
+
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.bind_nvram(path) {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_flush_nvram() -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.flush_nvram() {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+fn image_format(format: u8) -> Option<ImageFormat> {
+    match format {
+        IMAGE_PBM => Some(ImageFormat::Pbm),
+        IMAGE_PNG => Some(ImageFormat::Png),
+        _ => None,
+    }
+}
+
+/// Write a screenshot to the file at `path`, in the given format
+/// (0 for PBM, 1 for PNG).
+///
+/// # Safety
+///
+/// `path` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_screenshot(format: u8, path: *const c_char) -> c_int {
+    if path.is_null() {
+        return ERROR;
+    }
+
+    let (format, path) = match (image_format(format), CStr::from_ptr(path).to_str()) {
+        (Some(format), Ok(path)) => (format, path),
+        _ => return ERROR,
+    };
+
+    let image = match DMD.lock() {
+        Ok(mut dmd) => dmd.screenshot(format),
+        Err(_) => return ERROR,
+    };
+
+    match fs::write(path, image) {
+        Ok(()) => SUCCESS,
+        Err(_) => ERROR,
+    }
+}
+
+/// Copy a screenshot in the given format (0 for PBM, 1 for PNG) into
+/// `buf`, and set `len` to its length. If it is longer than `max`,
+/// nothing is copied, `len` is set to the space needed, and BUSY is
+/// returned.
+///
+/// # Safety
+///
+/// `buf` must point to space for at least `max` bytes.
+#[no_mangle]
+unsafe fn dmd_screenshot_buf(format: u8, buf: *mut u8, max: usize, len: &mut usize) -> c_int {
+    let format = match image_format(format) {
+        Some(format) => format,
+        None => return ERROR,
+    };
+
+    let image = match DMD.lock() {
+        Ok(mut dmd) => dmd.screenshot(format),
+        Err(_) => return ERROR,
+    };
+
+    *len = image.len();
+    if image.len() > max {
+        return BUSY;
+    }
+    if buf.is_null() {
+        return ERROR;
+    }
+    ptr::copy_nonoverlapping(image.as_ptr(), buf, image.len());
+    SUCCESS
+}
+
+/// Render the display as braille text (mode 0) or sixel graphics
+/// (mode 1), as a NUL-terminated UTF-8 string in `buf`, and set `len`
+/// to its length without the NUL. If it does not fit in `max` bytes,
+/// nothing is copied, `len` is set to the length, and BUSY is
+/// returned.
+///
+/// # Safety
+///
+/// `buf` must point to space for at least `max` bytes.
+#[no_mangle]
+unsafe fn dmd_render_text(
+    mode: u8,
+    scale: u8,
+    buf: *mut c_char,
+    max: usize,
+    len: &mut usize,
+) -> c_int {
+    let mode = match mode {
+        TEXT_BRAILLE => TextMode::Braille,
+        TEXT_SIXEL => TextMode::Sixel,
+        _ => return ERROR,
+    };
+
+    let text = match DMD.lock() {
+        Ok(mut dmd) => dmd.render_text(mode, usize::from(scale)),
+        Err(_) => return ERROR,
+    };
+
+    *len = text.len();
+    if text.len() >= max {
+        return BUSY;
+    }
+    if buf.is_null() {
+        return ERROR;
+    }
+    ptr::copy_nonoverlapping(text.as_ptr() as *const c_char, buf, text.len());
+    *buf.add(text.len()) = 0;
+    SUCCESS
+}
+
+/// Read the text on the display into `buf` as a NUL-terminated
+/// string, with one line of text per line, each given as its pixel x
+/// and y positions and its text, separated by tabs. `len` is set to
+/// the length without the NUL. If it does not fit in `max` bytes,
+/// nothing is copied, `len` is set to the length, and BUSY is
+/// returned.
+///
+/// # Safety
+///
+/// `buf` must point to space for at least `max` bytes.
+#[no_mangle]
+unsafe fn dmd_screen_text(buf: *mut c_char, max: usize, len: &mut usize) -> c_int {
+    let lines = match DMD.lock() {
+        Ok(mut dmd) => dmd.screen_text(),
+        Err(_) => return ERROR,
+    };
+
+    let text: String =
+        lines.iter().map(|line| format!("{}\t{}\t{}\n", line.x, line.y, line.text)).collect();
+
+    *len = text.len();
+    if text.len() >= max {
+        return BUSY;
+    }
+    if buf.is_null() {
+        return ERROR;
+    }
+    ptr::copy_nonoverlapping(text.as_ptr() as *const c_char, buf, text.len());
+    *buf.add(text.len()) = 0;
+    SUCCESS
+}
+
+/// Start recording the display to the file at `path`, in the given
+/// format (0 for GIF, 1 for APNG).
+///
+/// # Safety
+///
+/// `path` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_start_recording(format: u8, path: *const c_char) -> c_int {
+    if path.is_null() {
+        return ERROR;
+    }
+
+    let format = match format {
+        VIDEO_GIF => VideoFormat::Gif,
+        VIDEO_APNG => VideoFormat::Apng,
+        _ => return ERROR,
+    };
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ERROR,
+    };
+
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.start_recording(path, format) {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_stop_recording() -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.stop_recording() {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+/// Start logging a session to the file at `path`, writing each entry
+/// as it happens. Returns ERROR unless the terminal has just been
+/// reset.
+///
+/// # Safety
+///
+/// `path` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_start_session(path: *const c_char) -> c_int {
+    if path.is_null() {
+        return ERROR;
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ERROR,
+    };
+
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.start_session_file(path) {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+/// Finish logging the session. Returns ERROR if there is none, or if
+/// its file could not be written.
+#[no_mangle]
+fn dmd_stop_session() -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) if dmd.is_logging_session() => match dmd.stop_session() {
+            Ok(_) => SUCCESS,
+            Err(_) => ERROR,
+        },
+        _ => ERROR,
+    }
+}
+
+/// Replay the session logged in the file at `path` in a terminal of
+/// its own, leaving this one alone. Returns ERROR if the file cannot
+/// be read, or if the replay does not do exactly what the session did.
+///
+/// # Safety
+///
+/// `path` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_replay_session(path: *const c_char) -> c_int {
+    if path.is_null() {
+        return ERROR;
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ERROR,
+    };
+
+    match Session::load(path).and_then(|session| session.replay()) {
+        Ok(_) => SUCCESS,
+        Err(_) => ERROR,
+    }
+}
+
+/// Start capturing printer output to the file at `path`.
+///
+/// # Safety
+///
+/// `path` must point to a valid NUL-terminated string.
+#[no_mangle]
+unsafe fn dmd_start_printer_capture(path: *const c_char) -> c_int {
+    if path.is_null() {
+        return ERROR;
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => return ERROR,
+    };
+
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.start_printer_capture(path) {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_stop_printer_capture() -> c_int {
+    match DMD.lock() {
+        Ok(mut dmd) => match dmd.stop_printer_capture() {
+            Ok(()) => SUCCESS,
+            Err(_) => ERROR,
+        },
+        Err(_) => ERROR,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::dmd::Dmd;
+    use crate::display::{Polarity, FRAMEBUFFER_LEN};
+    use crate::dmd::{dmd_latest_frame, Dmd, DMD, SUCCESS};
+    use crate::history::TimeTravelError;
+    use crate::image::ImageFormat;
+    use crate::input::Input;
+    use crate::keyboard::{KeyboardEvent, Modifiers};
+    use crate::mouse::Acceleration;
+    use crate::session::{Session, SessionError, SessionEvent};
+    use crate::setup::{Background, Setup};
+    use crate::text::TextLine;
+
+    #[test]
+    fn reads_frames_without_waiting_for_the_emulator() {
+        let _dmd = DMD.lock().unwrap();
+        let mut frame = Box::new([0xff; FRAMEBUFFER_LEN]);
+        let mut sequence = u64::MAX;
+        assert_eq!(SUCCESS, dmd_latest_frame(&mut frame, &mut sequence));
+        assert_eq!(0, sequence);
+        assert!(frame.iter().all(|b| *b == 0));
+    }
 
     #[test]
     fn creates_dmd() {
@@ -408,4 +1880,296 @@ mod tests {
         assert_eq!(0xa5, new_nvram[0xfff]);
         assert_eq!(0xff, new_nvram[0x1fff]);
     }
+
+    #[test]
+    fn firmware_writes_the_factory_setup() {
+        // Version 1 tests the BBRAM before initializing it
+        for version in [1, 2].iter() {
+            let mut dmd = Dmd::new();
+            dmd.reset(*version).unwrap();
+            dmd.run(10_000_000);
+            assert_eq!(Setup::factory_image(*version), dmd.get_nvram());
+        }
+    }
+
+    #[test]
+    fn resets_to_power_on() {
+        let mut dmd = Dmd::new();
+        dmd.reset(2).unwrap();
+        dmd.run(5_000_000);
+        dmd.queue_input(dmd.now() + 1_000, Input::KeyboardRx(b'x'));
+        dmd.reset(2).unwrap();
+        assert_eq!(0, dmd.now());
+        assert_eq!(0, dmd.inputs_pending());
+
+        let mut fresh = Dmd::new();
+        fresh.set_nvram(dmd.get_nvram());
+        fresh.reset(2).unwrap();
+        dmd.run(2_000_000);
+        fresh.run(2_000_000);
+        assert_eq!(fresh.get_pc(), dmd.get_pc());
+        assert_eq!(fresh.screen_hash(), dmd.screen_hash());
+    }
+
+    #[test]
+    fn rewinds_no_further_than_binding_the_nvram() {
+        let path = std::env::temp_dir().join(format!("dmd_bound_nvram_{}", std::process::id()));
+        let image = vec![0x5a; 8192];
+        std::fs::write(&path, &image).unwrap();
+
+        let mut dmd = Dmd::new();
+        dmd.enable_time_travel(1_000, 10);
+        dmd.reset(2).unwrap();
+        dmd.run(5_000);
+        dmd.bind_nvram(&path).unwrap();
+        assert_eq!(Err(TimeTravelError::TooFarBack(1_000)), dmd.rewind_to(1_000));
+        dmd.flush_nvram().unwrap();
+
+        let flushed = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image, flushed);
+    }
+
+    #[test]
+    fn screenshots_follow_video_inversion() {
+        let mut screenshots = Vec::new();
+
+        for background in [Background::Dark, Background::Light].iter() {
+            let mut dmd = Dmd::new();
+            dmd.reset(2).unwrap();
+            dmd.reset_setup();
+            let mut setup = dmd.setup().unwrap();
+            setup.background = *background;
+            dmd.set_setup(&setup).unwrap();
+            dmd.reset(2).unwrap();
+            dmd.run(5_000_000);
+
+            let polarity = match background {
+                Background::Dark => Polarity::Normal,
+                Background::Light => Polarity::Inverse,
+            };
+            assert_eq!(polarity, dmd.polarity());
+            screenshots.push(dmd.screenshot(ImageFormat::Pbm));
+        }
+
+        let header = b"P4\n800 1024\n".len();
+        let (dark, light) = (&screenshots[0][header..], &screenshots[1][header..]);
+        assert!(dark.iter().any(|b| *b != 0xff));
+        assert!(dark.iter().zip(light).all(|(d, l)| *d == !*l));
+    }
+
+    #[test]
+    fn reads_text_echoed_by_the_terminal() {
+        let mut dmd = Dmd::new();
+        dmd.reset(2).unwrap();
+        dmd.run(5_000_000);
+        for c in b"Hello, world!" {
+            dmd.rs232_rx(*c);
+            dmd.run(200_000);
+        }
+
+        assert_eq!(
+            vec![TextLine {
+                x: 3,
+                y: 3,
+                text: "Hello, world!".to_string(),
+            }],
+            dmd.screen_text()
+        );
+    }
+
+    #[test]
+    fn sends_keys_to_the_host() {
+        let mut dmd = Dmd::new();
+        dmd.reset(2).unwrap();
+        dmd.run(5_000_000);
+        while dmd.rs232_tx().is_some() {}
+
+        let sent = |dmd: &mut Dmd| {
+            dmd.run(500_000);
+            std::iter::from_fn(|| dmd.rs232_tx()).collect::<Vec<u8>>()
+        };
+
+        assert!(dmd.key_press("Up", Modifiers::NONE));
+        assert_eq!(b"\x1b[A".to_vec(), sent(&mut dmd));
+        assert!(!dmd.key_press("Hyper_L", Modifiers::NONE));
+        dmd.type_text("ls -l\n");
+        assert_eq!(b"ls -l\r".to_vec(), sent(&mut dmd));
+
+        dmd.set_caps_lock(true);
+        assert!(dmd.keyboard_leds().caps_lock);
+        dmd.type_text("ls");
+        assert_eq!(b"LS".to_vec(), sent(&mut dmd));
+    }
+
+    #[test]
+    fn types_queued_text_when_due() {
+        let mut dmd = Dmd::new();
+        dmd.reset(2).unwrap();
+        dmd.run(5_000_000);
+        while dmd.rs232_tx().is_some() {}
+
+        let at = dmd.now() + 100_000_000;
+        dmd.queue_text(at, "ls\n");
+        assert!(dmd.queue_key_press(at, "Up", Modifiers::NONE));
+        assert!(!dmd.queue_key_press(at, "Hyper_L", Modifiers::NONE));
+        assert_eq!(4, dmd.inputs_pending());
+
+        dmd.run(99_000);
+        assert_eq!(None, dmd.rs232_tx());
+        dmd.run(500_000);
+        let sent: Vec<u8> = std::iter::from_fn(|| dmd.rs232_tx()).collect();
+        assert_eq!(b"ls\r\x1b[A".to_vec(), sent);
+        assert_eq!(0, dmd.inputs_pending());
+    }
+
+    #[test]
+    fn replays_sessions() {
+        let mut dmd = Dmd::new();
+        dmd.reset(2).unwrap();
+        dmd.start_session().unwrap();
+        dmd.run(5_000_000);
+        for c in b"hello, world\r\n".iter() {
+            dmd.rs232_rx(*c);
+            dmd.run(1_000);
+        }
+        dmd.type_text("ls\n");
+        dmd.mouse_move_to(400, 512);
+        dmd.queue_input(dmd.now() + 5_000, Input::MouseDown(0));
+        dmd.run(500_000);
+        dmd.mouse_up(0);
+        dmd.run(500_000);
+        let session = dmd.stop_session().unwrap().unwrap();
+
+        assert!(session.outputs().any(|e| e.event == SessionEvent::Rs232Tx(b'l')));
+        assert_eq!(dmd.screen_hash(), session.screen);
+        let replayed = session.replay().unwrap();
+        assert_eq!(dmd.get_pc(), replayed.get_pc());
+        assert_eq!(dmd.now(), replayed.now());
+
+        let mut altered = session.clone();
+        for event in altered.events.iter_mut() {
+            if event.event == SessionEvent::Input(Input::Rs232Rx(b'w')) {
+                event.event = SessionEvent::Input(Input::Rs232Rx(b'W'));
+            }
+        }
+        assert!(matches!(altered.replay(), Err(SessionError::Mismatch { .. })));
+
+        assert!(matches!(dmd.start_session(), Err(SessionError::NotAtPowerOn)));
+        dmd.reset(2).unwrap();
+        assert!(dmd.start_session().is_ok());
+    }
+
+    #[test]
+    fn replays_sessions_logged_to_a_file() {
+        let path = std::env::temp_dir().join(format!("dmd_session_{}", std::process::id()));
+        let mut dmd = Dmd::new();
+        dmd.set_io_board(true);
+        dmd.reset(2).unwrap();
+        dmd.start_session_file(&path).unwrap();
+        dmd.run(1_000_000);
+        dmd.rs232_rx(b'x');
+        // The log starts again from the reset
+        dmd.reset(2).unwrap();
+        dmd.set_mouse_acceleration(Acceleration {
+            numerator: 4,
+            denominator: 1,
+            threshold: 0,
+        });
+        dmd.run(3_000_000);
+        dmd.mouse_move_by(50, 0);
+        dmd.run(1_000_000);
+        assert_eq!(None, dmd.stop_session().unwrap());
+        assert_eq!((200, 1023), dmd.mouse_position());
+
+        let session = Session::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(session.io_board);
+        assert!(!session.inputs().any(|i| i.input == Input::Rs232Rx(b'x')));
+        let replayed = session.replay().unwrap();
+        assert!(replayed.io_board());
+        assert_eq!(dmd.mouse_position(), replayed.mouse_position());
+        assert_eq!(dmd.now(), replayed.now());
+    }
+
+    #[test]
+    fn rewinds_to_earlier_instructions() {
+        let mut dmd = Dmd::new();
+        dmd.enable_time_travel(100_000, 40);
+        dmd.reset(2).unwrap();
+        dmd.run(5_000_000);
+        for c in b"hello\r\n".iter() {
+            dmd.rs232_rx(*c);
+            dmd.run(1_000);
+        }
+        let steps = dmd.instruction_count();
+        let state = (dmd.get_pc(), dmd.now(), dmd.screen_hash());
+
+        dmd.type_text("ls\n");
+        dmd.mouse_move_to(400, 512);
+        dmd.run(1_000_000);
+        let end = dmd.instruction_count();
+        let end_state = (dmd.get_pc(), dmd.now(), dmd.screen_hash());
+        while dmd.rs232_tx().is_some() {}
+
+        dmd.rewind_to(steps).unwrap();
+        assert_eq!(steps, dmd.instruction_count());
+        assert_eq!(state, (dmd.get_pc(), dmd.now(), dmd.screen_hash()));
+        assert_eq!(None, dmd.rs232_tx());
+
+        // The inputs made since are applied again on the way forward,
+        // and answered again
+        dmd.run((end - steps) as usize);
+        assert_eq!(end_state, (dmd.get_pc(), dmd.now(), dmd.screen_hash()));
+        assert!(std::iter::from_fn(|| dmd.rs232_tx()).any(|c| c == b'l'));
+
+        let pc = dmd.get_pc();
+        dmd.step();
+        dmd.reverse_step().unwrap();
+        assert_eq!(end, dmd.instruction_count());
+        assert_eq!(pc, dmd.get_pc());
+
+        assert_eq!(Err(TimeTravelError::InFuture(end + 1)), dmd.rewind_to(end + 1));
+        assert_eq!(Err(TimeTravelError::TooFarBack(0)), dmd.rewind_to(0));
+    }
+
+    #[test]
+    fn reverse_continues_to_breakpoints() {
+        let mut dmd = Dmd::new();
+        dmd.enable_time_travel(100_000, 100);
+        dmd.reset(2).unwrap();
+        dmd.run(5_000_000);
+        let pc = dmd.get_pc();
+        let steps = dmd.instruction_count();
+        dmd.run(1_000);
+
+        dmd.add_breakpoint(pc);
+        assert!(dmd.reverse_continue().unwrap());
+        assert_eq!(pc, dmd.get_pc());
+        assert!(dmd.instruction_count() >= steps);
+        let hit = dmd.instruction_count();
+        assert!(dmd.run_to_breakpoint(10_000_000));
+        assert_eq!(pc, dmd.get_pc());
+        assert!(dmd.instruction_count() > hit);
+
+        dmd.clear_breakpoints();
+        assert!(!dmd.reverse_continue().unwrap());
+        assert_eq!(dmd.earliest_instruction(), Some(dmd.instruction_count()));
+
+        dmd.disable_time_travel();
+        assert_eq!(Err(TimeTravelError::NotEnabled), dmd.rewind_to(0));
+    }
+
+    #[test]
+    fn rings_the_bell() {
+        let mut dmd = Dmd::new();
+        dmd.reset(2).unwrap();
+        dmd.run(5_000_000);
+        while dmd.keyboard_event().is_some() {}
+
+        dmd.rs232_rx(0x07);
+        dmd.run(500_000);
+        let events: Vec<KeyboardEvent> = std::iter::from_fn(|| dmd.keyboard_event()).collect();
+        assert!(events.contains(&KeyboardEvent::Bell));
+    }
 }
@@ -1,7 +1,9 @@
 /// The 2681 DUART has two I/O UARTs, each one represented by the
 /// `Port` struct. The first is used only for the EIA RS232 serial
 /// port. The second is duplexed between keyboard I/O and a write-only
-/// parallel printer port.
+/// parallel printer port. Output pin OP3 selects where the second
+/// port's transmitter goes: while it is low, characters go to the
+/// printer rather than the keyboard.
 ///
 /// Each of the two UARTs simulates the following hardware registers:
 ///
@@ -24,6 +26,11 @@
 ///   - One TRANSMIT shift register that latches the data from the
 ///     holding register and shifts them out onto the serial line.
 ///
+/// The transmitter and receiver state machines, and the vertical
+/// blank interrupt, are driven by events on the bus `Scheduler`
+/// rather than being polled on every instruction. All times are in
+/// emulated nanoseconds.
+///
 /// In addition to simulating these hardware registers, this
 /// implementation has a TX queue and an RX queue. These queues do not
 /// exist in hardware.  They are used to buffer data sent and received
@@ -37,6 +44,7 @@
 ///
 use crate::bus::{AccessCode, Device};
 use crate::err::BusError;
+use crate::sched::{Event, Scheduler};
 
 use crate::utils::FifoQueue;
 use log::{debug, trace};
@@ -44,16 +52,15 @@ use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::fmt::Error;
 use std::fmt::Formatter;
+use std::mem;
 use std::ops::Range;
-use std::time::Duration;
-use std::time::Instant;
 
 const START_ADDR: usize = 0x200000;
 const END_ADDR: usize = 0x2000040;
 const ADDRESS_RANGE: Range<usize> = START_ADDR..END_ADDR;
 
 // Vertical blanks should occur at 60Hz. This value is in nanoseconds
-const VERTICAL_BLANK_DELAY: u32 = 16_666_666; // 60 Hz
+const VERTICAL_BLANK_DELAY: u64 = 16_666_666; // 60 Hz
 
 const BAUD_RATES_A: [u32; 13] =
     [50, 110, 135, 200, 300, 600, 1200, 1050, 2400, 4800, 7200, 9600, 38400];
@@ -83,6 +90,11 @@ const IP_OPCR: u8 = 0x37;
 const OPBITS_SET: u8 = 0x3b;
 const OPBITS_RESET: u8 = 0x3f;
 
+//
+// Output Port Bits
+//
+const OP_PRINTER: u8 = 0x08; // OP3, low to route port B to the printer
+
 //
 // Port Configuration Bits
 //
@@ -139,6 +151,25 @@ const MOUSE_BLANK_INT: u8 = 0x02;
 const TX_INT: u8 = 0x10;
 const RX_INT: u8 = 0x20;
 
+//
+// Mouse buttons 0, 1 and 2: their active low input port bits, and the
+// IPCR bits that flag a change in them.
+//
+const MOUSE_BUTTONS: [(u8, u8); 3] = [(0x08, 0x80), (0x02, 0x20), (0x01, 0x10)];
+const MOUSE_CHANGE_BITS: u8 = 0xb0;
+
+/// How far the firmware has got with the last mouse button change.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ButtonChange {
+    /// Seen, or there has been none.
+    Seen,
+    /// Flagged in the IPCR, with an interrupt raised.
+    Raised,
+    /// The IPCR has been read, but not yet the input port.
+    Acknowledged,
+}
+
+#[derive(Clone)]
 struct Port {
     // Mode, Status, and Configuration registers
     mode: [u8; 2],
@@ -152,16 +183,21 @@ struct Port {
     tx_shift_reg: Option<u8>,
     // Buffers to hold TX and RX characters so that they can be
     // processed by the user of this library in chunks.
-    rx_deque: VecDeque<u8>,
+    // Received characters are marked if they arrive with a parity
+    // error.
+    rx_deque: VecDeque<(u8, bool)>,
     tx_deque: VecDeque<u8>,
+    // Hold queued characters back until the last one has been read,
+    // as a typist would, rather than overrunning the FIFO.
+    paced: bool,
     // Service timing info
-    char_delay: Duration,
-    next_tx_service: Instant,
-    next_rx_service: Instant,
+    char_delay: u64,
+    next_tx_service: u64,
+    next_rx_service: u64,
 }
 
 impl Port {
-    fn new() -> Port {
+    fn new(paced: bool) -> Port {
         Port {
             mode: [0; 2],
             mode_ptr: 0,
@@ -173,9 +209,10 @@ impl Port {
             tx_shift_reg: None,
             rx_deque: VecDeque::new(),
             tx_deque: VecDeque::new(),
-            char_delay: Duration::new(0, 1_000_000),
-            next_tx_service: Instant::now(),
-            next_rx_service: Instant::now(),
+            paced,
+            char_delay: 1_000_000,
+            next_tx_service: 0,
+            next_rx_service: 0,
         }
     }
 
@@ -249,34 +286,64 @@ impl Port {
         self.stat |= STS_RXR;
     }
 
+    /// The time at which the receiver next needs servicing, if it has
+    /// any work to do.
+    fn next_rx_event(&self) -> Option<u64> {
+        if self.rx_enabled() && !self.rx_deque.is_empty() {
+            Some(self.next_rx_service)
+        } else {
+            None
+        }
+    }
+
+    /// The time at which the transmitter next needs servicing, if it
+    /// has any work to do.
+    fn next_tx_event(&self) -> Option<u64> {
+        if self.tx_holding_reg.is_some() || self.tx_shift_reg.is_some() {
+            Some(self.next_tx_service)
+        } else {
+            None
+        }
+    }
+
     /// Move the receiver state machine
-    fn rx_service(&mut self) {
-        let rx_service_needed = self.rx_enabled()
-            && !self.rx_deque.is_empty()
-            && Instant::now() >= self.next_rx_service;
+    fn rx_service(&mut self, now: u64) {
+        let rx_service_needed =
+            self.rx_enabled() && !self.rx_deque.is_empty() && now >= self.next_rx_service;
 
         if !rx_service_needed {
             // Nothing to do.
             return;
         }
 
+        if self.paced && !self.rx_fifo.is_empty() {
+            self.next_rx_service = now + self.char_delay;
+            return;
+        }
+
         if !self.loopback() {
-            if let Some(c) = self.rx_deque.pop_back() {
+            if let Some((c, parity_error)) = self.rx_deque.pop_back() {
                 self.rx_char(c);
+                if parity_error {
+                    self.stat |= STS_PER;
+                }
             }
         }
 
-        self.next_rx_service = Instant::now() + self.char_delay;
+        self.next_rx_service = now + self.char_delay;
     }
 
-    /// Move the transmitter state machine.
-    fn tx_service(&mut self, keyboard: bool) {
+    /// Move the transmitter state machine, returning the character
+    /// that has finished transmitting, if any.
+    fn tx_service(&mut self, now: u64) -> Option<u8> {
+        let mut sent = None;
+
         if self.tx_holding_reg.is_none() && self.tx_shift_reg.is_none() {
             // Nothing to do
-            return;
+            return sent;
         }
 
-        if Instant::now() >= self.next_tx_service {
+        if now >= self.next_tx_service {
             // Check for data in the transmitter shift register that's
             // ready to go out to the RS232 output buffer
             if let Some(c) = self.tx_shift_reg {
@@ -285,11 +352,8 @@ impl Port {
                     debug!("RS232 TX: LOOPBACK: Finish transmit character {:02x}", c);
                     self.rx_char(c);
                 } else {
-                    if keyboard && c == 0x02 {
-                        self.stat |= STS_PER;
-                    }
                     debug!("RS232 TX: Finish transmit character {:02x}", c);
-                    self.tx_deque.push_front(c);
+                    sent = Some(c);
                 }
 
                 self.tx_shift_reg = None;
@@ -306,9 +370,11 @@ impl Port {
                 // Clear the holding register
                 self.tx_holding_reg = None;
                 // Ready for a new character
-                self.next_tx_service = Instant::now() + self.char_delay;
+                self.next_tx_service = now + self.char_delay;
             }
         }
+
+        sent
     }
 
     fn loopback(&self) -> bool {
@@ -345,6 +411,7 @@ impl Port {
     }
 }
 
+#[derive(Clone)]
 pub struct Duart {
     ports: [Port; 2],
     acr: u8,
@@ -357,7 +424,12 @@ pub struct Duart {
     // which doesn't actually exist on the real duart. We should fix
     // that, because DAMN.
     ivec: u8,
-    next_vblank: Instant,
+    next_vblank: u64,
+    button_change: ButtonChange,
+    // Characters sent on the keyboard port, for the keyboard model.
+    keyboard_commands: VecDeque<u8>,
+    // Characters sent to the printer, which shares port B.
+    printer_deque: VecDeque<u8>,
 }
 
 impl Default for Duart {
@@ -366,10 +438,11 @@ impl Default for Duart {
     }
 }
 
-/// Compute the delay rate to wait for the next transmit or receive
-fn delay_rate(csr_bits: u8, acr_bits: u8) -> Duration {
-    const NS_PER_SEC: u32 = 1_000_000_000;
-    const BITS_PER_CHAR: u32 = 8;
+/// Compute the delay rate, in nanoseconds, to wait for the next
+/// transmit or receive
+fn delay_rate(csr_bits: u8, acr_bits: u8) -> u64 {
+    const NS_PER_SEC: u64 = 1_000_000_000;
+    const BITS_PER_CHAR: u64 = 8;
 
     let baud_bits: usize = ((csr_bits >> 4) & 0xf) as usize;
     let baud_rate = if acr_bits & 0x80 == 0 {
@@ -378,13 +451,13 @@ fn delay_rate(csr_bits: u8, acr_bits: u8) -> Duration {
         BAUD_RATES_B[baud_bits]
     };
 
-    Duration::new(0, NS_PER_SEC / (baud_rate / BITS_PER_CHAR))
+    NS_PER_SEC / (u64::from(baud_rate) / BITS_PER_CHAR)
 }
 
 impl Duart {
     pub fn new() -> Duart {
         Duart {
-            ports: [Port::new(), Port::new()],
+            ports: [Port::new(false), Port::new(true)],
             acr: 0,
             ipcr: 0x40,
             inprt: 0xb,
@@ -392,16 +465,14 @@ impl Duart {
             isr: 0,
             imr: 0,
             ivec: 0,
-            next_vblank: Instant::now() + Duration::new(0, VERTICAL_BLANK_DELAY),
+            next_vblank: VERTICAL_BLANK_DELAY,
+            button_change: ButtonChange::Seen,
+            keyboard_commands: VecDeque::new(),
+            printer_deque: VecDeque::new(),
         }
     }
 
     pub fn get_interrupt(&mut self) -> Option<u8> {
-        if Instant::now() > self.next_vblank {
-            self.next_vblank = Instant::now() + Duration::new(0, VERTICAL_BLANK_DELAY);
-            self.vertical_blank();
-        }
-
         // Mask in keyboard and RS232 RX interrupts
         if (self.ports[PORT_0].stat & STS_RXR) != 0 {
             self.ivec |= RX_INT;
@@ -427,11 +498,56 @@ impl Duart {
         }
     }
 
-    pub fn service(&mut self) {
-        self.ports[PORT_0].tx_service(false);
-        self.ports[PORT_0].rx_service();
-        self.ports[PORT_1].tx_service(true);
-        self.ports[PORT_1].rx_service();
+    /// Register the times at which the DUART next needs servicing.
+    pub fn schedule(&self, scheduler: &mut Scheduler) {
+        let events = [
+            (Event::DuartTxA, self.ports[PORT_0].next_tx_event()),
+            (Event::DuartRxA, self.ports[PORT_0].next_rx_event()),
+            (Event::DuartTxB, self.ports[PORT_1].next_tx_event()),
+            (Event::DuartRxB, self.ports[PORT_1].next_rx_event()),
+            (Event::VerticalBlank, Some(self.next_vblank)),
+        ];
+
+        for (event, at) in events.iter() {
+            match at {
+                Some(at) => scheduler.schedule(*event, *at),
+                None => scheduler.cancel(*event),
+            }
+        }
+    }
+
+    /// Handle a scheduled event that has come due. Returns the
+    /// character the RS232 port has finished sending, if any.
+    pub fn service(&mut self, event: Event, now: u64) -> Option<u8> {
+        let mut sent = None;
+
+        match event {
+            Event::DuartTxA => {
+                if let Some(c) = self.ports[PORT_0].tx_service(now) {
+                    self.ports[PORT_0].tx_deque.push_front(c);
+                    sent = Some(c);
+                }
+            }
+            Event::DuartRxA => self.ports[PORT_0].rx_service(now),
+            Event::DuartTxB => {
+                if let Some(c) = self.ports[PORT_1].tx_service(now) {
+                    if self.printer_selected() {
+                        self.printer_deque.push_front(c);
+                    } else {
+                        self.ports[PORT_1].tx_deque.push_front(c);
+                        self.keyboard_commands.push_front(c);
+                    }
+                }
+            }
+            Event::DuartRxB => self.ports[PORT_1].rx_service(now),
+            Event::VerticalBlank => {
+                self.next_vblank = now + VERTICAL_BLANK_DELAY;
+                self.vertical_blank();
+            }
+            _ => {}
+        }
+
+        sent
     }
 
     pub fn vertical_blank(&mut self) {
@@ -454,12 +570,24 @@ impl Duart {
 
     /// Queue a single character for processing by the rs232 port.
     pub fn rs232_rx(&mut self, c: u8) {
-        self.ports[PORT_0].rx_deque.push_front(c);
+        self.ports[PORT_0].rx_deque.push_front((c, false));
     }
 
     /// Queue a single character for processing by the keyboard port
     pub fn keyboard_rx(&mut self, c: u8) {
-        self.ports[PORT_1].rx_deque.push_front(c);
+        self.ports[PORT_1].rx_deque.push_front((c, false));
+    }
+
+    /// Queue a status byte from the keyboard, which arrives with a
+    /// parity error to set it apart from key codes.
+    pub fn keyboard_status(&mut self, c: u8) {
+        self.ports[PORT_1].rx_deque.push_front((c, true));
+    }
+
+    /// The next command the firmware has sent to the keyboard.
+    /// Commands are also left for `keyboard_tx`.
+    pub fn keyboard_command(&mut self) -> Option<u8> {
+        self.keyboard_commands.pop_back()
     }
 
     pub fn rs232_tx(&mut self) -> Option<u8> {
@@ -470,45 +598,48 @@ impl Duart {
         self.ports[PORT_1].tx_deque.pop_back()
     }
 
-    pub fn mouse_down(&mut self, button: u8) {
-        self.ipcr = 0;
-        self.inprt |= 0xb;
-        self.isr |= ISTS_IPC;
-        self.ivec |= MOUSE_BLANK_INT;
-        match button {
-            0 => {
-                self.ipcr |= 0x80;
-                self.inprt &= !(0x08);
-            }
-            1 => {
-                self.ipcr |= 0x20;
-                self.inprt &= !(0x02);
-            }
-            2 => {
-                self.ipcr |= 0x10;
-                self.inprt &= !(0x01)
-            }
-            _ => {}
+    pub fn printer_tx(&mut self) -> Option<u8> {
+        self.printer_deque.pop_back()
+    }
+
+    /// Swap the characters waiting for the user of this library with
+    /// those of another DUART.
+    pub fn swap_output(&mut self, other: &mut Duart) {
+        for (port, other_port) in self.ports.iter_mut().zip(other.ports.iter_mut()) {
+            mem::swap(&mut port.tx_deque, &mut other_port.tx_deque);
         }
+        mem::swap(&mut self.printer_deque, &mut other.printer_deque);
+    }
+
+    /// True while OP3 is low, routing port B output to the printer.
+    /// The pins are the complement of the output port register.
+    fn printer_selected(&self) -> bool {
+        self.outprt & OP_PRINTER != 0
     }
 
-    pub fn mouse_up(&mut self, button: u8) {
-        self.ipcr = 0;
-        self.inprt |= 0xb;
+    /// Press or release a mouse button, and raise an input port
+    /// change interrupt for it. Only one change should be made at a
+    /// time: see `mouse_button_seen`.
+    pub fn mouse_button(&mut self, button: u8, down: bool) {
+        let (bit, change) = match MOUSE_BUTTONS.get(usize::from(button)) {
+            Some(bits) => *bits,
+            None => return,
+        };
+        if down {
+            self.inprt &= !bit;
+        } else {
+            self.inprt |= bit;
+        }
+        self.ipcr |= change;
         self.isr |= ISTS_IPC;
         self.ivec |= MOUSE_BLANK_INT;
-        match button {
-            0 => {
-                self.ipcr |= 0x80;
-            }
-            1 => {
-                self.ipcr |= 0x20;
-            }
-            2 => {
-                self.ipcr |= 0x10;
-            }
-            _ => {}
-        }
+        self.button_change = ButtonChange::Raised;
+    }
+
+    /// True once the firmware has handled the last mouse button
+    /// change, by reading the IPCR and then the input port.
+    pub fn mouse_button_seen(&self) -> bool {
+        self.button_change == ButtonChange::Seen
     }
 
     fn handle_command(&mut self, cmd: u8, port_no: usize) {
@@ -651,17 +782,16 @@ impl Device for Duart {
                 let ctx = &mut self.ports[PORT_0];
                 self.isr &= !ISTS_RAI;
                 self.ivec &= !RX_INT;
-                let val = if let Some(c) = ctx.rx_read_char() {
-                    c
-                } else {
-                    0
-                };
+                let val = ctx.rx_read_char().unwrap_or_default();
                 debug!("READ : RHRA, val={:02x}", val);
                 Ok(val)
             }
             IPCR_ACR => {
                 let val = self.ipcr;
-                self.ipcr &= !0x0f;
+                self.ipcr &= !(0x0f | MOUSE_CHANGE_BITS);
+                if self.button_change == ButtonChange::Raised {
+                    self.button_change = ButtonChange::Acknowledged;
+                }
                 self.ivec = 0;
                 self.isr &= !ISTS_IPC;
                 trace!("READ : IPCR_ACR, val={:02x}", val);
@@ -688,16 +818,15 @@ impl Device for Duart {
                 let ctx = &mut self.ports[PORT_1];
                 self.isr &= !ISTS_RAI;
                 self.ivec &= !KEYBOARD_INT;
-                let val = if let Some(c) = ctx.rx_read_char() {
-                    c
-                } else {
-                    0
-                };
+                let val = ctx.rx_read_char().unwrap_or_default();
                 debug!("READ : RHRB, val={:02x}", val);
                 Ok(val)
             }
             IP_OPCR => {
                 let val = self.inprt;
+                if self.button_change == ButtonChange::Acknowledged {
+                    self.button_change = ButtonChange::Seen;
+                }
                 trace!("READ : IP_OPCR val={:02x}", val);
                 Ok(val)
             }
@@ -769,8 +898,6 @@ impl Device for Duart {
             }
             THRB => {
                 debug!("WRITE: THRB, val={:02x}", val);
-                // TODO: When OP3 is low, do not send data to
-                // the keyboard! It's meant for the printer.
                 let ctx = &mut self.ports[PORT_1];
                 ctx.tx_holding_reg = Some(val);
                 // TxRDY and TxEMT are both de-asserted on load.
@@ -809,3 +936,61 @@ impl Device for Duart {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAR_DELAY: u64 = 1_000_000;
+
+    /// Enable both receivers, queue `count` characters on a port, and
+    /// give the receiver time for all of them without reading any.
+    fn flood(duart: &mut Duart, port: usize, count: u8) -> u64 {
+        duart.write_byte(START_ADDR + CRA as usize, CMD_ERX, AccessCode::Write).unwrap();
+        duart.write_byte(START_ADDR + CRB as usize, CMD_ERX, AccessCode::Write).unwrap();
+        for c in 0..count {
+            duart.ports[port].rx_deque.push_front((b'a' + c, false));
+        }
+        let event = [Event::DuartRxA, Event::DuartRxB][port];
+        let mut now = 0;
+        for _ in 0..count {
+            duart.service(event, now);
+            now += CHAR_DELAY;
+        }
+        now
+    }
+
+    #[test]
+    fn holds_keyboard_input_back_until_the_fifo_drains() {
+        let mut duart = Duart::new();
+        let csrb = START_ADDR + CSRB as usize;
+        let rhrb = START_ADDR + RHRB as usize;
+        let mut now = flood(&mut duart, PORT_1, 5);
+
+        // Only the first character has been received, and nothing
+        // has been lost
+        let status = duart.read_byte(csrb, AccessCode::AddressFetch).unwrap();
+        assert_eq!(STS_RXR, status & (STS_RXR | STS_FFL | STS_OER));
+        assert_eq!(4, duart.ports[PORT_1].rx_deque.len());
+
+        // Each held-back character arrives once the last is read
+        for c in b"abcde".iter() {
+            assert_eq!(*c, duart.read_byte(rhrb, AccessCode::AddressFetch).unwrap());
+            let status = duart.read_byte(csrb, AccessCode::AddressFetch).unwrap();
+            assert_eq!(0, status & STS_RXR);
+            duart.service(Event::DuartRxB, now);
+            now += CHAR_DELAY;
+        }
+        assert!(duart.ports[PORT_1].rx_deque.is_empty());
+    }
+
+    #[test]
+    fn overruns_the_rs232_fifo() {
+        let mut duart = Duart::new();
+        flood(&mut duart, PORT_0, 5);
+
+        let status = duart.read_byte(START_ADDR + CSRA as usize, AccessCode::AddressFetch).unwrap();
+        assert_eq!(STS_RXR | STS_FFL, status & (STS_RXR | STS_FFL));
+        assert!(duart.ports[PORT_0].rx_deque.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+//! Snapshots of the terminal, for stepping backwards.
+//!
+//! While time travel is enabled, the terminal saves its state every so
+//! many instructions, and the bus logs every input applied. To go back
+//! to an earlier instruction, the latest snapshot taken at or before
+//! it is restored, and the terminal runs forward again from there,
+//! given the same inputs at the same times. Emulation is deterministic
+//! given its input, so it arrives in exactly the state it was in.
+//!
+//! Output the host has already seen is not made again: characters
+//! sent, frames recorded and printer output made while running forward
+//! are dropped.
+
+use crate::bus::BusState;
+use crate::cpu::CpuState;
+
+use std::collections::VecDeque;
+
+use thiserror::Error;
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum TimeTravelError {
+    #[error("time travel is not enabled")]
+    NotEnabled,
+    #[error("instruction {0} is before the earliest snapshot")]
+    TooFarBack(u64),
+    #[error("instruction {0} has not been reached yet")]
+    InFuture(u64),
+    #[error("cannot rewind while logging a session")]
+    SessionInProgress,
+}
+
+pub(crate) struct Snapshot {
+    /// The number of instructions executed since reset.
+    pub steps: u64,
+    pub cpu: CpuState,
+    pub bus: BusState,
+    /// The number of inputs logged before the snapshot was taken,
+    /// counting from the start of the history.
+    pub inputs: usize,
+}
+
+pub(crate) struct History {
+    interval: u64,
+    limit: usize,
+    snapshots: VecDeque<Snapshot>,
+    // The number of inputs dropped from the start of the bus's log
+    // along with the snapshots that needed them.
+    inputs_dropped: usize,
+}
+
+impl History {
+    pub fn new(interval: u64, limit: usize) -> History {
+        History {
+            interval: interval.max(1),
+            limit: limit.max(1),
+            snapshots: VecDeque::new(),
+            inputs_dropped: 0,
+        }
+    }
+
+    /// The interval between snapshots, and the number kept.
+    pub fn settings(&self) -> (u64, usize) {
+        (self.interval, self.limit)
+    }
+
+    /// True if a snapshot is due before the given instruction.
+    pub fn snapshot_due(&self, steps: u64) -> bool {
+        steps % self.interval == 0 && self.snapshots.back().map_or(true, |last| last.steps < steps)
+    }
+
+    /// Keep a snapshot, dropping the earliest if there are too many.
+    /// Returns the number of inputs at the start of the bus's log that
+    /// are no longer needed.
+    pub fn push(&mut self, snapshot: Snapshot) -> usize {
+        self.snapshots.push_back(snapshot);
+        if self.snapshots.len() <= self.limit {
+            return 0;
+        }
+        self.snapshots.pop_front();
+        let first = self.snapshots.front().map_or(0, |s| s.inputs);
+        let unneeded = first - self.inputs_dropped;
+        self.inputs_dropped = first;
+        unneeded
+    }
+
+    /// The number of inputs logged, counting from the start of the
+    /// history, given the length of the bus's log.
+    pub fn inputs_logged(&self, log_len: usize) -> usize {
+        self.inputs_dropped + log_len
+    }
+
+    /// The instruction at which the earliest snapshot was taken.
+    pub fn earliest(&self) -> Option<u64> {
+        self.snapshots.front().map(|s| s.steps)
+    }
+
+    /// Drop the snapshots taken after the given instruction, and return
+    /// the latest of those left, with the index in the bus's log of the
+    /// first input applied after it was taken.
+    pub fn rewind(&mut self, steps: u64) -> Result<(&Snapshot, usize), TimeTravelError> {
+        match self.earliest() {
+            Some(earliest) if earliest <= steps => {}
+            _ => return Err(TimeTravelError::TooFarBack(steps)),
+        }
+        while self.snapshots.back().map_or(false, |last| last.steps > steps) {
+            self.snapshots.pop_back();
+        }
+        let snapshot = self.snapshots.back().ok_or(TimeTravelError::TooFarBack(steps))?;
+        Ok((snapshot, snapshot.inputs - self.inputs_dropped))
+    }
+}
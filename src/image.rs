@@ -0,0 +1,230 @@
+//! Encoding the framebuffer as image files.
+//!
+//! Both formats are written as 1-bit images, 800 pixels wide and 1024
+//! high. A lit pixel is white, unless the video is inverted, in which
+//! case it is black.
+//!
+//! PNG requires zlib compressed image data. The framebuffer is small,
+//! so rather than carrying a compressor, the data is written in
+//! uncompressed ("stored") deflate blocks, which any decoder accepts.
+
+use crate::display::{FRAMEBUFFER_LEN, HEIGHT, STRIDE, WIDTH};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// The largest deflate block that can be stored uncompressed.
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImageFormat {
+    /// Netpbm bitmap, binary ("P4") variant.
+    Pbm,
+    Png,
+}
+
+/// Encode a framebuffer of `FRAMEBUFFER_LEN` bytes. If it is shorter,
+/// the rest of the image is blank.
+pub fn encode(framebuffer: &[u8], inverted: bool, format: ImageFormat) -> Vec<u8> {
+    match format {
+        ImageFormat::Pbm => encode_pbm(framebuffer, inverted),
+        ImageFormat::Png => encode_png(framebuffer, inverted),
+    }
+}
+
+/// Yield each scanline of the framebuffer, with white pixels set.
+fn scanlines(framebuffer: &[u8], inverted: bool) -> impl Iterator<Item = [u8; STRIDE]> + '_ {
+    let mask = if inverted {
+        0xff
+    } else {
+        0
+    };
+    (0..HEIGHT).map(move |y| {
+        let mut line = [mask; STRIDE];
+        let start = (y * STRIDE).min(framebuffer.len());
+        let end = ((y + 1) * STRIDE).min(framebuffer.len());
+        for (dst, src) in line.iter_mut().zip(&framebuffer[start..end]) {
+            *dst = src ^ mask;
+        }
+        line
+    })
+}
+
+fn encode_pbm(framebuffer: &[u8], inverted: bool) -> Vec<u8> {
+    let mut out = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+    out.reserve(FRAMEBUFFER_LEN);
+    // PBM sets the bits of black pixels
+    for line in scanlines(framebuffer, inverted) {
+        out.extend(line.iter().map(|b| !b));
+    }
+    out
+}
+
+fn encode_png(framebuffer: &[u8], inverted: bool) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(FRAMEBUFFER_LEN + HEIGHT);
+    for line in scanlines(framebuffer, inverted) {
+        // Filter type 0, none
+        raw.push(0);
+        raw.extend_from_slice(&line);
+    }
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut out, b"IHDR", &png_header());
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// The IHDR chunk data for a 1-bit greyscale image of the screen.
+pub(crate) fn png_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(HEIGHT as u32).to_be_bytes());
+    // Bit depth 1, greyscale, deflate, no filtering, no interlace
+    header.extend_from_slice(&[1, 0, 0, 0, 0]);
+    header
+}
+
+/// Append a PNG chunk, with its length and CRC.
+pub(crate) fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wrap data in a zlib stream of stored deflate blocks.
+pub(crate) fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len() / MAX_STORED_BLOCK + 1;
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+
+    // Deflate, 32K window, no preset dictionary, fastest compression
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Undo `zlib_stored`, checking its framing along the way.
+    fn unstore(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(&[0x78, 0x01], &zlib[0..2]);
+        let mut data = Vec::new();
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos] == 1;
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]) as usize;
+            let nlen = u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]) as usize;
+            assert_eq!(0xffff, len ^ nlen);
+            data.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(&adler32(&data).to_be_bytes(), &zlib[pos..]);
+        data
+    }
+
+    #[test]
+    fn computes_checksums() {
+        assert_eq!(0xae426082, crc32(b"IEND"));
+        assert_eq!(0x11e60398, adler32(b"Wikipedia"));
+        assert_eq!(1, adler32(&[]));
+    }
+
+    #[test]
+    fn encodes_pbm() {
+        let mut framebuffer = vec![0; FRAMEBUFFER_LEN];
+        framebuffer[0] = 0x80;
+
+        let pbm = encode(&framebuffer, false, ImageFormat::Pbm);
+        let header = b"P4\n800 1024\n";
+        assert_eq!(header, &pbm[..header.len()]);
+        assert_eq!(header.len() + FRAMEBUFFER_LEN, pbm.len());
+        assert_eq!(0x7f, pbm[header.len()]);
+        assert_eq!(0xff, pbm[header.len() + 1]);
+
+        let pbm = encode(&framebuffer, true, ImageFormat::Pbm);
+        assert_eq!(0x80, pbm[header.len()]);
+        assert_eq!(0x00, pbm[header.len() + 1]);
+    }
+
+    #[test]
+    fn encodes_png() {
+        // Short framebuffers are padded with blank scanlines
+        let png = encode(&[0xf0, 0x0f], false, ImageFormat::Png);
+        assert_eq!(&PNG_SIGNATURE, &png[0..8]);
+
+        let mut pos = 8;
+        let mut kinds = Vec::new();
+        let mut idat = Vec::new();
+        while pos < png.len() {
+            let len = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]);
+            let end = pos + 8 + len as usize;
+            let crc = crc32(&png[pos + 4..end]);
+            assert_eq!(&crc.to_be_bytes(), &png[end..end + 4]);
+            kinds.push(png[pos + 4..pos + 8].to_vec());
+            if &png[pos + 4..pos + 8] == b"IDAT" {
+                idat = png[pos + 8..end].to_vec();
+            }
+            pos = end + 4;
+        }
+        assert_eq!(vec![b"IHDR".to_vec(), b"IDAT".to_vec(), b"IEND".to_vec()], kinds);
+
+        let raw = unstore(&idat);
+        assert_eq!(HEIGHT * (STRIDE + 1), raw.len());
+        assert_eq!(&[0, 0xf0, 0x0f, 0], &raw[0..4]);
+        assert!(raw[STRIDE + 1..].iter().all(|b| *b == 0));
+
+        let png = encode(&[0xf0, 0x0f], true, ImageFormat::Png);
+        let raw = unstore(&png[8 + 12 + 13 + 8..png.len() - 12 - 4]);
+        assert_eq!(&[0, 0x0f, 0xf0, 0xff], &raw[0..4]);
+    }
+}
@@ -0,0 +1,293 @@
+//! Input applied at set points in emulated time.
+//!
+//! Input passed straight to the devices lands wherever the CPU happens
+//! to be when the host gets round to it, so two runs given the same
+//! input seldom behave the same. Queued input instead carries the
+//! emulated time at which it is to be applied, and the bus applies it
+//! once the CPU has run up to that time. The same inputs at the same
+//! times then give the same run, however quickly the host sends them.
+//!
+//! An input queued for a time lands exactly where a direct call made
+//! when `now` returned that time would have: after the instruction
+//! executing then, and ahead of any device event that comes due
+//! before the next one. Direct input can therefore be replayed by
+//! queueing it for the time at which it was made. Inputs queued for
+//! the same time are applied in the order they were queued, and one
+//! queued for a time already passed is applied before the next
+//! instruction.
+//!
+//! Inputs are written as text, one to a line, such as `rs232_rx 0d`,
+//! `key_press shift a`, or `mouse_move_to 400 512`. Characters are in
+//! hex, and keys are written as for `Key::from_str`.
+
+use crate::keyboard::{Key, Modifiers};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum InputError {
+    #[error("unknown input {0:?}")]
+    Unknown(String),
+    #[error("invalid arguments for {0}")]
+    Arguments(String),
+}
+
+/// One input to the terminal, as made by the matching `Dmd` method.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Input {
+    /// A character received on the RS232 port.
+    Rs232Rx(u8),
+    /// A raw keycode received from the keyboard.
+    KeyboardRx(u8),
+    /// A key pressed on the keyboard model, with modifiers held.
+    KeyPress(Key, Modifiers),
+    /// CAPS LOCK turned on or off.
+    CapsLock(bool),
+    /// The mouse counters set directly.
+    MouseMove(u16, u16),
+    /// The mouse moved to a point on the screen.
+    MouseMoveTo(i32, i32),
+    /// The mouse moved by a distance on the screen.
+    MouseMoveBy(i32, i32),
+    MouseDown(u8),
+    MouseUp(u8),
+    /// A character received on an SCC channel.
+    SccRx(u8, u8),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::Rs232Rx(c) => write!(f, "rs232_rx {:02x}", c),
+            Input::KeyboardRx(keycode) => write!(f, "keyboard_rx {:02x}", keycode),
+            Input::KeyPress(key, modifiers) => {
+                let modifiers = match (modifiers.shift, modifiers.control) {
+                    (false, false) => "-",
+                    (true, false) => "shift",
+                    (false, true) => "control",
+                    (true, true) => "shift+control",
+                };
+                write!(f, "key_press {} {}", modifiers, key)
+            }
+            Input::CapsLock(on) => write!(
+                f,
+                "caps_lock {}",
+                if *on {
+                    "on"
+                } else {
+                    "off"
+                }
+            ),
+            Input::MouseMove(x, y) => write!(f, "mouse_move {} {}", x, y),
+            Input::MouseMoveTo(x, y) => write!(f, "mouse_move_to {} {}", x, y),
+            Input::MouseMoveBy(dx, dy) => write!(f, "mouse_move_by {} {}", dx, dy),
+            Input::MouseDown(button) => write!(f, "mouse_down {}", button),
+            Input::MouseUp(button) => write!(f, "mouse_up {}", button),
+            Input::SccRx(channel, c) => write!(f, "scc_rx {} {:02x}", channel, c),
+        }
+    }
+}
+
+impl FromStr for Input {
+    type Err = InputError;
+
+    /// Parse an input as written by `Display`.
+    fn from_str(s: &str) -> Result<Input, InputError> {
+        let (name, rest) = s.split_once(' ').unwrap_or((s, ""));
+        let bad = || InputError::Arguments(name.to_string());
+
+        // The key pressed is the rest of the line, so that it may be
+        // a space
+        if name == "key_press" {
+            let (modifiers, key) = rest.split_once(' ').ok_or_else(bad)?;
+            let modifiers = match modifiers {
+                "-" => Modifiers::NONE,
+                "shift" => Modifiers {
+                    shift: true,
+                    control: false,
+                },
+                "control" => Modifiers {
+                    shift: false,
+                    control: true,
+                },
+                "shift+control" => Modifiers {
+                    shift: true,
+                    control: true,
+                },
+                _ => return Err(bad()),
+            };
+            let key = key.parse().map_err(|_| bad())?;
+            return Ok(Input::KeyPress(key, modifiers));
+        }
+
+        let args: Vec<&str> = rest.split_whitespace().collect();
+        let hex = |i: usize| args.get(i).and_then(|a| u8::from_str_radix(a, 16).ok());
+        let int = |i: usize| args.get(i).and_then(|a| a.parse::<i32>().ok());
+        let count = match name {
+            "rs232_rx" | "keyboard_rx" | "caps_lock" | "mouse_down" | "mouse_up" => 1,
+            "mouse_move" | "mouse_move_to" | "mouse_move_by" | "scc_rx" => 2,
+            _ => return Err(InputError::Unknown(name.to_string())),
+        };
+        if args.len() != count {
+            return Err(bad());
+        }
+
+        let input = match name {
+            "rs232_rx" => hex(0).map(Input::Rs232Rx),
+            "keyboard_rx" => hex(0).map(Input::KeyboardRx),
+            "caps_lock" => match args[0] {
+                "on" => Some(Input::CapsLock(true)),
+                "off" => Some(Input::CapsLock(false)),
+                _ => None,
+            },
+            "mouse_move" => {
+                let x = args[0].parse().ok();
+                let y = args[1].parse().ok();
+                x.zip(y).map(|(x, y)| Input::MouseMove(x, y))
+            }
+            "mouse_move_to" => int(0).zip(int(1)).map(|(x, y)| Input::MouseMoveTo(x, y)),
+            "mouse_move_by" => int(0).zip(int(1)).map(|(dx, dy)| Input::MouseMoveBy(dx, dy)),
+            "mouse_down" => args[0].parse().ok().map(Input::MouseDown),
+            "mouse_up" => args[0].parse().ok().map(Input::MouseUp),
+            _ => args[0].parse().ok().zip(hex(1)).map(|(channel, c)| Input::SccRx(channel, c)),
+        };
+        input.ok_or_else(bad)
+    }
+}
+
+/// An input and the emulated time, in nanoseconds since reset, at
+/// which it is applied.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimedInput {
+    pub at: u64,
+    pub input: Input,
+}
+
+/// Inputs waiting for their time, earliest first.
+#[derive(Debug, Default)]
+pub struct InputQueue {
+    inputs: VecDeque<TimedInput>,
+}
+
+impl InputQueue {
+    pub fn new() -> InputQueue {
+        InputQueue {
+            inputs: VecDeque::new(),
+        }
+    }
+
+    /// Queue an input, after any others queued for the same time.
+    pub fn push(&mut self, at: u64, input: Input) {
+        let index = self.inputs.partition_point(|queued| queued.at <= at);
+        self.inputs.insert(
+            index,
+            TimedInput {
+                at,
+                input,
+            },
+        );
+    }
+
+    /// The time at which the earliest input comes due, which is as
+    /// soon as time has moved on from the time it was queued for.
+    pub fn next_due(&self) -> Option<u64> {
+        self.inputs.front().map(|queued| queued.at.saturating_add(1))
+    }
+
+    /// Remove and return the earliest input, if it is due by `now`.
+    pub fn pop_due(&mut self, now: u64) -> Option<TimedInput> {
+        match self.inputs.front() {
+            Some(queued) if queued.at < now => self.inputs.pop_front(),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.inputs.clear();
+    }
+
+    /// Remove and return all the queued inputs, earliest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = TimedInput> + '_ {
+        self.inputs.drain(..)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_inputs_in_time_order() {
+        let mut queue = InputQueue::new();
+        queue.push(3_000, Input::Rs232Rx(b'c'));
+        queue.push(1_000, Input::Rs232Rx(b'a'));
+        queue.push(3_000, Input::MouseDown(0));
+        queue.push(2_000, Input::Rs232Rx(b'b'));
+
+        assert_eq!(Some(1_001), queue.next_due());
+        assert_eq!(None, queue.pop_due(1_000));
+        assert_eq!(Some(Input::Rs232Rx(b'a')), queue.pop_due(1_001).map(|q| q.input));
+        assert_eq!(None, queue.pop_due(1_001).map(|q| q.input));
+
+        assert_eq!(Some(Input::Rs232Rx(b'b')), queue.pop_due(5_000).map(|q| q.input));
+        assert_eq!(Some(Input::Rs232Rx(b'c')), queue.pop_due(5_000).map(|q| q.input));
+        assert_eq!(Some(Input::MouseDown(0)), queue.pop_due(5_000).map(|q| q.input));
+        assert!(queue.is_empty());
+        assert_eq!(None, queue.next_due());
+
+        // An input queued for the end of time never comes due
+        queue.push(u64::MAX, Input::Rs232Rx(b'z'));
+        assert_eq!(Some(u64::MAX), queue.next_due());
+        assert_eq!(None, queue.pop_due(u64::MAX));
+    }
+
+    #[test]
+    fn writes_and_parses_inputs() {
+        let inputs = [
+            Input::Rs232Rx(0x0d),
+            Input::KeyboardRx(0xc1),
+            Input::KeyPress(Key::Char(b' '), Modifiers::NONE),
+            Input::KeyPress(
+                Key::Pf(3),
+                Modifiers {
+                    shift: true,
+                    control: true,
+                },
+            ),
+            Input::CapsLock(true),
+            Input::MouseMove(4095, 0),
+            Input::MouseMoveTo(400, 512),
+            Input::MouseMoveBy(-3, 7),
+            Input::MouseDown(2),
+            Input::MouseUp(0),
+            Input::SccRx(1, 0x41),
+        ];
+        for input in inputs.iter() {
+            assert_eq!(Ok(*input), input.to_string().parse());
+        }
+        assert_eq!("key_press -  ", Input::KeyPress(Key::Char(b' '), Modifiers::NONE).to_string());
+
+        assert_eq!(Err(InputError::Unknown("jump".to_string())), "jump 3".parse::<Input>());
+        assert_eq!(Err(InputError::Arguments("rs232_rx".to_string())), "rs232_rx".parse::<Input>());
+        assert_eq!(
+            Err(InputError::Arguments("rs232_rx".to_string())),
+            "rs232_rx 100".parse::<Input>()
+        );
+        assert_eq!(
+            Err(InputError::Arguments("key_press".to_string())),
+            "key_press meta a".parse::<Input>()
+        );
+    }
+}
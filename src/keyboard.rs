@@ -0,0 +1,655 @@
+//! Translating host key events into 5620 keyboard codes.
+//!
+//! The 5620 keyboard sends one byte per key press. Keys that type an
+//! ASCII character send that character, or its control character
+//! while CTRL is held. The other keys send codes with the high bit
+//! set, which the firmware translates through a table in ROM:
+//!
+//! ```text
+//!   0xae       SETUP
+//!   0xba       BREAK
+//!   0xc0-0xc4  Home, Up, Down, Right, Left; keypad 1, 2, 0, ., -
+//!   0xc6       Home Down; keypad 3
+//!   0xc7       Keypad ENTER
+//!   0xc8-0xcf  PF1-PF8 with SHIFT
+//!   0xd2-0xd7  Keypad 7, 4, 8, 5, 9, 6
+//!   0xe5       CLEAR
+//!   0xe8-0xef  PF1-PF8
+//! ```
+//!
+//! The cursor keys share their codes with the bottom of the numeric
+//! keypad, and the firmware decides which they are from its keypad
+//! mode.
+//!
+//! The firmware controls the keyboard with single byte commands. Each
+//! command carries the keyboard's whole state, so the bits below are
+//! resent with every command:
+//!
+//! ```text
+//!   0x02       Request status
+//!   0x04       Reset, sent as the terminal restarts
+//!   0x08       Sound the bell
+//!   0x10       Key click on
+//! ```
+//!
+//! The keyboard answers a status request, and reports changes to its
+//! CAPS LOCK and REPEAT keys, with a status byte sent with bad parity
+//! to set it apart from key codes. A set 0x04 bit means CAPS LOCK is
+//! off, and a set 0x10 bit that REPEAT is up. The firmware shows CAPS
+//! LOCK on screen, so the keyboard's CAPS LOCK lamp is the only one
+//! it has.
+//!
+//! There is no command asking the keyboard to identify itself. Only
+//! one keyboard was made for the 5620, and the firmware never asks
+//! which is attached, so a status request is the only request the
+//! keyboard answers.
+//!
+//! A `Layout` maps host key names to 5620 `Key`s. The standard layout
+//! uses X11 keysym names, so "F1" is PF1, "F9" is SETUP and "Pause"
+//! is BREAK, and each printable character is named by itself. Other
+//! layouts can be built with `bind`, or read from text with
+//! `Layout::parse`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+
+use log::debug;
+use thiserror::Error;
+
+pub const PF_KEY_COUNT: u8 = 8;
+
+const KEY_SETUP: u8 = 0xae;
+const KEY_BREAK: u8 = 0xba;
+const KEY_HOME: u8 = 0xc0;
+const KEY_UP: u8 = 0xc1;
+const KEY_DOWN: u8 = 0xc2;
+const KEY_RIGHT: u8 = 0xc3;
+const KEY_LEFT: u8 = 0xc4;
+const KEY_HOME_DOWN: u8 = 0xc6;
+const KEY_ENTER: u8 = 0xc7;
+const KEY_SHIFT_PF1: u8 = 0xc8;
+const KEY_CLEAR: u8 = 0xe5;
+const KEY_PF1: u8 = 0xe8;
+
+// Commands from the terminal
+const CMD_STATUS: u8 = 0x02;
+const CMD_RESET: u8 = 0x04;
+const CMD_BELL: u8 = 0x08;
+const CMD_CLICK: u8 = 0x10;
+
+// Status sent to the terminal
+const STATUS_CAPS_OFF: u8 = 0x04;
+const STATUS_REPEAT_UP: u8 = 0x10;
+
+/// The most events kept for the host. Older events are dropped to
+/// make room for new ones if the host does not take them.
+pub const EVENT_LIMIT: usize = 64;
+
+/// Codes of the numeric keypad keys.
+const KEYPAD: [(u8, u8); 12] = [
+    (b'0', KEY_DOWN),
+    (b'1', KEY_HOME),
+    (b'2', KEY_UP),
+    (b'3', KEY_HOME_DOWN),
+    (b'4', 0xd3),
+    (b'5', 0xd5),
+    (b'6', 0xd7),
+    (b'7', 0xd2),
+    (b'8', 0xd4),
+    (b'9', 0xd6),
+    (b'.', KEY_RIGHT),
+    (b'-', KEY_LEFT),
+];
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum KeyboardError {
+    #[error("unknown 5620 key {0:?}")]
+    UnknownKey(String),
+    #[error("line {0}: expected \"name = key\"")]
+    Syntax(usize),
+}
+
+/// The modifier keys held during a key press.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub control: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers {
+        shift: false,
+        control: false,
+    };
+}
+
+/// A key on the 5620 keyboard.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+    /// A key typing a printable ASCII character.
+    Char(u8),
+    Return,
+    LineFeed,
+    Tab,
+    BackSpace,
+    Escape,
+    Delete,
+    Break,
+    Setup,
+    /// Programmable function keys PF1 to PF8.
+    Pf(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    HomeDown,
+    Clear,
+    /// Numeric keypad keys: the digits, '.' and '-'.
+    Keypad(u8),
+    Enter,
+    /// Sends no code, but toggles the keyboard's CAPS LOCK.
+    CapsLock,
+}
+
+impl Key {
+    /// The code the keyboard sends when this key is pressed with the
+    /// given modifiers, if it sends one.
+    pub fn code(self, modifiers: Modifiers) -> Option<u8> {
+        let code = match self {
+            Key::Char(c) if (0x20..=0x7e).contains(&c) => {
+                if modifiers.control && c >= 0x40 {
+                    c & 0x1f
+                } else {
+                    c
+                }
+            }
+            Key::Char(_) => return None,
+            Key::Return => 0x0d,
+            Key::LineFeed => 0x0a,
+            Key::Tab => 0x09,
+            Key::BackSpace => 0x08,
+            Key::Escape => 0x1b,
+            Key::Delete => 0x7f,
+            Key::Break => KEY_BREAK,
+            Key::Setup => KEY_SETUP,
+            Key::Pf(n) if (1..=PF_KEY_COUNT).contains(&n) => {
+                if modifiers.shift {
+                    KEY_SHIFT_PF1 + n - 1
+                } else {
+                    KEY_PF1 + n - 1
+                }
+            }
+            Key::Pf(_) => return None,
+            Key::Up => KEY_UP,
+            Key::Down => KEY_DOWN,
+            Key::Left => KEY_LEFT,
+            Key::Right => KEY_RIGHT,
+            Key::Home => KEY_HOME,
+            Key::HomeDown => KEY_HOME_DOWN,
+            Key::Clear => KEY_CLEAR,
+            Key::Keypad(c) => return KEYPAD.iter().find(|(k, _)| *k == c).map(|(_, code)| *code),
+            Key::Enter => KEY_ENTER,
+            Key::CapsLock => return None,
+        };
+        Some(code)
+    }
+
+    /// The key, and whether CTRL must be held, that types an ASCII
+    /// character. Newlines are typed with RETURN.
+    pub fn from_char(c: char) -> Option<(Key, Modifiers)> {
+        let key = match c {
+            '\r' | '\n' => Key::Return,
+            '\t' => Key::Tab,
+            '\x08' => Key::BackSpace,
+            '\x1b' => Key::Escape,
+            '\x7f' => Key::Delete,
+            ' '..='~' => Key::Char(c as u8),
+            '\0'..='\x1f' => {
+                let modifiers = Modifiers {
+                    control: true,
+                    ..Modifiers::NONE
+                };
+                return Some((Key::Char(c as u8 | 0x40), modifiers));
+            }
+            _ => return None,
+        };
+        Some((key, Modifiers::NONE))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Char(c) => write!(f, "{}", char::from(*c)),
+            Key::Pf(n) => write!(f, "PF{}", n),
+            Key::Keypad(c) => write!(f, "KP{}", char::from(*c)),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl FromStr for Key {
+    type Err = KeyboardError;
+
+    /// Parse a key as written by `Display`: a printable character, a
+    /// key name such as "Return", "PF1" or "Setup", or a keypad key
+    /// such as "KP0" or "KP.".
+    fn from_str(s: &str) -> Result<Key, KeyboardError> {
+        let unknown = || KeyboardError::UnknownKey(s.to_string());
+        let key = match s {
+            "Return" => Key::Return,
+            "LineFeed" => Key::LineFeed,
+            "Tab" => Key::Tab,
+            "BackSpace" => Key::BackSpace,
+            "Escape" => Key::Escape,
+            "Delete" => Key::Delete,
+            "Break" => Key::Break,
+            "Setup" => Key::Setup,
+            "Up" => Key::Up,
+            "Down" => Key::Down,
+            "Left" => Key::Left,
+            "Right" => Key::Right,
+            "Home" => Key::Home,
+            "HomeDown" => Key::HomeDown,
+            "Clear" => Key::Clear,
+            "Enter" => Key::Enter,
+            "CapsLock" => return Ok(Key::CapsLock),
+            _ => {
+                if let Some(n) = s.strip_prefix("PF") {
+                    Key::Pf(n.parse().map_err(|_| unknown())?)
+                } else if let Some(c) = s.strip_prefix("KP").filter(|c| c.len() == 1) {
+                    Key::Keypad(c.as_bytes()[0])
+                } else if s.len() == 1 {
+                    Key::Char(s.as_bytes()[0])
+                } else {
+                    return Err(unknown());
+                }
+            }
+        };
+        match key.code(Modifiers::NONE) {
+            Some(_) => Ok(key),
+            None => Err(unknown()),
+        }
+    }
+}
+
+/// A mapping from host key names to 5620 keys.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Layout {
+    keys: HashMap<String, Key>,
+}
+
+impl Default for Layout {
+    /// The standard layout, using X11 keysym names.
+    fn default() -> Self {
+        let mut layout = Layout::new();
+        for c in b' '..=b'~' {
+            layout.bind(&char::from(c).to_string(), Key::Char(c));
+        }
+        layout.bind("space", Key::Char(b' '));
+        for n in 1..=PF_KEY_COUNT {
+            layout.bind(&format!("F{}", n), Key::Pf(n));
+        }
+        for c in b'0'..=b'9' {
+            layout.bind(&format!("KP_{}", char::from(c)), Key::Keypad(c));
+        }
+        let names = [
+            ("Return", Key::Return),
+            ("Linefeed", Key::LineFeed),
+            ("Tab", Key::Tab),
+            ("BackSpace", Key::BackSpace),
+            ("Escape", Key::Escape),
+            ("Delete", Key::Delete),
+            ("Pause", Key::Break),
+            ("Break", Key::Break),
+            ("F9", Key::Setup),
+            ("Up", Key::Up),
+            ("Down", Key::Down),
+            ("Left", Key::Left),
+            ("Right", Key::Right),
+            ("Home", Key::Home),
+            ("End", Key::HomeDown),
+            ("Clear", Key::Clear),
+            ("KP_Decimal", Key::Keypad(b'.')),
+            ("KP_Subtract", Key::Keypad(b'-')),
+            ("KP_Enter", Key::Enter),
+            ("Caps_Lock", Key::CapsLock),
+        ];
+        for (name, key) in names.iter() {
+            layout.bind(name, *key);
+        }
+        layout
+    }
+}
+
+impl Layout {
+    /// A layout with no keys bound.
+    pub fn new() -> Layout {
+        Layout {
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Read a layout from text, with one binding per line in the form
+    /// `name = key`, where `key` is written as for `Key::from_str`, or
+    /// is "none" to unbind the name. Bindings are applied on top of
+    /// the standard layout. Blank lines and lines starting with '#'
+    /// are ignored.
+    pub fn parse(text: &str) -> Result<Layout, KeyboardError> {
+        let mut layout = Layout::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Split after the first character, so that "=" can itself
+            // be bound
+            let at = line.char_indices().skip(1).find(|(_, c)| *c == '=').map(|(at, _)| at);
+            let (name, key) = match at {
+                Some(at) => (line[..at].trim(), line[at + 1..].trim()),
+                None => return Err(KeyboardError::Syntax(i + 1)),
+            };
+            if name.is_empty() || key.is_empty() {
+                return Err(KeyboardError::Syntax(i + 1));
+            }
+            if key == "none" {
+                layout.unbind(name);
+            } else {
+                layout.bind(name, key.parse()?);
+            }
+        }
+        Ok(layout)
+    }
+
+    pub fn bind(&mut self, name: &str, key: Key) {
+        self.keys.insert(name.to_string(), key);
+    }
+
+    pub fn unbind(&mut self, name: &str) {
+        self.keys.remove(name);
+    }
+
+    /// The 5620 key bound to a host key name.
+    pub fn key(&self, name: &str) -> Option<Key> {
+        self.keys.get(name).copied()
+    }
+
+    /// The code sent for the named host key pressed with the given
+    /// modifiers.
+    pub fn keycode(&self, name: &str, modifiers: Modifiers) -> Option<u8> {
+        self.key(name).and_then(|key| key.code(modifiers))
+    }
+}
+
+/// The lamps on the keyboard.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Leds {
+    pub caps_lock: bool,
+}
+
+/// Something the keyboard does that the host may want to show or
+/// sound.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyboardEvent {
+    /// The firmware sounded the bell.
+    Bell,
+    /// A key was pressed while key click was on.
+    Click,
+    /// The firmware turned key click on or off.
+    KeyClick(bool),
+    LedsChanged(Leds),
+}
+
+/// The keyboard itself: the keys it sends, and the commands it takes
+/// from the terminal.
+#[derive(Clone, Debug, Default)]
+pub struct Keyboard {
+    caps_lock: bool,
+    click: bool,
+    // Bytes for the terminal, oldest first, each marked if it is a
+    // status byte.
+    sent: VecDeque<(u8, bool)>,
+    events: VecDeque<KeyboardEvent>,
+}
+
+impl Keyboard {
+    pub fn new() -> Keyboard {
+        Keyboard::default()
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.caps_lock
+    }
+
+    pub fn click(&self) -> bool {
+        self.click
+    }
+
+    pub fn leds(&self) -> Leds {
+        Leds {
+            caps_lock: self.caps_lock,
+        }
+    }
+
+    /// Press a key, returning false if it does nothing. Letters are
+    /// sent in upper case while CAPS LOCK is on.
+    pub fn press(&mut self, key: Key, modifiers: Modifiers) -> bool {
+        if key == Key::CapsLock {
+            self.set_caps_lock(!self.caps_lock);
+            return true;
+        }
+
+        let mut code = match key.code(modifiers) {
+            Some(code) => code,
+            None => return false,
+        };
+        if self.caps_lock {
+            code = code.to_ascii_uppercase();
+        }
+        if self.click {
+            self.event(KeyboardEvent::Click);
+        }
+        self.sent.push_back((code, false));
+        true
+    }
+
+    /// Turn CAPS LOCK on or off, telling the terminal if it changed.
+    pub fn set_caps_lock(&mut self, on: bool) {
+        if self.caps_lock != on {
+            self.caps_lock = on;
+            self.event(KeyboardEvent::LedsChanged(self.leds()));
+            self.sent.push_back((self.status(), true));
+        }
+    }
+
+    /// Carry out a command from the terminal.
+    pub fn command(&mut self, command: u8) {
+        debug!("KEYBOARD: command {:02x}", command);
+
+        if command & CMD_RESET != 0 {
+            self.set_caps_lock(false);
+        }
+
+        let click = command & CMD_CLICK != 0;
+        if click != self.click {
+            self.click = click;
+            self.event(KeyboardEvent::KeyClick(click));
+        }
+
+        if command & CMD_BELL != 0 {
+            self.event(KeyboardEvent::Bell);
+        }
+
+        if command & CMD_STATUS != 0 {
+            self.sent.push_back((self.status(), true));
+        }
+    }
+
+    fn event(&mut self, event: KeyboardEvent) {
+        if self.events.len() == EVENT_LIMIT {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    fn status(&self) -> u8 {
+        let caps = if self.caps_lock {
+            0
+        } else {
+            STATUS_CAPS_OFF
+        };
+        STATUS_REPEAT_UP | caps
+    }
+
+    /// The next byte for the terminal, and whether it is a status
+    /// byte.
+    pub fn next_sent(&mut self) -> Option<(u8, bool)> {
+        self.sent.pop_front()
+    }
+
+    /// The oldest event the host has not yet taken.
+    pub fn next_event(&mut self) -> Option<KeyboardEvent> {
+        self.events.pop_front()
+    }
+
+    /// Swap the events waiting to be taken with those of another
+    /// keyboard.
+    pub fn swap_events(&mut self, other: &mut Keyboard) {
+        mem::swap(&mut self.events, &mut other.events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTROL: Modifiers = Modifiers {
+        shift: false,
+        control: true,
+    };
+    const SHIFT: Modifiers = Modifiers {
+        shift: true,
+        control: false,
+    };
+
+    #[test]
+    fn maps_the_standard_layout() {
+        let layout = Layout::default();
+        assert_eq!(Some(b'a'), layout.keycode("a", Modifiers::NONE));
+        assert_eq!(Some(0x01), layout.keycode("a", CONTROL));
+        assert_eq!(Some(0x00), layout.keycode("@", CONTROL));
+        assert_eq!(Some(b'1'), layout.keycode("1", CONTROL));
+        assert_eq!(Some(b' '), layout.keycode("space", Modifiers::NONE));
+        assert_eq!(Some(0x0d), layout.keycode("Return", Modifiers::NONE));
+        assert_eq!(Some(0xe8), layout.keycode("F1", Modifiers::NONE));
+        assert_eq!(Some(0xef), layout.keycode("F8", Modifiers::NONE));
+        assert_eq!(Some(0xc8), layout.keycode("F1", SHIFT));
+        assert_eq!(Some(KEY_SETUP), layout.keycode("F9", Modifiers::NONE));
+        assert_eq!(Some(KEY_BREAK), layout.keycode("Pause", Modifiers::NONE));
+        assert_eq!(Some(KEY_UP), layout.keycode("Up", Modifiers::NONE));
+        assert_eq!(Some(0xd2), layout.keycode("KP_7", Modifiers::NONE));
+        assert_eq!(Some(KEY_RIGHT), layout.keycode("KP_Decimal", Modifiers::NONE));
+        assert_eq!(Some(KEY_ENTER), layout.keycode("KP_Enter", Modifiers::NONE));
+        assert_eq!(None, layout.keycode("Control_L", Modifiers::NONE));
+    }
+
+    #[test]
+    fn parses_keys() {
+        for key in [Key::Char(b'x'), Key::Pf(3), Key::Keypad(b'.'), Key::HomeDown].iter() {
+            assert_eq!(Ok(*key), key.to_string().parse());
+        }
+        assert_eq!(Err(KeyboardError::UnknownKey("PF9".to_string())), "PF9".parse::<Key>());
+        assert_eq!(Err(KeyboardError::UnknownKey("Hyper".to_string())), "Hyper".parse::<Key>());
+    }
+
+    #[test]
+    fn parses_layouts() {
+        let layout = Layout::parse("# Sun keyboard\nF11 = Setup\n\nF9 = none\n= = =\n").unwrap();
+        assert_eq!(Some(Key::Setup), layout.key("F11"));
+        assert_eq!(None, layout.key("F9"));
+        assert_eq!(Some(Key::Char(b'=')), layout.key("="));
+        assert_eq!(Some(Key::Pf(1)), layout.key("F1"));
+
+        assert_eq!(Err(KeyboardError::Syntax(2)), Layout::parse("\nF11 Setup"));
+        assert_eq!(
+            Err(KeyboardError::UnknownKey("Compose".to_string())),
+            Layout::parse("Multi_key = Compose")
+        );
+    }
+
+    fn sent(keyboard: &mut Keyboard) -> Vec<(u8, bool)> {
+        std::iter::from_fn(|| keyboard.next_sent()).collect()
+    }
+
+    #[test]
+    fn types_text() {
+        let codes: Vec<u8> = "ls -l\n\x03\x1b\u{e9}"
+            .chars()
+            .filter_map(Key::from_char)
+            .filter_map(|(key, modifiers)| key.code(modifiers))
+            .collect();
+        assert_eq!(b"ls -l\r\x03\x1b".to_vec(), codes);
+    }
+
+    #[test]
+    fn carries_out_commands() {
+        let mut keyboard = Keyboard::new();
+        keyboard.command(CMD_CLICK | CMD_STATUS);
+        assert_eq!(vec![(STATUS_REPEAT_UP | STATUS_CAPS_OFF, true)], sent(&mut keyboard));
+        keyboard.command(CMD_CLICK | CMD_BELL);
+        keyboard.command(CMD_CLICK);
+        keyboard.command(0);
+
+        let events: Vec<KeyboardEvent> = std::iter::from_fn(|| keyboard.next_event()).collect();
+        assert_eq!(
+            vec![
+                KeyboardEvent::KeyClick(true),
+                KeyboardEvent::Bell,
+                KeyboardEvent::KeyClick(false)
+            ],
+            events
+        );
+        assert!(sent(&mut keyboard).is_empty());
+    }
+
+    #[test]
+    fn locks_caps() {
+        let mut keyboard = Keyboard::new();
+        keyboard.command(CMD_CLICK);
+        keyboard.next_event();
+
+        assert!(keyboard.press(Key::CapsLock, Modifiers::NONE));
+        assert!(keyboard.press(Key::Char(b'a'), Modifiers::NONE));
+        assert!(keyboard.press(Key::Char(b'1'), Modifiers::NONE));
+        assert_eq!(
+            vec![(STATUS_REPEAT_UP, true), (b'A', false), (b'1', false)],
+            sent(&mut keyboard)
+        );
+        assert_eq!(
+            Some(KeyboardEvent::LedsChanged(Leds {
+                caps_lock: true
+            })),
+            keyboard.next_event()
+        );
+        assert_eq!(Some(KeyboardEvent::Click), keyboard.next_event());
+
+        keyboard.command(CMD_RESET);
+        assert!(!keyboard.caps_lock());
+        assert_eq!(vec![(STATUS_REPEAT_UP | STATUS_CAPS_OFF, true)], sent(&mut keyboard));
+    }
+
+    #[test]
+    fn drops_the_oldest_events() {
+        let mut keyboard = Keyboard::new();
+        keyboard.command(CMD_CLICK);
+        for _ in 0..EVENT_LIMIT {
+            keyboard.command(CMD_CLICK | CMD_BELL);
+        }
+
+        let events: Vec<KeyboardEvent> = std::iter::from_fn(|| keyboard.next_event()).collect();
+        assert_eq!(vec![KeyboardEvent::Bell; EVENT_LIMIT], events);
+    }
+}
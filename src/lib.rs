@@ -1,19 +1,36 @@
+mod bbram;
 #[allow(unused)]
 mod bus;
 #[allow(unused)]
 mod cpu;
+pub mod display;
 #[allow(unused)]
-mod dmd;
+pub mod dmd;
 mod duart;
 #[allow(unused)]
 mod err;
+pub mod history;
+pub mod image;
+pub mod input;
 #[allow(unused)]
 mod instr;
+pub mod keyboard;
 mod mem;
-mod mouse;
+pub mod mouse;
+mod printer;
+pub mod recorder;
 mod rom_hi;
 mod rom_lo;
+mod scc;
+mod sched;
+pub mod script;
+pub mod session;
+pub mod setup;
+pub mod term;
+pub mod text;
 mod utils;
+#[cfg(feature = "vnc")]
+pub mod vnc;
 
 #[macro_use]
 extern crate lazy_static;
@@ -10,6 +10,7 @@ use std::ops::Range;
 use std::ops::{Index, IndexMut};
 use std::vec::Vec;
 
+#[derive(Clone)]
 pub struct Mem {
     address_range: Range<usize>,
     len: usize,
@@ -31,6 +32,35 @@ impl Mem {
     pub fn as_slice(&self, range: Range<usize>) -> &[u8] {
         &self.ram[range]
     }
+
+    pub fn as_mut_slice(&mut self, range: Range<usize>) -> &mut [u8] {
+        &mut self.ram[range]
+    }
+
+    /// Read a little-endian halfword from the instruction stream at
+    /// the specified absolute address.
+    pub fn read_op_half(&self, address: usize) -> Result<u16, BusError> {
+        let offset = address.wrapping_sub(self.address_range.start);
+
+        match self.ram.get(offset..offset.wrapping_add(2)) {
+            Some(b) => Ok(u16::from(b[0]) | u16::from(b[1]).wrapping_shl(8)),
+            None => Err(BusError::Range),
+        }
+    }
+
+    /// Read a little-endian word from the instruction stream at the
+    /// specified absolute address.
+    pub fn read_op_word(&self, address: usize) -> Result<u32, BusError> {
+        let offset = address.wrapping_sub(self.address_range.start);
+
+        match self.ram.get(offset..offset.wrapping_add(4)) {
+            Some(b) => Ok(u32::from(b[0])
+                | u32::from(b[1]).wrapping_shl(8)
+                | u32::from(b[2]).wrapping_shl(16)
+                | u32::from(b[3]).wrapping_shl(24)),
+            None => Err(BusError::Range),
+        }
+    }
 }
 
 impl Debug for Mem {
@@ -1,20 +1,107 @@
+//! The mouse position registers.
+//!
+//! The mouse's Y and X counters are two read-only halfwords, Y first,
+//! which may be read a byte, a halfword or the whole word at a time.
+//! Any write is a bus error.
+//!
+//! The counters are 12 bits wide, and the firmware tracks the cursor
+//! by the change in them between reads, so only their motion matters.
+//! X counts up to the right, and Y counts up towards the top of the
+//! screen, the opposite of screen coordinates. Frontends can leave
+//! that to `move_to` and `move_by`, which take screen coordinates,
+//! keep the pointer on the screen, and set the counters to match.
+//!
+//! Motion and button presses are queued, and delivered one at a time,
+//! each once the firmware has seen the one before: a motion once the
+//! firmware has read the counters, and a button change once it has
+//! handled the input port change interrupt. That way a quick click, or
+//! a chord of buttons, is never merged or lost between two interrupts.
+//! Consecutive motions are merged, since only where they end matters.
+//! A firmware that is not following the mouse may never look, so an
+//! event is also taken as seen after half a second.
+//! The buttons themselves are on the DUART's input port, and the bus
+//! passes their changes on to it.
+
 #![allow(clippy::unreadable_literal)]
 
 use crate::bus::AccessCode;
 use crate::bus::Device;
+use crate::display::{HEIGHT, WIDTH};
 use crate::err::BusError;
+use std::collections::VecDeque;
 use std::ops::Range;
 
 use log::trace;
 
 const START_ADDRESS: usize = 0x400000;
-const END_ADDRESS: usize = 0x4000004;
+const END_ADDRESS: usize = 0x400004;
 const ADDRESS_RANGE: Range<usize> = START_ADDRESS..END_ADDRESS;
 
-#[derive(Debug)]
+const COUNTER_MASK: u16 = 0xfff;
+const MAX_X: i32 = WIDTH as i32 - 1;
+const MAX_Y: i32 = HEIGHT as i32 - 1;
+
+/// Vertical blanks after which an event the firmware has not looked
+/// at is taken as seen.
+const STALL_VBLANKS: u8 = 30;
+
+/// Pointer acceleration for relative motion. A motion of more than
+/// `threshold` pixels along either axis is scaled by `numerator /
+/// denominator`, like the X server's pointer acceleration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Acceleration {
+    pub numerator: u16,
+    pub denominator: u16,
+    pub threshold: u16,
+}
+
+impl Acceleration {
+    /// Motion is passed through unchanged.
+    pub const NONE: Acceleration = Acceleration {
+        numerator: 1,
+        denominator: 1,
+        threshold: 0,
+    };
+
+    fn apply(&self, dx: i32, dy: i32) -> (i32, i32) {
+        if dx.abs().max(dy.abs()) <= i32::from(self.threshold) {
+            return (dx, dy);
+        }
+        let numerator = i32::from(self.numerator);
+        let denominator = i32::from(self.denominator.max(1));
+        (dx * numerator / denominator, dy * numerator / denominator)
+    }
+}
+
+impl Default for Acceleration {
+    fn default() -> Self {
+        Acceleration::NONE
+    }
+}
+
+/// A change to the mouse, waiting its turn to be delivered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MouseEvent {
+    /// New values of the X and Y counters.
+    Move(u16, u16),
+    Down(u8),
+    Up(u8),
+}
+
+#[derive(Clone, Debug)]
 pub struct Mouse {
-    pub x: u16,
-    pub y: u16,
+    /// The counters, as the firmware reads them.
+    x: u16,
+    y: u16,
+    pub acceleration: Acceleration,
+    /// The counters once every queued motion has been delivered.
+    target: (u16, u16),
+    events: VecDeque<MouseEvent>,
+    /// True if the counters have changed since the firmware last read
+    /// them.
+    unread: bool,
+    /// Vertical blanks since the last event was delivered.
+    vblanks: u8,
 }
 
 impl Default for Mouse {
@@ -28,7 +115,110 @@ impl Mouse {
         Mouse {
             x: 0,
             y: 0,
+            acceleration: Acceleration::NONE,
+            target: (0, 0),
+            events: VecDeque::new(),
+            unread: false,
+            vblanks: 0,
+        }
+    }
+
+    /// Queue new values for the raw counters.
+    pub fn set_counters(&mut self, x: u16, y: u16) {
+        self.target = (x, y);
+        match self.events.back_mut() {
+            Some(MouseEvent::Move(qx, qy)) => {
+                *qx = x;
+                *qy = y;
+            }
+            _ => self.events.push_back(MouseEvent::Move(x, y)),
+        }
+    }
+
+    /// Queue a button press. Buttons 0, 1 and 2 are the left, middle
+    /// and right buttons; any other is ignored.
+    pub fn press(&mut self, button: u8) {
+        if button < 3 {
+            self.events.push_back(MouseEvent::Down(button));
+        }
+    }
+
+    /// Queue a button release.
+    pub fn release(&mut self, button: u8) {
+        if button < 3 {
+            self.events.push_back(MouseEvent::Up(button));
+        }
+    }
+
+    /// The number of events not yet delivered.
+    pub fn pending(&self) -> usize {
+        self.events.len()
+    }
+
+    /// The raw counters once every queued motion has been delivered.
+    pub fn target(&self) -> (u16, u16) {
+        self.target
+    }
+
+    /// The pointer position in screen coordinates, with the origin at
+    /// the top left, once every queued motion has been delivered.
+    pub fn position(&self) -> (u16, u16) {
+        let (x, y) = self.target;
+        let x = i32::from(x & COUNTER_MASK).min(MAX_X);
+        let y = (MAX_Y - i32::from(y & COUNTER_MASK)).max(0);
+        (x as u16, y as u16)
+    }
+
+    /// Move the pointer to a position in screen coordinates, clamped
+    /// to the screen.
+    pub fn move_to(&mut self, x: i32, y: i32) {
+        let x = x.clamp(0, MAX_X) as u16;
+        let y = (MAX_Y - y.clamp(0, MAX_Y)) as u16;
+        self.set_counters(x, y);
+    }
+
+    /// Move the pointer by a relative motion in screen coordinates,
+    /// such as a host reports while it holds a pointer lock, applying
+    /// the acceleration. The pointer stops at the edges of the screen.
+    pub fn move_by(&mut self, dx: i32, dy: i32) {
+        let (dx, dy) = self.acceleration.apply(dx, dy);
+        let (x, y) = self.position();
+        self.move_to(i32::from(x) + dx, i32::from(y) + dy);
+    }
+
+    /// True once the firmware has read the counters since the last
+    /// motion was delivered.
+    pub fn motion_seen(&self) -> bool {
+        !self.unread || self.stalled()
+    }
+
+    /// True if the last event was delivered long enough ago to be
+    /// taken as seen, whether or not the firmware looked at it.
+    pub fn stalled(&self) -> bool {
+        self.vblanks >= STALL_VBLANKS
+    }
+
+    pub fn vertical_blank(&mut self) {
+        self.vblanks = self.vblanks.saturating_add(1);
+    }
+
+    /// Take the next event from the queue. A motion is applied to the
+    /// counters; button changes are left to the caller.
+    pub fn next_event(&mut self) -> Option<MouseEvent> {
+        let event = self.events.pop_front()?;
+        self.vblanks = 0;
+        if let MouseEvent::Move(x, y) = event {
+            self.x = x;
+            self.y = y;
+            self.unread = true;
         }
+        Some(event)
+    }
+
+    /// The registers as they appear on the bus.
+    fn registers(&mut self) -> u32 {
+        self.unread = false;
+        u32::from(self.y) << 16 | u32::from(self.x)
     }
 }
 
@@ -42,54 +232,182 @@ impl Device for Mouse {
     }
 
     fn is_read_only(&self) -> bool {
-        false
+        true
     }
 
-    fn read_byte(&mut self, _address: usize, _access: AccessCode) -> Result<u8, BusError> {
-        unimplemented!()
+    fn read_byte(&mut self, address: usize, _access: AccessCode) -> Result<u8, BusError> {
+        trace!("Mouse Read Byte, address={:08x}", address);
+        match self.registers().to_be_bytes().get(address - START_ADDRESS) {
+            Some(byte) => Ok(*byte),
+            None => Err(BusError::NoDevice(address)),
+        }
     }
 
     fn read_half(&mut self, address: usize, _access: AccessCode) -> Result<u16, BusError> {
         trace!("Mouse Read, address={:08x}", address);
+        let registers = self.registers();
         match address - START_ADDRESS {
-            0 => Ok(self.y),
-            2 => Ok(self.x),
+            0 => Ok((registers >> 16) as u16),
+            2 => Ok(registers as u16),
             _ => Err(BusError::NoDevice(address)),
         }
     }
 
-    fn read_word(&mut self, _address: usize, _access: AccessCode) -> Result<u32, BusError> {
-        unimplemented!()
+    fn read_word(&mut self, address: usize, _access: AccessCode) -> Result<u32, BusError> {
+        trace!("Mouse Read Word, address={:08x}", address);
+        match address - START_ADDRESS {
+            0 => Ok(self.registers()),
+            _ => Err(BusError::NoDevice(address)),
+        }
     }
 
     fn write_byte(
         &mut self,
-        _address: usize,
+        address: usize,
         _val: u8,
         _access: AccessCode,
     ) -> Result<(), BusError> {
-        unimplemented!()
+        Err(BusError::Write(address))
     }
 
     fn write_half(
         &mut self,
-        _address: usize,
+        address: usize,
         _val: u16,
         _access: AccessCode,
     ) -> Result<(), BusError> {
-        unimplemented!()
+        Err(BusError::Write(address))
     }
 
     fn write_word(
         &mut self,
-        _address: usize,
+        address: usize,
         _val: u32,
         _access: AccessCode,
     ) -> Result<(), BusError> {
-        unimplemented!()
+        Err(BusError::Write(address))
+    }
+
+    fn load(&mut self, address: usize, _data: &[u8]) -> Result<(), BusError> {
+        Err(BusError::Write(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mouse() -> Mouse {
+        let mut mouse = Mouse::new();
+        mouse.x = 0x0123;
+        mouse.y = 0x0345;
+        mouse
+    }
+
+    #[test]
+    fn reads_bytes_halves_and_words() {
+        let mut mouse = mouse();
+        let bytes: Vec<u8> = (START_ADDRESS..END_ADDRESS)
+            .map(|address| mouse.read_byte(address, AccessCode::AddressFetch).unwrap())
+            .collect();
+        assert_eq!(vec![0x03, 0x45, 0x01, 0x23], bytes);
+
+        assert_eq!(0x0345, mouse.read_half(0x400000, AccessCode::AddressFetch).unwrap());
+        assert_eq!(0x0123, mouse.read_half(0x400002, AccessCode::AddressFetch).unwrap());
+        assert_eq!(0x03450123, mouse.read_word(0x400000, AccessCode::AddressFetch).unwrap());
+    }
+
+    #[test]
+    fn rejects_writes() {
+        let mut mouse = mouse();
+        assert!(matches!(
+            mouse.write_byte(0x400001, 0xff, AccessCode::Write),
+            Err(BusError::Write(0x400001))
+        ));
+        assert!(matches!(
+            mouse.write_half(0x400002, 0xffff, AccessCode::Write),
+            Err(BusError::Write(0x400002))
+        ));
+        assert!(matches!(
+            mouse.write_word(0x400000, 0xffffffff, AccessCode::Write),
+            Err(BusError::Write(0x400000))
+        ));
+        assert!(matches!(mouse.load(0x400000, &[0; 4]), Err(BusError::Write(0x400000))));
+        assert_eq!(0x03450123, mouse.read_word(0x400000, AccessCode::AddressFetch).unwrap());
+    }
+
+    #[test]
+    fn moves_in_screen_coordinates() {
+        let mut mouse = Mouse::new();
+
+        // Y counts up from the bottom of the screen
+        mouse.move_to(10, 0);
+        assert_eq!((10, 1023), mouse.target);
+        mouse.move_by(5, 3);
+        assert_eq!((15, 1020), mouse.target);
+        assert_eq!((15, 3), mouse.position());
+
+        // The pointer stays on the screen
+        mouse.move_by(-100, -100);
+        assert_eq!((0, 0), mouse.position());
+        mouse.move_to(5000, 5000);
+        assert_eq!((799, 1023), mouse.position());
+        assert_eq!((799, 0), mouse.target);
+
+        // The motions are merged, and reach the counters when delivered
+        assert_eq!(1, mouse.pending());
+        assert_eq!(Some(MouseEvent::Move(799, 0)), mouse.next_event());
+        assert_eq!((799, 0), (mouse.x, mouse.y));
+    }
+
+    #[test]
+    fn waits_for_motion_to_be_read() {
+        let mut mouse = Mouse::new();
+        assert!(mouse.motion_seen());
+
+        mouse.move_to(1, 2);
+        mouse.press(0);
+        mouse.move_to(3, 4);
+        assert_eq!(3, mouse.pending());
+        assert_eq!(Some(MouseEvent::Move(1, 1021)), mouse.next_event());
+        assert!(!mouse.motion_seen());
+        mouse.read_half(0x400000, AccessCode::AddressFetch).unwrap();
+        assert!(mouse.motion_seen());
+
+        assert_eq!(Some(MouseEvent::Down(0)), mouse.next_event());
+        assert_eq!(Some(MouseEvent::Move(3, 1019)), mouse.next_event());
+        for _ in 1..STALL_VBLANKS {
+            mouse.vertical_blank();
+        }
+        assert!(!mouse.motion_seen());
+        mouse.vertical_blank();
+        assert!(mouse.motion_seen());
+        assert_eq!(None, mouse.next_event());
+    }
+
+    #[test]
+    fn accelerates_fast_motion() {
+        let mut mouse = Mouse::new();
+        mouse.acceleration = Acceleration {
+            numerator: 3,
+            denominator: 2,
+            threshold: 4,
+        };
+        mouse.move_to(400, 500);
+
+        mouse.move_by(4, -4);
+        assert_eq!((404, 496), mouse.position());
+        mouse.move_by(10, -5);
+        assert_eq!((419, 489), mouse.position());
     }
 
-    fn load(&mut self, _address: usize, _data: &[u8]) -> Result<(), BusError> {
-        unimplemented!()
+    #[test]
+    fn is_mapped_on_the_bus() {
+        let mut bus = crate::bus::Bus::new(0x10000);
+        bus.mouse_move(0x0123, 0x0345);
+        assert_eq!(0x45, bus.read_byte(0x400001, AccessCode::OperandFetch).unwrap());
+        assert_eq!(0x03450123, bus.read_word(0x400000, AccessCode::OperandFetch).unwrap());
+        assert!(bus.write_byte(0x400000, 0).is_err());
+        assert!(bus.read_byte(0x400004, AccessCode::OperandFetch).is_err());
     }
 }
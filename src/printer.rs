@@ -0,0 +1,45 @@
+//! Capturing printer output to a file.
+//!
+//! The printer shares DUART port B with the keyboard. Whatever the
+//! firmware sends while the printer is selected is written to the
+//! file exactly as it arrives, with no translation.
+
+use log::error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub struct Printer {
+    out: BufWriter<File>,
+    // The first write error, reported when the capture is finished.
+    error: Option<io::Error>,
+}
+
+impl Printer {
+    pub fn create(path: &Path) -> io::Result<Printer> {
+        Ok(Printer {
+            out: BufWriter::new(File::create(path)?),
+            error: None,
+        })
+    }
+
+    /// Write a character sent to the printer. After a write fails,
+    /// the rest of the output is dropped.
+    pub fn print(&mut self, c: u8) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.out.write_all(&[c]) {
+            error!("Unable to write printer output: {}", e);
+            self.error = Some(e);
+        }
+    }
+
+    /// Flush the file, reporting the first error met while writing it.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
@@ -0,0 +1,572 @@
+//! Recording the display as an animated GIF or APNG.
+//!
+//! The bus hands the recorder the framebuffer at each vertical blank
+//! where it has changed. Each frame holds only the rectangle that
+//! differs from the frame before it, and is shown until the next
+//! frame arrives, so frame delays follow emulated time.
+//!
+//! A frame is written once the next one arrives, when its delay is
+//! known. APNG needs the frame count up front, so it is patched in
+//! when the recording is finished. A recorder dropped without being
+//! finished finishes its file then, showing the last frame for no
+//! time.
+
+use crate::display::{changed_region, FRAMEBUFFER_LEN, HEIGHT, STRIDE, WIDTH};
+use crate::image::{png_header, write_chunk, zlib_stored};
+
+use log::error;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// GIF codes are at most 12 bits wide.
+const LZW_MAX_CODE: u16 = 4095;
+/// GIF requires at least two bits per pixel in the LZW stream.
+const LZW_MIN_CODE_SIZE: u8 = 2;
+const LZW_CLEAR: u16 = 1 << LZW_MIN_CODE_SIZE;
+const LZW_EOI: u16 = LZW_CLEAR + 1;
+
+/// Offset of the APNG animation control chunk, following the
+/// signature and the header chunk.
+const ACTL_OFFSET: u64 = 8 + 12 + 13;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VideoFormat {
+    Gif,
+    Apng,
+}
+
+/// The part of the screen that changed, in bytes across and scanlines
+/// down, with white pixels set.
+struct Frame {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+    time: u64,
+}
+
+pub struct Recorder {
+    format: VideoFormat,
+    out: BufWriter<File>,
+    // The screen as of the last frame, with white pixels set.
+    screen: Vec<u8>,
+    inverted: bool,
+    pending: Frame,
+    frames: u32,
+    // APNG sequence number, shared by frame control and data chunks.
+    sequence: u32,
+    error: Option<io::Error>,
+    // Set once the file is finished, so that it is not finished again
+    // when the recorder is dropped.
+    finished: bool,
+}
+
+impl Recorder {
+    /// Start a recording in a new file, with the current contents of
+    /// the screen as the first frame.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: VideoFormat,
+        framebuffer: &[u8],
+        inverted: bool,
+        now: u64,
+    ) -> io::Result<Recorder> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        match format {
+            VideoFormat::Gif => {
+                out.write_all(b"GIF89a")?;
+                out.write_all(&(WIDTH as u16).to_le_bytes())?;
+                out.write_all(&(HEIGHT as u16).to_le_bytes())?;
+                // A global color table of two entries, black and white
+                out.write_all(&[0x80, 0, 0])?;
+                out.write_all(&[0, 0, 0, 0xff, 0xff, 0xff])?;
+                // Loop forever
+                out.write_all(&[0x21, 0xff, 0x0b])?;
+                out.write_all(b"NETSCAPE2.0")?;
+                out.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+            }
+            VideoFormat::Apng => {
+                let mut header = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+                write_chunk(&mut header, b"IHDR", &png_header());
+                write_chunk(&mut header, b"acTL", &[0; 8]);
+                out.write_all(&header)?;
+            }
+        }
+
+        let mut screen = vec![0; FRAMEBUFFER_LEN];
+        copy_screen(&mut screen, framebuffer, inverted);
+
+        Ok(Recorder {
+            format,
+            out,
+            pending: Frame {
+                x: 0,
+                y: 0,
+                width: STRIDE,
+                height: HEIGHT,
+                pixels: screen.clone(),
+                time: now,
+            },
+            screen,
+            inverted,
+            frames: 0,
+            sequence: 0,
+            error: None,
+            finished: false,
+        })
+    }
+
+    /// Offer the framebuffer at a vertical blank. If the screen has
+    /// not changed since the last frame, nothing is recorded.
+    pub fn vblank(&mut self, framebuffer: &[u8], inverted: bool, dirty: bool, now: u64) {
+        if self.error.is_some() || (!dirty && inverted == self.inverted) {
+            return;
+        }
+        self.inverted = inverted;
+
+        let mut screen = vec![0; FRAMEBUFFER_LEN];
+        copy_screen(&mut screen, framebuffer, inverted);
+
+        let (columns, rows) = match changed_region(&self.screen, &screen) {
+            Some(region) => region,
+            None => return,
+        };
+        let (left, right, top, bottom) = (columns.start, columns.end, rows.start, rows.end);
+
+        let mut pixels = Vec::with_capacity((right - left) * (bottom - top));
+        for y in top..bottom {
+            pixels.extend_from_slice(&screen[y * STRIDE + left..y * STRIDE + right]);
+        }
+        self.screen = screen;
+
+        let frame = Frame {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+            pixels,
+            time: now,
+        };
+        let previous = std::mem::replace(&mut self.pending, frame);
+        if let Err(e) = self.write_frame(&previous, now) {
+            error!("Unable to write recording: {}", e);
+            self.error = Some(e);
+        }
+    }
+
+    /// Write the last frame and finish the file.
+    pub fn finish(mut self, now: u64) -> io::Result<()> {
+        self.finish_file(now)
+    }
+
+    fn finish_file(&mut self, now: u64) -> io::Result<()> {
+        self.finished = true;
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        let last = std::mem::replace(
+            &mut self.pending,
+            Frame {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+                pixels: vec![],
+                time: now,
+            },
+        );
+        self.write_frame(&last, now)?;
+
+        match self.format {
+            VideoFormat::Gif => {
+                self.out.write_all(&[0x3b])?;
+                self.out.flush()?;
+            }
+            VideoFormat::Apng => {
+                let mut iend = vec![];
+                write_chunk(&mut iend, b"IEND", &[]);
+                self.out.write_all(&iend)?;
+
+                let mut control = self.frames.to_be_bytes().to_vec();
+                control.extend_from_slice(&0u32.to_be_bytes());
+                let mut actl = vec![];
+                write_chunk(&mut actl, b"acTL", &control);
+
+                self.out.flush()?;
+                let file = self.out.get_mut();
+                file.seek(SeekFrom::Start(ACTL_OFFSET))?;
+                file.write_all(&actl)?;
+            }
+        }
+
+        self.out.get_ref().sync_all()
+    }
+
+    fn write_frame(&mut self, frame: &Frame, until: u64) -> io::Result<()> {
+        match self.format {
+            VideoFormat::Gif => self.write_gif_frame(frame, until)?,
+            VideoFormat::Apng => self.write_apng_frame(frame, until)?,
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn write_gif_frame(&mut self, frame: &Frame, until: u64) -> io::Result<()> {
+        // Delays are in hundredths of a second
+        let delay = delay(frame.time, until, 10_000_000);
+
+        // Graphic control extension: leave the frame in place
+        self.out.write_all(&[0x21, 0xf9, 0x04, 0x04])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0x00, 0x00])?;
+
+        self.out.write_all(&[0x2c])?;
+        for v in [frame.x * 8, frame.y, frame.width * 8, frame.height].iter() {
+            self.out.write_all(&(*v as u16).to_le_bytes())?;
+        }
+        self.out.write_all(&[0x00, LZW_MIN_CODE_SIZE])?;
+
+        let data = lzw_encode(&frame.pixels);
+        for block in data.chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0x00])
+    }
+
+    fn write_apng_frame(&mut self, frame: &Frame, until: u64) -> io::Result<()> {
+        // Delays are in milliseconds
+        let delay = delay(frame.time, until, 1_000_000);
+
+        let mut control = self.sequence.to_be_bytes().to_vec();
+        for v in [frame.width * 8, frame.height, frame.x * 8, frame.y].iter() {
+            control.extend_from_slice(&(*v as u32).to_be_bytes());
+        }
+        // Delay in milliseconds, no disposal, replace the region
+        control.extend_from_slice(&delay.to_be_bytes());
+        control.extend_from_slice(&1000u16.to_be_bytes());
+        control.extend_from_slice(&[0, 0]);
+        self.sequence += 1;
+
+        let mut raw = Vec::with_capacity(frame.pixels.len() + frame.height);
+        for line in frame.pixels.chunks(frame.width) {
+            raw.push(0);
+            raw.extend_from_slice(line);
+        }
+        let compressed = zlib_stored(&raw);
+
+        let mut chunks = vec![];
+        write_chunk(&mut chunks, b"fcTL", &control);
+        if self.frames == 0 {
+            // The first frame is also the default image
+            write_chunk(&mut chunks, b"IDAT", &compressed);
+        } else {
+            let mut data = self.sequence.to_be_bytes().to_vec();
+            data.extend_from_slice(&compressed);
+            write_chunk(&mut chunks, b"fdAT", &data);
+            self.sequence += 1;
+        }
+        self.out.write_all(&chunks)
+    }
+}
+
+/// The delay between two times, in the given unit of nanoseconds.
+/// Rounding the times, rather than the difference, keeps rounding
+/// errors from accumulating over a recording.
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.finish_file(self.pending.time) {
+                error!("Unable to finish recording: {}", e);
+            }
+        }
+    }
+}
+
+fn delay(from: u64, until: u64, unit: u64) -> u16 {
+    // Time runs backwards when the terminal is rewound, which shows the
+    // earlier frame for no time at all
+    let delay = ((until + unit / 2) / unit).saturating_sub((from + unit / 2) / unit);
+    delay.min(u64::from(u16::MAX)) as u16
+}
+
+/// Copy the framebuffer, with white pixels set. If it is shorter than
+/// the screen, the rest of the screen is blank.
+fn copy_screen(screen: &mut [u8], framebuffer: &[u8], inverted: bool) {
+    let mask = if inverted {
+        0xff
+    } else {
+        0
+    };
+    let len = framebuffer.len().min(FRAMEBUFFER_LEN);
+    for (dst, src) in screen.iter_mut().zip(&framebuffer[..len]) {
+        *dst = src ^ mask;
+    }
+    for dst in screen[len..].iter_mut() {
+        *dst = mask;
+    }
+}
+
+/// Packs variable width codes, least significant bit first.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, width: u32) {
+        self.bits |= u32::from(code) << self.count;
+        self.count += width;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// LZW encode 1-bit pixels, most significant bit leftmost, as GIF
+/// color indexes.
+fn lzw_encode(pixels: &[u8]) -> Vec<u8> {
+    // Each code's successors, by pixel value
+    let mut table = vec![[0u16; 2]; LZW_MAX_CODE as usize + 1];
+    let mut hi = LZW_EOI;
+    let mut width = u32::from(LZW_MIN_CODE_SIZE) + 1;
+    let mut overflow = 1 << width;
+
+    let mut writer = BitWriter {
+        out: vec![],
+        bits: 0,
+        count: 0,
+    };
+    writer.write(LZW_CLEAR, width);
+
+    let mut bits = pixels.iter().flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1));
+    let mut code = match bits.next() {
+        Some(bit) => u16::from(bit),
+        None => {
+            writer.write(LZW_EOI, width);
+            return writer.finish();
+        }
+    };
+
+    for bit in bits {
+        let next = table[code as usize][bit as usize];
+        if next != 0 {
+            code = next;
+            continue;
+        }
+
+        writer.write(code, width);
+
+        hi += 1;
+        if hi == overflow {
+            width += 1;
+            overflow <<= 1;
+        }
+        if hi == LZW_MAX_CODE {
+            writer.write(LZW_CLEAR, width);
+            for entry in table.iter_mut() {
+                *entry = [0, 0];
+            }
+            hi = LZW_EOI;
+            width = u32::from(LZW_MIN_CODE_SIZE) + 1;
+            overflow = 1 << width;
+        } else {
+            table[code as usize][bit as usize] = hi;
+        }
+
+        code = u16::from(bit);
+    }
+
+    writer.write(code, width);
+    writer.write(LZW_EOI, width);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::crc32;
+
+    /// A GIF LZW decoder, following the specification rather than the
+    /// encoder.
+    fn lzw_decode(data: &[u8]) -> Vec<u8> {
+        let mut pos = 0;
+        let mut read = |width: u32| {
+            let mut code = 0;
+            for i in 0..width {
+                let bit = (data[(pos + i as usize) / 8] >> ((pos + i as usize) % 8)) & 1;
+                code |= u16::from(bit) << i;
+            }
+            pos += width as usize;
+            code
+        };
+
+        let mut out = vec![];
+        let mut table: Vec<Vec<u8>> = vec![];
+        let mut width = 3;
+        let mut prev: Option<Vec<u8>> = None;
+        loop {
+            let code = read(width);
+            if code == LZW_CLEAR {
+                table = (0..LZW_CLEAR + 2).map(|c| vec![c as u8]).collect();
+                width = 3;
+                prev = None;
+                continue;
+            }
+            if code == LZW_EOI {
+                break;
+            }
+            let entry = match (&prev, table.get(code as usize)) {
+                (_, Some(entry)) => entry.clone(),
+                (Some(p), None) => {
+                    let mut entry = p.clone();
+                    entry.push(p[0]);
+                    entry
+                }
+                (None, None) => panic!("bad code {}", code),
+            };
+            if let Some(mut p) = prev.take() {
+                if table.len() <= LZW_MAX_CODE as usize {
+                    p.push(entry[0]);
+                    table.push(p);
+                }
+            }
+            if table.len() == 1 << width && width < 12 {
+                width += 1;
+            }
+            out.extend_from_slice(&entry);
+            prev = Some(entry);
+        }
+        out
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("dmd_recorder_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn encodes_lzw() {
+        let mut pixels = vec![0u8; 4000];
+        for (i, p) in pixels.iter_mut().enumerate() {
+            *p = ((i * 7) ^ (i >> 3)) as u8;
+        }
+
+        let decoded = lzw_decode(&lzw_encode(&pixels));
+        let expected: Vec<u8> =
+            pixels.iter().flat_map(|b| (0..8).rev().map(move |i| (b >> i) & 1)).collect();
+        assert_eq!(expected, decoded);
+    }
+
+    #[test]
+    fn records_changed_regions_as_gif() {
+        let path = temp_path("gif");
+        let mut framebuffer = vec![0u8; FRAMEBUFFER_LEN];
+
+        let mut recorder =
+            Recorder::create(&path, VideoFormat::Gif, &framebuffer, false, 0).unwrap();
+        framebuffer[STRIDE * 10 + 3] = 0x80;
+        recorder.vblank(&framebuffer, false, true, 16_666_667);
+        // Unchanged frames are not recorded
+        recorder.vblank(&framebuffer, false, true, 33_333_333);
+        recorder.finish(100_000_000).unwrap();
+
+        let gif = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(b"GIF89a", &gif[0..6]);
+        assert_eq!(0x3b, gif[gif.len() - 1]);
+
+        // Two frames, of 2 and 8 hundredths of a second
+        let controls: Vec<usize> =
+            (0..gif.len() - 3).filter(|i| gif[*i..*i + 4] == [0x21, 0xf9, 0x04, 0x04]).collect();
+        assert_eq!(2, controls.len());
+        assert_eq!(&[2, 0], &gif[controls[0] + 4..controls[0] + 6]);
+        assert_eq!(&[8, 0], &gif[controls[1] + 4..controls[1] + 6]);
+
+        // The second is the changed byte, at x=24 y=10
+        let descriptor = &gif[controls[1] + 8..controls[1] + 18];
+        assert_eq!(&[0x2c, 24, 0, 10, 0, 8, 0, 1, 0, 0], descriptor);
+        let data_start = controls[1] + 19;
+        let len = gif[data_start] as usize;
+        let pixels = lzw_decode(&gif[data_start + 1..data_start + 1 + len]);
+        assert_eq!(vec![1, 0, 0, 0, 0, 0, 0, 0], pixels);
+    }
+
+    #[test]
+    fn records_apng() {
+        let path = temp_path("apng");
+        let mut framebuffer = vec![0u8; FRAMEBUFFER_LEN];
+
+        let mut recorder =
+            Recorder::create(&path, VideoFormat::Apng, &framebuffer, true, 0).unwrap();
+        framebuffer[STRIDE * HEIGHT - 1] = 0x01;
+        recorder.vblank(&framebuffer, true, true, 16_666_667);
+        recorder.finish(50_000_000).unwrap();
+
+        let png = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut chunks = vec![];
+        let mut pos = 8;
+        while pos < png.len() {
+            let len = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]);
+            let end = pos + 8 + len as usize;
+            assert_eq!(&crc32(&png[pos + 4..end]).to_be_bytes(), &png[end..end + 4]);
+            chunks.push((png[pos + 4..pos + 8].to_vec(), png[pos + 8..end].to_vec()));
+            pos = end + 4;
+        }
+
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(k, _)| &k[..]).collect();
+        assert_eq!(vec![&b"IHDR"[..], b"acTL", b"fcTL", b"IDAT", b"fcTL", b"fdAT", b"IEND"], kinds);
+
+        // Two frames, played forever
+        assert_eq!(&[0, 0, 0, 2, 0, 0, 0, 0], &chunks[1].1[..]);
+
+        // The second frame is the last byte on the screen, shown for
+        // 33ms, with sequence numbers counting through both frames
+        let fctl = &chunks[4].1;
+        assert_eq!(&[0, 0, 0, 1], &fctl[0..4]);
+        assert_eq!(&[0, 0, 0, 8, 0, 0, 0, 1], &fctl[4..12]);
+        assert_eq!(&[0, 0, 3, 24, 0, 0, 3, 255], &fctl[12..20]);
+        assert_eq!(&[0, 33, 3, 232, 0, 0], &fctl[20..26]);
+        assert_eq!(&[0, 0, 0, 2], &chunks[5].1[0..4]);
+
+        // Inverted, so the one lit pixel is black. The scanline follows
+        // the stored block header and the filter type.
+        assert_eq!(0xfe, chunks[5].1[4 + 2 + 5 + 1]);
+    }
+
+    #[test]
+    fn finishes_apng_when_dropped() {
+        let path = temp_path("dropped");
+        let mut framebuffer = vec![0u8; FRAMEBUFFER_LEN];
+
+        let mut recorder =
+            Recorder::create(&path, VideoFormat::Apng, &framebuffer, false, 0).unwrap();
+        framebuffer[0] = 0x80;
+        recorder.vblank(&framebuffer, false, true, 16_666_667);
+        drop(recorder);
+
+        let png = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let actl = ACTL_OFFSET as usize;
+        assert_eq!(b"acTL", &png[actl + 4..actl + 8]);
+        assert_eq!(&[0, 0, 0, 2, 0, 0, 0, 0], &png[actl + 8..actl + 16]);
+        assert_eq!(b"IEND", &png[png.len() - 8..png.len() - 4]);
+    }
+}
@@ -1,6 +1,6 @@
 // Auto-generated. Do not edit.
 pub const HI_ROM_V1_LEN: usize = 0x8000;
-pub const HI_ROM_V1: [u8; HI_ROM_V1_LEN] = [
+pub static HI_ROM_V1: [u8; HI_ROM_V1_LEN] = [
     0x64, 0x40, 0xd0, 0x40, 0x58, 0x40, 0xb0, 0x44, 0x40, 0x88, 0x40, 0x40, 0xb8, 0x40, 0x57, 0x70,
     0xbc, 0x04, 0x47, 0x94, 0x43, 0x28, 0x43, 0x47, 0xde, 0x84, 0x58, 0x44, 0x84, 0x59, 0x40, 0xd4,
     0x40, 0x44, 0x40, 0x84, 0x64, 0x41, 0xd0, 0x41, 0xc8, 0xfc, 0x41, 0xb0, 0x41, 0x40, 0xb8, 0xc9,
@@ -2051,7 +2051,7 @@ pub const HI_ROM_V1: [u8; HI_ROM_V1_LEN] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x25, 0xb4,
 ];
 pub const HI_ROM_V2_LEN: usize = 0x10000;
-pub const HI_ROM_V2: [u8; HI_ROM_V2_LEN] = [
+pub static HI_ROM_V2: [u8; HI_ROM_V2_LEN] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     0x00, 0x00, 0x00, 0x00, 0x60, 0x00, 0x03, 0xfc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
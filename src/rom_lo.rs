@@ -1,6 +1,6 @@
 // Auto-generated. Do not edit.
 pub const LO_ROM_V1_LEN: usize = 0x8000;
-pub const LO_ROM_V1: [u8; LO_ROM_V1_LEN] = [
+pub static LO_ROM_V1: [u8; LO_ROM_V1_LEN] = [
     0x00, 0x71, 0xc3, 0x20, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80,
     0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80,
     0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80,
@@ -2051,7 +2051,7 @@ pub const LO_ROM_V1: [u8; LO_ROM_V1_LEN] = [
     0x3c, 0x00, 0x43, 0x4f, 0x26, 0x84, 0x59, 0x40, 0xd4, 0x40, 0x58, 0x44, 0xbc, 0x04, 0x48, 0x84,
 ];
 pub const LO_ROM_V2_LEN: usize = 0x10000;
-pub const LO_ROM_V2: [u8; LO_ROM_V2_LEN] = [
+pub static LO_ROM_V2: [u8; LO_ROM_V2_LEN] = [
     0x00, 0x71, 0xc3, 0x20, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80,
     0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80,
     0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80, 0x00, 0x07, 0x04, 0x80,
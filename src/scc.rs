@@ -0,0 +1,859 @@
+//! The optional I/O board carries a Z8530 SCC, providing two extra
+//! asynchronous serial channels, A and B.
+//!
+//! The board decodes address bit 3 as the SCC's A/B select and bit 2
+//! as its Data/Control select, so that each channel has a control port
+//! and a data port on the low byte lane. Registers other than WR0/RR0
+//! are reached through the usual Z8530 register pointer: a write to
+//! WR0 selects the register that the next control access will use.
+//!
+//! Alongside the SCC, the board has three registers of its own: an
+//! interrupt acknowledge port, which returns the SCC's interrupt
+//! vector exactly as an interrupt acknowledge cycle would, a modem
+//! control latch, and a modem status register.
+//!
+//! Only asynchronous operation is simulated. Each channel has the
+//! SCC's three-slot receive FIFO and a transmit buffer and shift
+//! register, driven by events on the bus `Scheduler` at the rate set
+//! by the baud rate generator. As with the DUART, each channel also
+//! has a TX queue and an RX queue, which do not exist in hardware,
+//! to buffer data exchanged with the user of this library.
+//!
+//! The Z8530 is well documented in the Zilog SCC User's Manual.
+
+use crate::bus::{AccessCode, Device};
+use crate::err::BusError;
+use crate::sched::{Event, Scheduler};
+use crate::utils::FifoQueue;
+
+use log::{debug, trace};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::fmt::Error;
+use std::fmt::Formatter;
+use std::mem;
+use std::ops::Range;
+
+const START_ADDR: usize = 0x300000;
+const END_ADDR: usize = 0x300100;
+const ADDRESS_RANGE: Range<usize> = START_ADDR..END_ADDR;
+
+// The baud rate generator is clocked at 4.9152MHz
+const BRG_CLOCK: u64 = 4_915_200;
+const NS_PER_SEC: u64 = 1_000_000_000;
+// Start bit, eight data bits, stop bit
+const BITS_PER_CHAR: u64 = 10;
+// Character time used while the baud rate generator is not running
+const DEFAULT_CHAR_DELAY: u64 = 1_000_000;
+
+pub const CHANNEL_A: usize = 0;
+pub const CHANNEL_B: usize = 1;
+
+//
+// Board Registers
+//
+const CTRL_B: u8 = 0x03;
+const DATA_B: u8 = 0x07;
+const CTRL_A: u8 = 0x0b;
+const DATA_A: u8 = 0x0f;
+const INTACK: u8 = 0x13;
+const MODEM_CTRL: u8 = 0x23;
+const MODEM_STATUS: u8 = 0x33;
+
+//
+// WR0 Commands
+//
+const CMD_POINT_HIGH: u8 = 0x01;
+const CMD_RST_EXT: u8 = 0x02;
+const CMD_INT_NEXT_RX: u8 = 0x04;
+const CMD_RST_TX_IP: u8 = 0x05;
+const CMD_RST_ERR: u8 = 0x06;
+const CMD_RST_IUS: u8 = 0x07;
+
+//
+// Write Register Bits
+//
+const WR1_TX_IE: u8 = 0x02; // Tx Interrupt Enable
+const WR1_RX_INT_MASK: u8 = 0x18; // Rx Interrupt Mode
+const RX_INT_FIRST: u8 = 0x08; // On first character or special condition
+const RX_INT_ALL: u8 = 0x10; // On all characters or special condition
+const RX_INT_SPECIAL: u8 = 0x18; // On special condition only
+const WR3_RX_ENABLE: u8 = 0x01;
+const WR5_TX_ENABLE: u8 = 0x08;
+const WR5_DTR: u8 = 0x80;
+const WR9_VIS: u8 = 0x01; // Vector Includes Status
+const WR9_MIE: u8 = 0x08; // Master Interrupt Enable
+const WR9_STATUS_HIGH: u8 = 0x10;
+const WR9_RESET_MASK: u8 = 0xc0;
+const WR9_RESET_B: u8 = 0x40;
+const WR9_RESET_A: u8 = 0x80;
+const WR9_RESET_HW: u8 = 0xc0;
+const WR14_BRG_ENABLE: u8 = 0x01;
+const WR14_LOOPBACK: u8 = 0x10;
+
+//
+// Read Register Bits
+//
+const RR0_RX_AVAIL: u8 = 0x01; // Rx Character Available
+const RR0_TX_EMPTY: u8 = 0x04; // Tx Buffer Empty
+const RR0_DCD: u8 = 0x08; // Data Carrier Detect
+const RR0_CTS: u8 = 0x20; // Clear To Send
+const RR0_TX_UNDERRUN: u8 = 0x40; // Tx Underrun/EOM
+const RR1_ALL_SENT: u8 = 0x01;
+const RR1_RESIDUE: u8 = 0x06;
+const RR1_OVERRUN: u8 = 0x20;
+
+//
+// Interrupt Pending / Under Service bits, in RR3 order. Higher bits
+// have higher priority.
+//
+const IP_A_RX: u8 = 0x20;
+const IP_A_TX: u8 = 0x10;
+const IP_B_RX: u8 = 0x04;
+const IP_B_TX: u8 = 0x02;
+
+//
+// CPU Interrupt Vector.
+//
+const SCC_INT: u8 = 0x08;
+
+#[derive(Clone)]
+struct Channel {
+    // Register pointer and write registers. WR2 and WR9 are shared
+    // between channels, and live on the SCC.
+    ptr: usize,
+    wr: [u8; 16],
+    // State used by the RX and TX state machines.
+    rx_fifo: FifoQueue,
+    rx_overrun: bool,
+    rx_int_armed: bool,
+    tx_buf: Option<u8>,
+    tx_shift_reg: Option<u8>,
+    tx_ip: bool,
+    // Buffers to hold TX and RX characters so that they can be
+    // processed by the user of this library in chunks.
+    rx_deque: VecDeque<u8>,
+    tx_deque: VecDeque<u8>,
+    // Service timing info
+    next_tx_service: u64,
+    next_rx_service: u64,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        let mut channel = Channel {
+            ptr: 0,
+            wr: [0; 16],
+            rx_fifo: FifoQueue::new(),
+            rx_overrun: false,
+            rx_int_armed: false,
+            tx_buf: None,
+            tx_shift_reg: None,
+            tx_ip: false,
+            rx_deque: VecDeque::new(),
+            tx_deque: VecDeque::new(),
+            next_tx_service: 0,
+            next_rx_service: 0,
+        };
+        channel.reset();
+        channel
+    }
+
+    /// Reset the channel, putting its registers into the state
+    /// described by the SCC User's Manual for a channel reset.
+    fn reset(&mut self) {
+        self.ptr = 0;
+        self.wr[0] = 0;
+        self.wr[1] &= 0x24;
+        self.wr[3] &= !WR3_RX_ENABLE;
+        self.wr[5] &= 0x61;
+        self.wr[14] = (self.wr[14] & 0xc3) | 0x20;
+        self.wr[15] = 0xf8;
+        self.rx_fifo.clear();
+        self.rx_overrun = false;
+        self.rx_int_armed = false;
+        self.tx_buf = None;
+        self.tx_shift_reg = None;
+        self.tx_ip = false;
+    }
+
+    /// The time taken to send or receive one character, in
+    /// nanoseconds.
+    fn char_delay(&self) -> u64 {
+        let multiplier = match self.wr[4] >> 6 {
+            0 => 1,
+            1 => 16,
+            2 => 32,
+            _ => 64,
+        };
+
+        if self.wr[14] & WR14_BRG_ENABLE == 0 {
+            return DEFAULT_CHAR_DELAY;
+        }
+
+        let time_constant = u64::from(self.wr[13]) << 8 | u64::from(self.wr[12]);
+        let baud_rate = (BRG_CLOCK / (2 * multiplier * (time_constant + 2))).max(1);
+
+        NS_PER_SEC * BITS_PER_CHAR / baud_rate
+    }
+
+    fn loopback(&self) -> bool {
+        self.wr[14] & WR14_LOOPBACK != 0
+    }
+
+    fn rx_enabled(&self) -> bool {
+        self.wr[3] & WR3_RX_ENABLE != 0
+    }
+
+    fn tx_enabled(&self) -> bool {
+        self.wr[5] & WR5_TX_ENABLE != 0
+    }
+
+    fn dtr(&self) -> bool {
+        self.wr[5] & WR5_DTR != 0
+    }
+
+    /// True if a special receive condition is present.
+    fn rx_special(&self) -> bool {
+        self.rx_overrun
+    }
+
+    /// True if the receiver is requesting an interrupt.
+    fn rx_ip(&self) -> bool {
+        match self.wr[1] & WR1_RX_INT_MASK {
+            RX_INT_FIRST => (self.rx_int_armed && !self.rx_fifo.is_empty()) || self.rx_special(),
+            RX_INT_ALL => !self.rx_fifo.is_empty() || self.rx_special(),
+            RX_INT_SPECIAL => self.rx_special(),
+            _ => false,
+        }
+    }
+
+    /// True if the transmitter is requesting an interrupt.
+    fn tx_ip(&self) -> bool {
+        self.tx_ip && self.wr[1] & WR1_TX_IE != 0
+    }
+
+    fn rr0(&self) -> u8 {
+        let mut val = RR0_DCD | RR0_CTS;
+        if !self.rx_fifo.is_empty() {
+            val |= RR0_RX_AVAIL;
+        }
+        if self.tx_buf.is_none() {
+            val |= RR0_TX_EMPTY;
+            if self.tx_shift_reg.is_none() {
+                val |= RR0_TX_UNDERRUN;
+            }
+        }
+        val
+    }
+
+    fn rr1(&self) -> u8 {
+        let mut val = RR1_RESIDUE;
+        if self.tx_buf.is_none() && self.tx_shift_reg.is_none() {
+            val |= RR1_ALL_SENT;
+        }
+        if self.rx_overrun {
+            val |= RR1_OVERRUN;
+        }
+        val
+    }
+
+    /// Read a single character out of the receive FIFO.
+    fn rx_read_char(&mut self) -> Option<u8> {
+        let c = self.rx_fifo.pop().ok();
+        if c.is_some() {
+            self.rx_int_armed = false;
+        }
+        c
+    }
+
+    /// Receive a single character into the FIFO. If the FIFO is
+    /// already full, the character is lost.
+    fn rx_char(&mut self, c: u8) {
+        trace!("SCC rx_char: {:02x}, fifo_len={}", c, self.rx_fifo.len());
+        if self.rx_fifo.push(c).is_err() {
+            self.rx_overrun = true;
+        }
+    }
+
+    /// Load a character into the transmit buffer.
+    fn tx_char(&mut self, c: u8) {
+        self.tx_buf = Some(c);
+        self.tx_ip = false;
+    }
+
+    /// The time at which the receiver next needs servicing, if it has
+    /// any work to do.
+    fn next_rx_event(&self) -> Option<u64> {
+        if self.rx_enabled() && !self.rx_deque.is_empty() {
+            Some(self.next_rx_service)
+        } else {
+            None
+        }
+    }
+
+    /// The time at which the transmitter next needs servicing, if it
+    /// has any work to do.
+    fn next_tx_event(&self) -> Option<u64> {
+        if self.tx_enabled() && (self.tx_buf.is_some() || self.tx_shift_reg.is_some()) {
+            Some(self.next_tx_service)
+        } else {
+            None
+        }
+    }
+
+    /// Move the receiver state machine. Characters are only taken
+    /// from the RX queue when there is room in the FIFO for them.
+    fn rx_service(&mut self, now: u64) {
+        if !self.rx_enabled() || now < self.next_rx_service {
+            return;
+        }
+
+        if !self.loopback() && !self.rx_fifo.is_full() {
+            if let Some(c) = self.rx_deque.pop_back() {
+                self.rx_char(c);
+            }
+        }
+
+        self.next_rx_service = now + self.char_delay();
+    }
+
+    /// Move the transmitter state machine.
+    fn tx_service(&mut self, now: u64) {
+        if !self.tx_enabled() || now < self.next_tx_service {
+            return;
+        }
+
+        if let Some(c) = self.tx_shift_reg.take() {
+            if self.loopback() {
+                debug!("SCC TX: LOOPBACK: Finish transmit character {:02x}", c);
+                self.rx_char(c);
+            } else {
+                debug!("SCC TX: Finish transmit character {:02x}", c);
+                self.tx_deque.push_front(c);
+            }
+        }
+
+        if let Some(c) = self.tx_buf.take() {
+            self.tx_shift_reg = Some(c);
+            // The transmit buffer is now empty
+            self.tx_ip = true;
+            self.next_tx_service = now + self.char_delay();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Scc {
+    channels: [Channel; 2],
+    wr2: u8,
+    wr9: u8,
+    ius: u8,
+    modem_ctrl: u8,
+}
+
+impl Default for Scc {
+    fn default() -> Self {
+        Scc::new()
+    }
+}
+
+impl Scc {
+    pub fn new() -> Scc {
+        Scc {
+            channels: [Channel::new(), Channel::new()],
+            wr2: 0,
+            wr9: 0,
+            ius: 0,
+            modem_ctrl: 0,
+        }
+    }
+
+    /// Interrupts that are pending, in RR3 order.
+    fn ip(&self) -> u8 {
+        let a = &self.channels[CHANNEL_A];
+        let b = &self.channels[CHANNEL_B];
+        let mut ip = 0;
+
+        if a.rx_ip() {
+            ip |= IP_A_RX;
+        }
+        if a.tx_ip() {
+            ip |= IP_A_TX;
+        }
+        if b.rx_ip() {
+            ip |= IP_B_RX;
+        }
+        if b.tx_ip() {
+            ip |= IP_B_TX;
+        }
+
+        ip
+    }
+
+    /// The highest priority pending interrupt that is not blocked by
+    /// an interrupt of equal or higher priority already under service.
+    fn highest_ip(&self) -> Option<u8> {
+        let ip = self.ip();
+        (0..8).rev().map(|bit| 1u8 << bit).find(|&bit| ip & bit != 0).filter(|&bit| self.ius < bit)
+    }
+
+    /// The interrupt vector, modified to include the status of the
+    /// highest priority pending interrupt if WR9 requests it.
+    fn vector(&self) -> u8 {
+        if self.wr9 & WR9_VIS == 0 {
+            return self.wr2;
+        }
+
+        let code = match self.highest_ip() {
+            Some(IP_A_RX) if self.channels[CHANNEL_A].rx_special() => 7,
+            Some(IP_A_RX) => 6,
+            Some(IP_A_TX) => 4,
+            Some(IP_B_RX) if self.channels[CHANNEL_B].rx_special() => 3,
+            Some(IP_B_RX) => 2,
+            Some(IP_B_TX) => 0,
+            // No interrupt pending
+            _ => 3,
+        };
+
+        if self.wr9 & WR9_STATUS_HIGH == 0 {
+            (self.wr2 & !0x0e) | (code << 1)
+        } else {
+            let reversed = ((code & 1) << 2) | (code & 2) | ((code & 4) >> 2);
+            (self.wr2 & !0x70) | (reversed << 4)
+        }
+    }
+
+    /// Perform an interrupt acknowledge cycle, returning the vector
+    /// and placing the highest priority pending interrupt under
+    /// service.
+    fn interrupt_acknowledge(&mut self) -> u8 {
+        let vector = self.vector();
+        if let Some(bit) = self.highest_ip() {
+            self.ius |= bit;
+        }
+        vector
+    }
+
+    pub fn get_interrupt(&self) -> Option<u8> {
+        if self.wr9 & WR9_MIE != 0 && self.highest_ip().is_some() {
+            Some(SCC_INT)
+        } else {
+            None
+        }
+    }
+
+    /// The modem status register. Inputs are active low. With nothing
+    /// attached to the board, the inputs are looped back as the
+    /// firmware's self-test expects: each channel's DTR output drives
+    /// all of its inputs, except that bits 3 and 7 of the modem control
+    /// latch hold the third input of channel A and B, respectively.
+    fn modem_status(&self) -> u8 {
+        let mut val = 0;
+        if !self.channels[CHANNEL_A].dtr() {
+            val |= 0x07;
+        }
+        if !self.channels[CHANNEL_B].dtr() {
+            val |= 0x38;
+        }
+        if self.modem_ctrl & 0x08 != 0 {
+            val &= !0x04;
+        }
+        if self.modem_ctrl & 0x80 != 0 {
+            val &= !0x20;
+        }
+        val
+    }
+
+    /// Register the times at which the SCC next needs servicing.
+    pub fn schedule(&self, scheduler: &mut Scheduler) {
+        let events = [
+            (Event::SccTxA, self.channels[CHANNEL_A].next_tx_event()),
+            (Event::SccRxA, self.channels[CHANNEL_A].next_rx_event()),
+            (Event::SccTxB, self.channels[CHANNEL_B].next_tx_event()),
+            (Event::SccRxB, self.channels[CHANNEL_B].next_rx_event()),
+        ];
+
+        for (event, at) in events.iter() {
+            match at {
+                Some(at) => scheduler.schedule(*event, *at),
+                None => scheduler.cancel(*event),
+            }
+        }
+    }
+
+    /// Handle a scheduled event that has come due.
+    pub fn service(&mut self, event: Event, now: u64) {
+        match event {
+            Event::SccTxA => self.channels[CHANNEL_A].tx_service(now),
+            Event::SccRxA => self.channels[CHANNEL_A].rx_service(now),
+            Event::SccTxB => self.channels[CHANNEL_B].tx_service(now),
+            Event::SccRxB => self.channels[CHANNEL_B].rx_service(now),
+            _ => {}
+        }
+    }
+
+    /// Queue a single character for processing by a channel.
+    pub fn rx(&mut self, channel: usize, c: u8) {
+        self.channels[channel].rx_deque.push_front(c);
+    }
+
+    /// Take the next character transmitted by a channel, if any.
+    pub fn tx(&mut self, channel: usize) -> Option<u8> {
+        self.channels[channel].tx_deque.pop_back()
+    }
+
+    /// Swap the characters waiting for the user of this library with
+    /// those of another SCC.
+    pub fn swap_output(&mut self, other: &mut Scc) {
+        for (channel, other_channel) in self.channels.iter_mut().zip(other.channels.iter_mut()) {
+            mem::swap(&mut channel.tx_deque, &mut other_channel.tx_deque);
+        }
+    }
+
+    fn read_control(&mut self, channel: usize) -> u8 {
+        let ctx = &mut self.channels[channel];
+        let reg = ctx.ptr;
+        ctx.ptr = 0;
+
+        let val = match reg {
+            0 | 4 => ctx.rr0(),
+            1 | 5 => ctx.rr1(),
+            2 | 6 => {
+                if channel == CHANNEL_A {
+                    self.wr2
+                } else {
+                    self.vector()
+                }
+            }
+            3 | 7 => {
+                if channel == CHANNEL_A {
+                    self.ip()
+                } else {
+                    0
+                }
+            }
+            8 => ctx.rx_read_char().unwrap_or_default(),
+            9 | 13 => ctx.wr[13],
+            10 | 14 => 0,
+            12 => ctx.wr[12],
+            _ => ctx.wr[15] & !0x05,
+        };
+
+        trace!("READ : SCC{} RR{}, val={:02x}", channel, reg, val);
+        val
+    }
+
+    fn write_control(&mut self, channel: usize, val: u8) {
+        let reg = self.channels[channel].ptr;
+        self.channels[channel].ptr = 0;
+
+        trace!("WRITE: SCC{} WR{}, val={:02x}", channel, reg, val);
+
+        match reg {
+            0 => self.command(channel, val),
+            2 => self.wr2 = val,
+            8 => self.channels[channel].tx_char(val),
+            9 => {
+                match val & WR9_RESET_MASK {
+                    WR9_RESET_B => self.channels[CHANNEL_B].reset(),
+                    WR9_RESET_A => self.channels[CHANNEL_A].reset(),
+                    WR9_RESET_HW => {
+                        self.channels[CHANNEL_A].reset();
+                        self.channels[CHANNEL_B].reset();
+                        self.ius = 0;
+                    }
+                    _ => {}
+                }
+                self.wr9 = val & !WR9_RESET_MASK;
+            }
+            1 => {
+                let ctx = &mut self.channels[channel];
+                if val & WR1_RX_INT_MASK == RX_INT_FIRST
+                    && ctx.wr[1] & WR1_RX_INT_MASK != RX_INT_FIRST
+                {
+                    ctx.rx_int_armed = true;
+                }
+                ctx.wr[1] = val;
+            }
+            _ => self.channels[channel].wr[reg] = val,
+        }
+    }
+
+    /// Handle a write to WR0.
+    fn command(&mut self, channel: usize, val: u8) {
+        let ctx = &mut self.channels[channel];
+        ctx.ptr = (val & 0x07) as usize;
+
+        match (val >> 3) & 0x07 {
+            CMD_POINT_HIGH => ctx.ptr |= 8,
+            CMD_RST_EXT => {
+                debug!("SCC{}: Reset Ext/Status Interrupts.", channel);
+            }
+            CMD_INT_NEXT_RX => {
+                debug!("SCC{}: Enable Interrupt on Next Rx Character.", channel);
+                ctx.rx_int_armed = true;
+            }
+            CMD_RST_TX_IP => {
+                debug!("SCC{}: Reset Tx Interrupt Pending.", channel);
+                ctx.tx_ip = false;
+            }
+            CMD_RST_ERR => {
+                debug!("SCC{}: Error Reset.", channel);
+                ctx.rx_overrun = false;
+            }
+            CMD_RST_IUS => {
+                debug!("SCC{}: Reset Highest IUS.", channel);
+                if let Some(bit) =
+                    (0..8).rev().map(|bit| 1u8 << bit).find(|&bit| self.ius & bit != 0)
+                {
+                    self.ius &= !bit;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Debug for Scc {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "[SCC]")
+    }
+}
+
+impl Device for Scc {
+    fn address_range(&self) -> &Range<usize> {
+        &ADDRESS_RANGE
+    }
+
+    fn name(&self) -> &str {
+        "SCC"
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read_byte(&mut self, address: usize, _access: AccessCode) -> Result<u8, BusError> {
+        match (address - START_ADDR) as u8 {
+            CTRL_A => Ok(self.read_control(CHANNEL_A)),
+            CTRL_B => Ok(self.read_control(CHANNEL_B)),
+            DATA_A => Ok(self.channels[CHANNEL_A].rx_read_char().unwrap_or_default()),
+            DATA_B => Ok(self.channels[CHANNEL_B].rx_read_char().unwrap_or_default()),
+            INTACK => {
+                let val = self.interrupt_acknowledge();
+                trace!("READ : INTACK, val={:02x}", val);
+                Ok(val)
+            }
+            MODEM_CTRL => Ok(self.modem_ctrl),
+            MODEM_STATUS => Ok(self.modem_status()),
+            _ => {
+                trace!("READ : UNHANDLED. ADDRESS={:08x}", address);
+                Err(BusError::NoDevice(address))
+            }
+        }
+    }
+
+    fn read_half(&mut self, address: usize, access: AccessCode) -> Result<u16, BusError> {
+        let b = self.read_byte(address + 1, access)?;
+        Ok(u16::from(b))
+    }
+
+    fn read_word(&mut self, address: usize, access: AccessCode) -> Result<u32, BusError> {
+        let b = self.read_byte(address + 3, access)?;
+        Ok(u32::from(b))
+    }
+
+    fn write_byte(&mut self, address: usize, val: u8, _access: AccessCode) -> Result<(), BusError> {
+        match (address - START_ADDR) as u8 {
+            CTRL_A => self.write_control(CHANNEL_A, val),
+            CTRL_B => self.write_control(CHANNEL_B, val),
+            DATA_A => self.channels[CHANNEL_A].tx_char(val),
+            DATA_B => self.channels[CHANNEL_B].tx_char(val),
+            MODEM_CTRL => {
+                trace!("WRITE: MODEM_CTRL, val={:02x}", val);
+                self.modem_ctrl = val;
+            }
+            _ => {
+                trace!("WRITE: UNHANDLED. ADDRESS={:08x}", address);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_half(&mut self, address: usize, val: u16, access: AccessCode) -> Result<(), BusError> {
+        self.write_byte(address + 1, val as u8, access)
+    }
+
+    fn write_word(&mut self, address: usize, val: u32, access: AccessCode) -> Result<(), BusError> {
+        self.write_byte(address + 3, val as u8, access)
+    }
+
+    fn load(&mut self, address: usize, _data: &[u8]) -> Result<(), BusError> {
+        Err(BusError::Write(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_reg(scc: &mut Scc, ctrl: u8, reg: u8, val: u8) {
+        let ctrl = START_ADDR + ctrl as usize;
+        if reg > 7 {
+            scc.write_byte(ctrl, (CMD_POINT_HIGH << 3) | (reg & 7), AccessCode::Write).unwrap();
+        } else if reg > 0 {
+            scc.write_byte(ctrl, reg, AccessCode::Write).unwrap();
+        }
+        scc.write_byte(ctrl, val, AccessCode::Write).unwrap();
+    }
+
+    fn read_reg(scc: &mut Scc, ctrl: u8, reg: u8) -> u8 {
+        let ctrl = START_ADDR + ctrl as usize;
+        if reg > 7 {
+            scc.write_byte(ctrl, (CMD_POINT_HIGH << 3) | (reg & 7), AccessCode::Write).unwrap();
+        } else if reg > 0 {
+            scc.write_byte(ctrl, reg, AccessCode::Write).unwrap();
+        }
+        scc.read_byte(ctrl, AccessCode::AddressFetch).unwrap()
+    }
+
+    #[test]
+    fn register_pointer_selects_registers() {
+        let mut scc = Scc::new();
+
+        write_reg(&mut scc, CTRL_A, 12, 0x1e);
+        write_reg(&mut scc, CTRL_B, 12, 0x06);
+
+        assert_eq!(0x1e, read_reg(&mut scc, CTRL_A, 12));
+        assert_eq!(0x06, read_reg(&mut scc, CTRL_B, 12));
+        // The pointer returns to RR0 after each access
+        assert_eq!(
+            RR0_TX_EMPTY | RR0_TX_UNDERRUN | RR0_DCD | RR0_CTS,
+            read_reg(&mut scc, CTRL_A, 0)
+        );
+    }
+
+    #[test]
+    fn computes_character_time_from_baud_rate_generator() {
+        let mut scc = Scc::new();
+
+        // x32 clock, time constant 6: 9600 baud
+        write_reg(&mut scc, CTRL_A, 4, 0x84);
+        write_reg(&mut scc, CTRL_A, 12, 6);
+        write_reg(&mut scc, CTRL_A, 13, 0);
+        write_reg(&mut scc, CTRL_A, 14, WR14_BRG_ENABLE);
+
+        assert_eq!(NS_PER_SEC * BITS_PER_CHAR / 9600, scc.channels[CHANNEL_A].char_delay());
+        assert_eq!(DEFAULT_CHAR_DELAY, scc.channels[CHANNEL_B].char_delay());
+    }
+
+    #[test]
+    fn transmits_characters() {
+        let mut scc = Scc::new();
+
+        write_reg(&mut scc, CTRL_B, 5, WR5_TX_ENABLE);
+        scc.write_byte(START_ADDR + DATA_B as usize, 0x41, AccessCode::Write).unwrap();
+        assert_eq!(0, read_reg(&mut scc, CTRL_B, 0) & RR0_TX_EMPTY);
+
+        // Into the shift register, then out one character time later
+        scc.service(Event::SccTxB, 0);
+        assert_ne!(0, read_reg(&mut scc, CTRL_B, 0) & RR0_TX_EMPTY);
+        assert_eq!(None, scc.tx(CHANNEL_B));
+        scc.service(Event::SccTxB, DEFAULT_CHAR_DELAY);
+        assert_eq!(Some(0x41), scc.tx(CHANNEL_B));
+        assert_eq!(None, scc.tx(CHANNEL_A));
+    }
+
+    #[test]
+    fn receives_characters_and_interrupts() {
+        let mut scc = Scc::new();
+
+        write_reg(&mut scc, CTRL_A, 9, WR9_RESET_HW | WR9_MIE | WR9_VIS);
+        write_reg(&mut scc, CTRL_A, 2, 0x40);
+        write_reg(&mut scc, CTRL_A, 1, RX_INT_ALL);
+        write_reg(&mut scc, CTRL_A, 3, WR3_RX_ENABLE);
+
+        scc.rx(CHANNEL_A, 0x5a);
+        assert_eq!(None, scc.get_interrupt());
+        scc.service(Event::SccRxA, 0);
+        assert_eq!(Some(SCC_INT), scc.get_interrupt());
+        assert_eq!(IP_A_RX, read_reg(&mut scc, CTRL_A, 3));
+
+        // Acknowledge: Channel A Rx Character Available is status 6
+        assert_eq!(0x4c, scc.read_byte(START_ADDR + INTACK as usize, AccessCode::IrqAck).unwrap());
+        assert_eq!(
+            0x5a,
+            scc.read_byte(START_ADDR + DATA_A as usize, AccessCode::AddressFetch).unwrap()
+        );
+        assert_eq!(None, scc.get_interrupt());
+
+        // Reset Highest IUS
+        write_reg(&mut scc, CTRL_A, 0, CMD_RST_IUS << 3);
+        assert_eq!(0, scc.ius);
+    }
+
+    #[test]
+    fn interrupts_under_service_block_lower_priorities() {
+        let mut scc = Scc::new();
+
+        write_reg(&mut scc, CTRL_A, 9, WR9_MIE);
+        write_reg(&mut scc, CTRL_A, 1, RX_INT_ALL);
+        write_reg(&mut scc, CTRL_A, 3, WR3_RX_ENABLE);
+        write_reg(&mut scc, CTRL_B, 1, RX_INT_ALL);
+        write_reg(&mut scc, CTRL_B, 3, WR3_RX_ENABLE);
+
+        scc.rx(CHANNEL_A, 1);
+        scc.rx(CHANNEL_B, 2);
+        scc.service(Event::SccRxA, 0);
+        scc.service(Event::SccRxB, 0);
+
+        scc.read_byte(START_ADDR + INTACK as usize, AccessCode::IrqAck).unwrap();
+        assert_eq!(IP_A_RX, scc.ius);
+        assert_eq!(None, scc.get_interrupt());
+
+        write_reg(&mut scc, CTRL_A, 0, CMD_RST_IUS << 3);
+        assert_eq!(Some(SCC_INT), scc.get_interrupt());
+    }
+
+    #[test]
+    fn loops_back_transmitted_characters() {
+        let mut scc = Scc::new();
+
+        write_reg(&mut scc, CTRL_A, 14, WR14_LOOPBACK);
+        write_reg(&mut scc, CTRL_A, 3, WR3_RX_ENABLE);
+        write_reg(&mut scc, CTRL_A, 5, WR5_TX_ENABLE);
+        scc.write_byte(START_ADDR + DATA_A as usize, 0x33, AccessCode::Write).unwrap();
+
+        scc.service(Event::SccTxA, 0);
+        scc.service(Event::SccTxA, DEFAULT_CHAR_DELAY);
+
+        assert_eq!(None, scc.tx(CHANNEL_A));
+        assert_ne!(0, read_reg(&mut scc, CTRL_A, 0) & RR0_RX_AVAIL);
+        assert_eq!(
+            0x33,
+            scc.read_byte(START_ADDR + DATA_A as usize, AccessCode::AddressFetch).unwrap()
+        );
+    }
+
+    #[test]
+    fn modem_status_passes_firmware_self_test() {
+        let mut scc = Scc::new();
+        let modem_ctrl = START_ADDR + MODEM_CTRL as usize;
+        let modem_status = START_ADDR + MODEM_STATUS as usize;
+
+        write_reg(&mut scc, CTRL_A, 5, WR5_DTR);
+        write_reg(&mut scc, CTRL_B, 5, WR5_DTR);
+        scc.write_byte(modem_ctrl, 0x88, AccessCode::Write).unwrap();
+        assert_eq!(0, scc.read_byte(modem_status, AccessCode::AddressFetch).unwrap() & 0xdb);
+        scc.write_byte(modem_ctrl, 0, AccessCode::Write).unwrap();
+        assert_eq!(0, scc.read_byte(modem_status, AccessCode::AddressFetch).unwrap());
+
+        write_reg(&mut scc, CTRL_A, 5, 0);
+        write_reg(&mut scc, CTRL_B, 5, 0);
+        assert_eq!(0x3f, scc.read_byte(modem_status, AccessCode::AddressFetch).unwrap());
+        scc.write_byte(modem_ctrl, 0x88, AccessCode::Write).unwrap();
+        assert_eq!(0x1b, scc.read_byte(modem_status, AccessCode::AddressFetch).unwrap() & 0xdb);
+    }
+}
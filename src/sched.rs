@@ -0,0 +1,217 @@
+//! A central queue of timed device events.
+//!
+//! Devices register the emulated time at which they next need to act
+//! (a character finishing its trip through a shift register, the next
+//! vertical blank, and so on), and the bus only services them once
+//! that time has been reached. Checking whether anything is due costs
+//! a single comparison per instruction.
+//!
+//! Time is measured in emulated nanoseconds, counted from reset, and
+//! advances by a fixed amount for every instruction executed.
+
+/// Emulated time taken by one instruction, in nanoseconds. The 5620's
+/// WE32100 runs at 10MHz, and averages roughly ten clocks per
+/// instruction.
+pub const INSTRUCTION_NS: u64 = 1_000;
+
+const EVENT_COUNT: usize = 11;
+
+/// Every kind of event a device may schedule.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// Apply queued input that has come due. Input goes ahead of
+    /// device events due at the same time.
+    Input,
+    /// DUART port A transmitter state machine
+    DuartTxA,
+    /// DUART port A receiver state machine
+    DuartRxA,
+    /// DUART port B transmitter state machine
+    DuartTxB,
+    /// DUART port B receiver state machine
+    DuartRxB,
+    /// Start of the display's vertical blanking interval
+    VerticalBlank,
+    /// SCC channel A transmitter state machine
+    SccTxA,
+    /// SCC channel A receiver state machine
+    SccRxA,
+    /// SCC channel B transmitter state machine
+    SccTxB,
+    /// SCC channel B receiver state machine
+    SccRxB,
+    /// Write unsaved BBRAM changes to its file
+    BbramFlush,
+}
+
+const EVENTS: [Event; EVENT_COUNT] = [
+    Event::Input,
+    Event::DuartTxA,
+    Event::DuartRxA,
+    Event::DuartTxB,
+    Event::DuartRxB,
+    Event::VerticalBlank,
+    Event::SccTxA,
+    Event::SccRxA,
+    Event::SccTxB,
+    Event::SccRxB,
+    Event::BbramFlush,
+];
+
+impl Event {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Each event may be scheduled at most once; scheduling it again
+/// replaces its previous deadline.
+#[derive(Clone)]
+pub struct Scheduler {
+    now: u64,
+    next: u64,
+    deadlines: [Option<u64>; EVENT_COUNT],
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            now: 0,
+            next: u64::MAX,
+            deadlines: [None; EVENT_COUNT],
+        }
+    }
+
+    /// The current emulated time, in nanoseconds.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Advance emulated time, returning true if any event is now due.
+    #[inline]
+    pub fn advance(&mut self, ns: u64) -> bool {
+        self.now += ns;
+        self.now >= self.next
+    }
+
+    /// Schedule an event to fire at the given emulated time. An event
+    /// scheduled in the past fires on the next call to `advance`.
+    pub fn schedule(&mut self, event: Event, at: u64) {
+        let previous = self.deadlines[event.index()].replace(at);
+        if previous == Some(self.next) {
+            self.update_next();
+        } else {
+            self.next = self.next.min(at);
+        }
+    }
+
+    /// Cancel an event, if it is scheduled.
+    pub fn cancel(&mut self, event: Event) {
+        if self.deadlines[event.index()].take().is_some() {
+            self.update_next();
+        }
+    }
+
+    /// Remove and return the earliest event that is due. Events that
+    /// are due at the same time are returned in declaration order.
+    pub fn pop_due(&mut self) -> Option<Event> {
+        if self.now < self.next {
+            return None;
+        }
+
+        let mut due: Option<(u64, Event)> = None;
+
+        for event in EVENTS.iter() {
+            if let Some(at) = self.deadlines[event.index()] {
+                if at <= self.now && due.map_or(true, |(t, _)| at < t) {
+                    due = Some((at, *event));
+                }
+            }
+        }
+
+        due.map(|(_, event)| {
+            self.deadlines[event.index()] = None;
+            self.update_next();
+            event
+        })
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.deadlines.iter().flatten().copied().min().unwrap_or(u64::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_due_when_nothing_is_scheduled() {
+        let mut s = Scheduler::new();
+
+        assert!(!s.advance(1_000_000));
+        assert_eq!(None, s.pop_due());
+    }
+
+    #[test]
+    fn events_become_due_at_their_deadline() {
+        let mut s = Scheduler::new();
+
+        s.schedule(Event::VerticalBlank, 3000);
+
+        assert!(!s.advance(1000));
+        assert!(!s.advance(1000));
+        assert_eq!(None, s.pop_due());
+        assert!(s.advance(1000));
+        assert_eq!(Some(Event::VerticalBlank), s.pop_due());
+        assert_eq!(None, s.pop_due());
+        assert!(!s.advance(1000));
+    }
+
+    #[test]
+    fn due_events_are_returned_earliest_first() {
+        let mut s = Scheduler::new();
+
+        s.schedule(Event::VerticalBlank, 100);
+        s.schedule(Event::DuartRxB, 200);
+        s.schedule(Event::DuartTxA, 200);
+        s.schedule(Event::DuartRxA, 5000);
+
+        assert!(s.advance(1000));
+        assert_eq!(Some(Event::VerticalBlank), s.pop_due());
+        assert_eq!(Some(Event::DuartTxA), s.pop_due());
+        assert_eq!(Some(Event::DuartRxB), s.pop_due());
+        assert_eq!(None, s.pop_due());
+        assert!(s.advance(4000));
+        assert_eq!(Some(Event::DuartRxA), s.pop_due());
+    }
+
+    #[test]
+    fn rescheduling_replaces_the_deadline() {
+        let mut s = Scheduler::new();
+
+        s.schedule(Event::DuartTxB, 100);
+        s.schedule(Event::DuartTxB, 2000);
+
+        assert!(!s.advance(1000));
+        assert!(s.advance(1000));
+        assert_eq!(Some(Event::DuartTxB), s.pop_due());
+    }
+
+    #[test]
+    fn cancelled_events_never_fire() {
+        let mut s = Scheduler::new();
+
+        s.schedule(Event::DuartRxA, 100);
+        s.cancel(Event::DuartRxA);
+
+        assert!(!s.advance(1000));
+        assert_eq!(None, s.pop_due());
+    }
+}
@@ -0,0 +1,557 @@
+//! Driving the terminal from tests.
+//!
+//! A `Driver` works the terminal as a person sitting at it would: it
+//! types, clicks, and watches the screen and the RS232 line, waiting
+//! for what it expects for no longer than it is told to. Everything it
+//! does is queued as input at the current emulated time, and waits are
+//! measured in emulated time, so a test runs the same way every time,
+//! however fast the host is.
+//!
+//! Scripts drive a terminal from a text file, one command to a line.
+//! Strings are in double quotes, with `\n`, `\r`, `\t`, `\\`, `\"` and
+//! `\xNN` escapes. Durations take a unit of `ns`, `us`, `ms` or `s`.
+//! Regions are given as x, y, width and height in pixels, and hashes
+//! in hex. A failed assertion reports the hash found, which is the
+//! easiest way to find the hash to expect.
+//!
+//! ```text
+//! # Log in, and check the prompt appears
+//! boot 2
+//! run 5s
+//! wait_output "login: " 10s
+//! type "dmd\n"
+//! click 400 512
+//! input key_press shift PF1
+//! wait_region 0 0 800 32 3d2e1b0c 2s
+//! assert_screen 77a1c3f4
+//! ```
+
+use crate::display::Rect;
+use crate::dmd::Dmd;
+use crate::err::BusError;
+use crate::input::{Input, InputError};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+const COMMANDS: [&str; 10] = [
+    "boot",
+    "run",
+    "type",
+    "send",
+    "click",
+    "input",
+    "wait_output",
+    "wait_region",
+    "assert_screen",
+    "assert_region",
+];
+
+/// How often a wait checks for what it is waiting for, in nanoseconds
+/// of emulated time.
+const POLL_NS: u64 = 10_000_000;
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Bus(#[from] BusError),
+    #[error("line {0}: {1}")]
+    Syntax(usize, String),
+    #[error("timed out after {waited}ns waiting for {what}")]
+    Timeout {
+        what: String,
+        waited: u64,
+    },
+    #[error("{what} hash is {found:08x}, expected {expected:08x}")]
+    Mismatch {
+        what: String,
+        expected: u32,
+        found: u32,
+    },
+    #[error("line {0}: {1}")]
+    Failed(usize, Box<ScriptError>),
+}
+
+/// One line of a script.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    /// Power on a new terminal running a ROM version.
+    Boot(u8),
+    /// Run for a time, in nanoseconds.
+    Run(u64),
+    /// Type ASCII text on the keyboard.
+    Type(String),
+    /// Send characters to the terminal on the RS232 port.
+    Send(Vec<u8>),
+    /// Click a mouse button at a point on the screen.
+    Click(i32, i32, u8),
+    /// Make any input, as written by the `input` module.
+    Input(Input),
+    /// Wait for the terminal to send text on the RS232 port.
+    WaitOutput(Vec<u8>, u64),
+    /// Wait for a region of the screen to have a hash.
+    WaitRegion(Rect, u32, u64),
+    AssertScreen(u32),
+    AssertRegion(Rect, u32),
+}
+
+/// A terminal being driven by a test.
+pub struct Driver {
+    dmd: Dmd,
+    // RS232 output not yet matched by a wait.
+    output: Vec<u8>,
+}
+
+impl Driver {
+    /// Drive a terminal that has already been set up and reset.
+    pub fn new(dmd: Dmd) -> Driver {
+        Driver {
+            dmd,
+            output: Vec::new(),
+        }
+    }
+
+    /// Power on a new terminal running the given ROM version.
+    pub fn boot(version: u8) -> Result<Driver, BusError> {
+        let mut dmd = Dmd::new();
+        dmd.reset(version)?;
+        Ok(Driver::new(dmd))
+    }
+
+    pub fn dmd(&mut self) -> &mut Dmd {
+        &mut self.dmd
+    }
+
+    /// RS232 output not yet matched by `wait_for_output`.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn type_text(&mut self, text: &str) {
+        self.dmd.queue_text(self.dmd.now(), text);
+    }
+
+    /// Send characters to the terminal on the RS232 port.
+    pub fn send(&mut self, data: &[u8]) {
+        let now = self.dmd.now();
+        for c in data.iter() {
+            self.dmd.queue_input(now, Input::Rs232Rx(*c));
+        }
+    }
+
+    /// Move the mouse to a point on the screen, and click a button.
+    pub fn click(&mut self, x: i32, y: i32, button: u8) {
+        let now = self.dmd.now();
+        self.dmd.queue_input(now, Input::MouseMoveTo(x, y));
+        self.dmd.queue_input(now, Input::MouseDown(button));
+        self.dmd.queue_input(now, Input::MouseUp(button));
+    }
+
+    /// Run for a time, in nanoseconds.
+    pub fn run_for(&mut self, ns: u64) {
+        self.run_until(self.dmd.now().saturating_add(ns));
+    }
+
+    fn run_until(&mut self, at: u64) {
+        while self.dmd.now() < at {
+            self.dmd.step();
+        }
+        self.take_output();
+    }
+
+    fn take_output(&mut self) {
+        while let Some(c) = self.dmd.rs232_tx() {
+            self.output.push(c);
+        }
+    }
+
+    /// Run until `done` holds, for at most `timeout` nanoseconds.
+    /// Returns false if it timed out.
+    fn wait<F: FnMut(&mut Driver) -> bool>(&mut self, timeout: u64, mut done: F) -> bool {
+        let deadline = self.dmd.now().saturating_add(timeout);
+        self.take_output();
+        loop {
+            if done(self) {
+                return true;
+            }
+            let now = self.dmd.now();
+            if now >= deadline {
+                return false;
+            }
+            self.run_until(now.saturating_add(POLL_NS).min(deadline));
+        }
+    }
+
+    /// Wait for the terminal to send the given text on the RS232 port.
+    /// The output up to the end of the text is used up, so waiting
+    /// for the same text again waits for it to be sent again.
+    pub fn wait_for_output(&mut self, text: &[u8], timeout: u64) -> Result<(), ScriptError> {
+        let found = self.wait(timeout, |driver| {
+            let end = if text.is_empty() {
+                Some(0)
+            } else {
+                driver.output.windows(text.len()).position(|w| w == text).map(|i| i + text.len())
+            };
+            match end {
+                Some(end) => {
+                    driver.output.drain(..end);
+                    true
+                }
+                None => false,
+            }
+        });
+        if found {
+            Ok(())
+        } else {
+            Err(ScriptError::Timeout {
+                what: format!("output {:?}", String::from_utf8_lossy(text)),
+                waited: timeout,
+            })
+        }
+    }
+
+    /// Wait for a region of the screen to have the given hash.
+    pub fn wait_for_region(
+        &mut self,
+        region: &Rect,
+        hash: u32,
+        timeout: u64,
+    ) -> Result<(), ScriptError> {
+        if self.wait(timeout, |driver| driver.dmd.region_hash(region) == hash) {
+            Ok(())
+        } else {
+            Err(ScriptError::Timeout {
+                what: format!(
+                    "{} hash {:08x}, last {:08x}",
+                    describe(region),
+                    hash,
+                    self.dmd.region_hash(region)
+                ),
+                waited: timeout,
+            })
+        }
+    }
+
+    /// Check the hash of the whole screen.
+    pub fn assert_screen(&self, hash: u32) -> Result<(), ScriptError> {
+        let found = self.dmd.screen_hash();
+        if found == hash {
+            Ok(())
+        } else {
+            Err(ScriptError::Mismatch {
+                what: "screen".to_string(),
+                expected: hash,
+                found,
+            })
+        }
+    }
+
+    /// Check the hash of a region of the screen.
+    pub fn assert_region(&self, region: &Rect, hash: u32) -> Result<(), ScriptError> {
+        let found = self.dmd.region_hash(region);
+        if found == hash {
+            Ok(())
+        } else {
+            Err(ScriptError::Mismatch {
+                what: describe(region),
+                expected: hash,
+                found,
+            })
+        }
+    }
+
+    /// Carry out a command. `Boot` replaces the terminal with a new one.
+    pub fn execute(&mut self, command: &Command) -> Result<(), ScriptError> {
+        match command {
+            Command::Boot(version) => *self = Driver::boot(*version)?,
+            Command::Run(ns) => self.run_for(*ns),
+            Command::Type(text) => self.type_text(text),
+            Command::Send(data) => self.send(data),
+            Command::Click(x, y, button) => self.click(*x, *y, *button),
+            Command::Input(input) => self.dmd.queue_input(self.dmd.now(), *input),
+            Command::WaitOutput(text, timeout) => self.wait_for_output(text, *timeout)?,
+            Command::WaitRegion(region, hash, timeout) => {
+                self.wait_for_region(region, *hash, *timeout)?
+            }
+            Command::AssertScreen(hash) => self.assert_screen(*hash)?,
+            Command::AssertRegion(region, hash) => self.assert_region(region, *hash)?,
+        }
+        Ok(())
+    }
+}
+
+fn describe(region: &Rect) -> String {
+    format!("region {} {} {} {}", region.x, region.y, region.width, region.height)
+}
+
+/// A script, each command with its line number.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Script {
+    commands: Vec<(usize, Command)>,
+}
+
+impl Script {
+    /// Read a script. It must start by booting the terminal.
+    pub fn parse(text: &str) -> Result<Script, ScriptError> {
+        let mut commands = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let command = parse_command(line).map_err(|e| ScriptError::Syntax(i + 1, e))?;
+            commands.push((i + 1, command));
+        }
+
+        match commands.first() {
+            Some((_, Command::Boot(_))) => Ok(Script {
+                commands,
+            }),
+            Some((line, _)) => {
+                Err(ScriptError::Syntax(*line, "the script must start with boot".to_string()))
+            }
+            None => Err(ScriptError::Syntax(0, "the script is empty".to_string())),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Script, ScriptError> {
+        Script::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = &Command> {
+        self.commands.iter().map(|(_, command)| command)
+    }
+
+    /// Run the script on a new terminal, stopping at the first command
+    /// that fails. Returns the driver as it is at the end.
+    pub fn run(&self) -> Result<Driver, ScriptError> {
+        let mut driver = Driver::new(Dmd::new());
+        for (line, command) in self.commands.iter() {
+            driver.execute(command).map_err(|e| ScriptError::Failed(*line, Box::new(e)))?;
+        }
+        Ok(driver)
+    }
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+
+    // The input is the rest of the line, so that it may hold spaces
+    if name == "input" {
+        return rest.parse().map(Command::Input).map_err(|e: InputError| e.to_string());
+    }
+
+    let (string, rest) = match name {
+        "type" | "send" | "wait_output" => parse_string(rest)?,
+        _ => (Vec::new(), rest),
+    };
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    let bad = || format!("invalid arguments for {}", name);
+    let hash = |s: &str| u32::from_str_radix(s, 16).map_err(|_| format!("invalid hash {:?}", s));
+    let region = |args: &[&str]| -> Result<Rect, String> {
+        Ok(Rect {
+            x: parse_number(args[0])?,
+            y: parse_number(args[1])?,
+            width: parse_number(args[2])?,
+            height: parse_number(args[3])?,
+        })
+    };
+
+    let command = match (name, args.len()) {
+        ("boot", 1) => Command::Boot(parse_number(args[0])?),
+        ("run", 1) => Command::Run(parse_duration(args[0])?),
+        ("type", 0) => match String::from_utf8(string) {
+            Ok(text) if text.is_ascii() => Command::Type(text),
+            _ => return Err("only ASCII text can be typed".to_string()),
+        },
+        ("send", 0) => Command::Send(string),
+        ("click", 2) | ("click", 3) => {
+            let x = parse_number(args[0])?;
+            let y = parse_number(args[1])?;
+            let button = match args.get(2) {
+                Some(button) => parse_number(button)?,
+                None => 0,
+            };
+            Command::Click(x, y, button)
+        }
+        ("wait_output", 1) => Command::WaitOutput(string, parse_duration(args[0])?),
+        ("wait_region", 6) => {
+            Command::WaitRegion(region(&args)?, hash(args[4])?, parse_duration(args[5])?)
+        }
+        ("assert_screen", 1) => Command::AssertScreen(hash(args[0])?),
+        ("assert_region", 5) => Command::AssertRegion(region(&args)?, hash(args[4])?),
+        _ if COMMANDS.contains(&name) => return Err(bad()),
+        _ => return Err(format!("unknown command {:?}", name)),
+    };
+    Ok(command)
+}
+
+/// Parse a quoted string at the start of `s`, and return it with the
+/// rest of `s`.
+fn parse_string(s: &str) -> Result<(Vec<u8>, &str), String> {
+    let mut chars = s.strip_prefix('"').ok_or("expected a string")?.char_indices();
+    let mut string = Vec::new();
+    while let Some((i, c)) = chars.next() {
+        let c = match c {
+            '"' => return Ok((string, &s[i + 2..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => b'\n',
+                Some('r') => b'\r',
+                Some('t') => b'\t',
+                Some('\\') => b'\\',
+                Some('"') => b'"',
+                Some('x') => {
+                    let digits: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                    match u8::from_str_radix(&digits, 16) {
+                        Ok(c) if digits.len() == 2 => c,
+                        _ => return Err(format!("invalid escape \\x{}", digits)),
+                    }
+                }
+                _ => return Err("invalid escape".to_string()),
+            },
+            c => {
+                let mut buf = [0; 4];
+                string.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                continue;
+            }
+        };
+        string.push(c);
+    }
+    Err("unterminated string".to_string())
+}
+
+fn parse_number<T: FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid number {:?}", s))
+}
+
+/// Parse a duration with its unit, into nanoseconds.
+fn parse_duration(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid duration {:?}", s);
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (count, unit) = s.split_at(split);
+    let scale = match unit {
+        "ns" => 1,
+        "us" => 1_000,
+        "ms" => 1_000_000,
+        "s" => 1_000_000_000,
+        _ => return Err(invalid()),
+    };
+    count.parse::<u64>().ok().and_then(|n| n.checked_mul(scale)).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::{HEIGHT, WIDTH};
+
+    #[test]
+    fn parses_scripts() {
+        let script = Script::parse(
+            "# A comment\n\
+             boot 2\n\
+             run 5s\n\
+             type \"ls -l\\n\"\n\
+             send \"\\x1b[H\\\"\"\n\
+             click 400 512\n\
+             click 10 20 2\n\
+             input key_press shift PF1\n\
+             \n\
+             wait_output \"$ \" 250ms\n\
+             wait_region 0 0 800 32 3d2e1b0c 2s\n\
+             assert_screen 77a1c3f4\n\
+             assert_region 8 16 64 4 0000beef\n",
+        )
+        .unwrap();
+
+        let top = Rect {
+            x: 0,
+            y: 0,
+            width: 800,
+            height: 32,
+        };
+        let corner = Rect {
+            x: 8,
+            y: 16,
+            width: 64,
+            height: 4,
+        };
+        let expected = vec![
+            Command::Boot(2),
+            Command::Run(5_000_000_000),
+            Command::Type("ls -l\n".to_string()),
+            Command::Send(b"\x1b[H\"".to_vec()),
+            Command::Click(400, 512, 0),
+            Command::Click(10, 20, 2),
+            Command::Input("key_press shift PF1".parse().unwrap()),
+            Command::WaitOutput(b"$ ".to_vec(), 250_000_000),
+            Command::WaitRegion(top, 0x3d2e1b0c, 2_000_000_000),
+            Command::AssertScreen(0x77a1c3f4),
+            Command::AssertRegion(corner, 0xbeef),
+        ];
+        assert_eq!(expected, script.commands().cloned().collect::<Vec<_>>());
+
+        let line = |text: &str| match Script::parse(text) {
+            Err(ScriptError::Syntax(line, _)) => line,
+            _ => panic!("{:?} parsed", text),
+        };
+        assert_eq!(1, line("run 5s\n"));
+        assert_eq!(0, line("# Nothing\n"));
+        assert_eq!(2, line("boot 2\njump 3\n"));
+        assert_eq!(2, line("boot 2\nrun 5\n"));
+        assert_eq!(3, line("boot 2\n\ntype \"unterminated\n"));
+        assert_eq!(2, line("boot 2\nclick 1\n"));
+    }
+
+    #[test]
+    fn drives_the_terminal() {
+        let mut driver = Driver::boot(2).unwrap();
+        driver.run_for(5_000_000_000);
+        driver.type_text("ls\n");
+        driver.wait_for_output(b"ls", 1_000_000_000).unwrap();
+        assert!(matches!(
+            driver.wait_for_output(b"ls", 50_000_000),
+            Err(ScriptError::Timeout { .. })
+        ));
+
+        let hash = driver.dmd().screen_hash();
+        driver.assert_screen(hash).unwrap();
+        let screen = Rect {
+            x: 0,
+            y: 0,
+            width: WIDTH as u32,
+            height: HEIGHT as u32,
+        };
+        driver.wait_for_region(&screen, hash, 0).unwrap();
+        // The longest timeout a script can give
+        driver.wait_for_region(&screen, hash, u64::MAX).unwrap();
+        assert!(matches!(
+            driver.assert_region(&screen, !hash),
+            Err(ScriptError::Mismatch { found, .. }) if found == hash
+        ));
+    }
+
+    #[test]
+    fn reports_the_line_that_failed() {
+        let script = Script::parse(
+            "boot 2\n\
+             run 5s\n\
+             click 400 512\n\
+             type \"ls\\n\"\n\
+             wait_output \"ls\" 1s\n\
+             assert_screen 00000000\n",
+        )
+        .unwrap();
+        match script.run() {
+            Err(ScriptError::Failed(6, e)) => assert!(matches!(*e, ScriptError::Mismatch { .. })),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("the screen hash matched"),
+        }
+    }
+}
@@ -0,0 +1,403 @@
+//! Recording a session, and replaying it.
+//!
+//! A session log holds everything needed to run a session again from
+//! power-on: the ROM version, whether the I/O board is installed, the
+//! NVRAM as it was at the start, and each input with the emulated time
+//! at which it was applied. Mouse motion is logged as the counter
+//! values it produced, so that a replay does not depend on the pointer
+//! acceleration in use. With the input it holds what the terminal did
+//! in response: each character sent on the RS232 port, and a hash of
+//! the screen at each vertical blank where it changed, and at the end.
+//!
+//! Emulation is deterministic given its input, so replaying the input
+//! into a new terminal must reproduce the output exactly. Replay
+//! reports the first difference, which is where to start looking for
+//! whatever made the two runs diverge.
+//!
+//! Logs are text, one entry to a line. Inputs are written as described
+//! in the `input` module, after the time in nanoseconds:
+//!
+//! ```text
+//! version 2
+//! io_board 0
+//! nvram 0000...
+//! 16666000 frame 3d2e1b0c
+//! 41210000 rs232_rx 0d
+//! 43002000 rs232_tx 24
+//! end 50000000 77a1c3f4
+//! ```
+//!
+//! A log can be kept in memory, or written to a file as the session
+//! runs.
+
+use crate::dmd::Dmd;
+use crate::err::BusError;
+use crate::input::{Input, TimedInput};
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use log::error;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Bus(#[from] BusError),
+    #[error("a session must start at power-on")]
+    NotAtPowerOn,
+    #[error("line {0}: {1}")]
+    Syntax(usize, String),
+    #[error("replay differs at {at}ns: expected {expected}, found {found}")]
+    Mismatch {
+        at: u64,
+        expected: String,
+        found: String,
+    },
+}
+
+/// An entry in a session log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionEvent {
+    Input(Input),
+    /// A character sent on the RS232 port.
+    Rs232Tx(u8),
+    /// A hash of the screen at a vertical blank where it changed.
+    Frame(u32),
+}
+
+impl fmt::Display for SessionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionEvent::Input(input) => write!(f, "{}", input),
+            SessionEvent::Rs232Tx(c) => write!(f, "rs232_tx {:02x}", c),
+            SessionEvent::Frame(hash) => write!(f, "frame {:08x}", hash),
+        }
+    }
+}
+
+impl FromStr for SessionEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SessionEvent, String> {
+        if let Some(c) = s.strip_prefix("rs232_tx ") {
+            let c = u8::from_str_radix(c, 16).map_err(|_| format!("invalid character {:?}", c))?;
+            Ok(SessionEvent::Rs232Tx(c))
+        } else if let Some(hash) = s.strip_prefix("frame ") {
+            let hash =
+                u32::from_str_radix(hash, 16).map_err(|_| format!("invalid hash {:?}", hash))?;
+            Ok(SessionEvent::Frame(hash))
+        } else {
+            s.parse().map(SessionEvent::Input).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// A log entry and the emulated time, in nanoseconds since reset, at
+/// which it happened.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimedEvent {
+    pub at: u64,
+    pub event: SessionEvent,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Session {
+    pub version: u8,
+    pub io_board: bool,
+    pub nvram: Vec<u8>,
+    pub events: Vec<TimedEvent>,
+    /// The emulated time at which the session ended.
+    pub end: u64,
+    /// A hash of the screen at the end.
+    pub screen: u32,
+}
+
+impl Session {
+    /// Start an empty log for a session running the given ROM version
+    /// with the given NVRAM.
+    pub fn new(version: u8, io_board: bool, nvram: &[u8]) -> Session {
+        Session {
+            version,
+            io_board,
+            nvram: nvram.to_vec(),
+            events: Vec::new(),
+            end: 0,
+            screen: 0,
+        }
+    }
+
+    pub fn log(&mut self, at: u64, event: SessionEvent) {
+        self.events.push(TimedEvent {
+            at,
+            event,
+        });
+    }
+
+    /// The inputs in the log, in the order they were applied.
+    pub fn inputs(&self) -> impl Iterator<Item = TimedInput> + '_ {
+        self.events.iter().filter_map(|e| match e.event {
+            SessionEvent::Input(input) => Some(TimedInput {
+                at: e.at,
+                input,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Everything but the inputs in the log.
+    pub fn outputs(&self) -> impl Iterator<Item = &TimedEvent> {
+        self.events.iter().filter(|e| !matches!(e.event, SessionEvent::Input(_)))
+    }
+
+    /// Read a log written by `Display`.
+    pub fn parse(text: &str) -> Result<Session, SessionError> {
+        let mut version = None;
+        let mut io_board = None;
+        let mut nvram = None;
+        let mut end = None;
+        let mut events = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let syntax = |message: &str| SessionError::Syntax(i + 1, message.to_string());
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (first, rest) = line.split_once(' ').ok_or_else(|| syntax("expected a value"))?;
+            match first {
+                "version" => {
+                    version = Some(rest.parse().map_err(|_| syntax("invalid version"))?);
+                }
+                "io_board" => {
+                    io_board = match rest {
+                        "0" => Some(false),
+                        "1" => Some(true),
+                        _ => return Err(syntax("invalid I/O board")),
+                    };
+                }
+                "nvram" => {
+                    nvram = Some(parse_hex(rest).ok_or_else(|| syntax("invalid NVRAM"))?);
+                }
+                "end" => {
+                    let (at, screen) =
+                        rest.split_once(' ').ok_or_else(|| syntax("expected a time and hash"))?;
+                    let at = at.parse().map_err(|_| syntax("invalid time"))?;
+                    let screen =
+                        u32::from_str_radix(screen, 16).map_err(|_| syntax("invalid hash"))?;
+                    end = Some((at, screen));
+                }
+                _ => {
+                    let at = first.parse().map_err(|_| syntax("invalid time"))?;
+                    let event = rest.parse().map_err(|e: String| syntax(&e))?;
+                    events.push(TimedEvent {
+                        at,
+                        event,
+                    });
+                }
+            }
+        }
+
+        let missing = |what: &str| SessionError::Syntax(0, format!("no {} line", what));
+        let (end, screen) = end.ok_or_else(|| missing("end"))?;
+        Ok(Session {
+            version: version.ok_or_else(|| missing("version"))?,
+            io_board: io_board.ok_or_else(|| missing("io_board"))?,
+            nvram: nvram.ok_or_else(|| missing("nvram"))?,
+            events,
+            end,
+            screen,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Session, SessionError> {
+        Session::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Run the session again in a new terminal, feeding it the logged
+    /// inputs at their times, and check that it does exactly what it
+    /// did before. Returns the terminal as it is at the end of the
+    /// session.
+    pub fn replay(&self) -> Result<Dmd, SessionError> {
+        let mut dmd = Dmd::new();
+        dmd.set_io_board(self.io_board);
+        dmd.set_nvram(&self.nvram);
+        dmd.reset(self.version)?;
+        dmd.start_session()?;
+        for input in self.inputs() {
+            dmd.queue_input(input.at, input.input);
+        }
+        while dmd.now() < self.end {
+            dmd.step();
+        }
+
+        let replayed = match dmd.stop_session()? {
+            Some(session) => session,
+            None => return Err(SessionError::NotAtPowerOn),
+        };
+        let mut expected = self.outputs();
+        let mut found = replayed.outputs();
+        loop {
+            match (expected.next(), found.next()) {
+                (None, None) => break,
+                (e, f) if e == f => {}
+                (e, f) => {
+                    let describe = |event: Option<&TimedEvent>| match event {
+                        Some(event) => format!("{} at {}ns", event.event, event.at),
+                        None => "nothing".to_string(),
+                    };
+                    let at = e.into_iter().chain(f).map(|event| event.at).min();
+                    return Err(SessionError::Mismatch {
+                        at: at.unwrap_or(self.end),
+                        expected: describe(e),
+                        found: describe(f),
+                    });
+                }
+            }
+        }
+        if replayed.screen != self.screen {
+            return Err(SessionError::Mismatch {
+                at: self.end,
+                expected: format!("screen {:08x}", self.screen),
+                found: format!("screen {:08x}", replayed.screen),
+            });
+        }
+
+        Ok(dmd)
+    }
+
+    /// The lines of the log before the first entry.
+    fn header(&self) -> String {
+        let nvram: String = self.nvram.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("version {}\nio_board {}\nnvram {}\n", self.version, u8::from(self.io_board), nvram)
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.header())?;
+        for event in self.events.iter() {
+            writeln!(f, "{} {}", event.at, event.event)?;
+        }
+        writeln!(f, "end {} {:08x}", self.end, self.screen)
+    }
+}
+
+/// A session log written to a file entry by entry as the session runs,
+/// rather than held in memory until it ends.
+pub struct SessionWriter {
+    out: BufWriter<File>,
+    // The first write error, reported when the log is finished.
+    error: Option<io::Error>,
+}
+
+impl SessionWriter {
+    /// Create the file, and write the start of the log of a session
+    /// with no entries yet.
+    pub fn create(path: &Path, session: &Session) -> io::Result<SessionWriter> {
+        let mut writer = SessionWriter {
+            out: BufWriter::new(File::create(path)?),
+            error: None,
+        };
+        writer.restart(session);
+        match writer.error.take() {
+            Some(e) => Err(e),
+            None => Ok(writer),
+        }
+    }
+
+    /// Throw away everything written so far, and start the log again
+    /// for a new session.
+    pub fn restart(&mut self, session: &Session) {
+        let result = self
+            .out
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.out.get_ref().set_len(0))
+            .and_then(|_| self.out.write_all(session.header().as_bytes()));
+        self.error = result.err();
+    }
+
+    /// Write an entry. After a write fails, the rest of the log is
+    /// dropped.
+    pub fn log(&mut self, at: u64, event: SessionEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = writeln!(self.out, "{} {}", at, event) {
+            error!("Unable to write session log: {}", e);
+            self.error = Some(e);
+        }
+    }
+
+    /// Write the end of the log and flush the file, reporting the
+    /// first error met while writing it.
+    pub fn finish(mut self, end: u64, screen: u32) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        writeln!(self.out, "end {} {:08x}", end, screen)?;
+        self.out.flush()
+    }
+}
+
+/// Where the entries of a session being logged go.
+pub(crate) enum SessionLog {
+    Memory(Session),
+    File(SessionWriter),
+}
+
+impl SessionLog {
+    pub fn log(&mut self, at: u64, event: SessionEvent) {
+        match self {
+            SessionLog::Memory(session) => session.log(at, event),
+            SessionLog::File(writer) => writer.log(at, event),
+        }
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_and_parses_logs() {
+        let mut session = Session::new(2, true, &[0x00, 0x5a, 0xff]);
+        session.log(12_000, SessionEvent::Input(Input::Rs232Rx(0x0d)));
+        session.log(16_666_000, SessionEvent::Frame(0x3d2e1b0c));
+        session.log(20_000_000, SessionEvent::Rs232Tx(0x24));
+        session.end = 50_000_000;
+        session.screen = 0x77a1c3f4;
+
+        let text = session.to_string();
+        assert_eq!(
+            "version 2\nio_board 1\nnvram 005aff\n12000 rs232_rx 0d\n16666000 frame 3d2e1b0c\n\
+             20000000 rs232_tx 24\nend 50000000 77a1c3f4\n",
+            text
+        );
+        assert_eq!(session, Session::parse(&text).unwrap());
+
+        assert!(matches!(
+            Session::parse("version 2\nio_board 0\nnvram 00\n12 jump 3\nend 50 0\n"),
+            Err(SessionError::Syntax(4, _))
+        ));
+        assert!(matches!(
+            Session::parse("version 2\nio_board 0\nnvram 00\n"),
+            Err(SessionError::Syntax(0, _))
+        ));
+    }
+}
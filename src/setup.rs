@@ -0,0 +1,527 @@
+//! Decoding and editing of the terminal's setup options, as stored in
+//! BBRAM by the firmware.
+//!
+//! The setup options occupy the 2048 bytes on the BBRAM's data lane,
+//! addressed here by their index on that lane. Both firmware versions
+//! share the same basic layout:
+//!
+//! ```text
+//!   0          Host speed
+//!   1          Return key
+//!   2          Newline handling
+//!   3          Duplex
+//!   4          Key tone
+//!   5          Screen background
+//!   6..414     PF keys f1-f8, 51 bytes each, NUL terminated
+//!   0x7fe      Checksum, high byte
+//!   0x7ff      Checksum, low byte
+//! ```
+//!
+//! The checksum is the sum of bytes 0 through 0x7fd, modulo 0x10000.
+//! Firmware version 2 adds options for the ports on the optional I/O
+//! board and for routing the printer and auxiliary ports, which are
+//! decoded into `ExtendedSetup`.
+//!
+//! Options that are not decoded here are left untouched when a
+//! `Setup` is written back into an image.
+
+use crate::bbram::{BBRAM_SIZE, DATA_LANE};
+
+use thiserror::Error;
+
+/// The number of setup bytes held on the BBRAM's data lane.
+pub const SETUP_LEN: usize = BBRAM_SIZE / 4;
+
+pub const PF_KEY_COUNT: usize = 8;
+/// The longest definition a PF key may hold, excluding its NUL.
+pub const PF_KEY_LEN: usize = 50;
+
+const HOST_SPEED: usize = 0;
+const RETURN_KEY: usize = 1;
+const NEWLINE: usize = 2;
+const DUPLEX: usize = 3;
+const KEY_TONE: usize = 4;
+const BACKGROUND: usize = 5;
+const PF_KEYS: usize = 6;
+// Set to 1 by both firmware versions when they initialize a corrupt
+// BBRAM, and never changed by the setup screen. What it records is
+// not known.
+const INITIALIZED: usize = 417;
+const PORT_C_OPTIONS: usize = 425;
+const PORT_D_OPTIONS: usize = 435;
+const HOST_PORT: usize = 440;
+const PRINTER_PORT: usize = 441;
+const AUX1_PORT: usize = 442;
+const AUX2_PORT: usize = 443;
+const IO_BOARD: usize = 444;
+const CHECKSUM_HI: usize = 0x7fe;
+const CHECKSUM_LO: usize = 0x7ff;
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum SetupError {
+    #[error("setup image must be {BBRAM_SIZE} bytes, found {0}")]
+    Length(usize),
+    #[error("setup checksum {stored:04x} does not match contents {computed:04x}")]
+    Checksum {
+        stored: u16,
+        computed: u16,
+    },
+    #[error("setup option {name} has invalid value {value}")]
+    InvalidValue {
+        name: &'static str,
+        value: u8,
+    },
+    #[error("PF key f{} definition is longer than {PF_KEY_LEN} bytes", .0 + 1)]
+    PfKeyTooLong(usize),
+    #[error("PF key f{} definition contains a NUL byte, which would end it", .0 + 1)]
+    PfKeyContainsNul(usize),
+}
+
+/// Setup options whose values are an index into a list of choices.
+trait Choice: Sized + Copy + 'static {
+    const NAME: &'static str;
+    const CHOICES: &'static [Self];
+
+    fn decode(setup: &[u8], index: usize) -> Result<Self, SetupError> {
+        let value = setup[index];
+        Self::CHOICES.get(value as usize).copied().ok_or(SetupError::InvalidValue {
+            name: Self::NAME,
+            value,
+        })
+    }
+
+    fn code(self) -> u8;
+}
+
+macro_rules! choice {
+    ($t:ident, $name:expr, [$($variant:ident),+]) => {
+        impl Choice for $t {
+            const NAME: &'static str = $name;
+            const CHOICES: &'static [Self] = &[$($t::$variant),+];
+
+            fn code(self) -> u8 {
+                self as u8
+            }
+        }
+    };
+}
+
+/// Line speed, in the order the firmware numbers them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Speed {
+    B1200,
+    B2400,
+    B4800,
+    B9600,
+    B19200,
+    B300,
+}
+
+impl Speed {
+    pub fn baud(self) -> u32 {
+        match self {
+            Speed::B1200 => 1200,
+            Speed::B2400 => 2400,
+            Speed::B4800 => 4800,
+            Speed::B9600 => 9600,
+            Speed::B19200 => 19200,
+            Speed::B300 => 300,
+        }
+    }
+}
+
+choice!(Speed, "speed", [B1200, B2400, B4800, B9600, B19200, B300]);
+
+/// What the Return key sends to the host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReturnKey {
+    Cr,
+    Lf,
+    CrLf,
+}
+
+choice!(ReturnKey, "return key", [Cr, Lf, CrLf]);
+
+/// What a newline received from the host does: move down a line
+/// only, or also return to the first column.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Newline {
+    Index,
+    Newline,
+}
+
+choice!(Newline, "newline", [Index, Newline]);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Duplex {
+    Full,
+    Half,
+}
+
+choice!(Duplex, "duplex", [Full, Half]);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Background {
+    Dark,
+    Light,
+}
+
+choice!(Background, "background", [Dark, Light]);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+choice!(Parity, "parity", [None, Odd, Even]);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CharSize {
+    Eight,
+    Seven,
+}
+
+choice!(CharSize, "bits/char", [Eight, Seven]);
+
+/// Electrical interface of an I/O board port.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Interface {
+    Rs232,
+    Rs422,
+}
+
+choice!(Interface, "type", [Rs232, Rs422]);
+
+/// A physical serial port. Ports A and B are on the DUART; ports C
+/// and D are SCC channels A and B on the optional I/O board.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Port {
+    A,
+    C,
+    D,
+    B,
+}
+
+choice!(Port, "port", [A, C, D, B]);
+
+/// Line settings for one of the I/O board's ports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortOptions {
+    pub speed: Speed,
+    pub parity: Parity,
+    pub char_size: CharSize,
+    pub interface: Interface,
+}
+
+impl PortOptions {
+    fn decode(setup: &[u8], index: usize) -> Result<PortOptions, SetupError> {
+        Ok(PortOptions {
+            speed: Speed::decode(setup, index)?,
+            parity: Parity::decode(setup, index + 1)?,
+            char_size: CharSize::decode(setup, index + 2)?,
+            interface: Interface::decode(setup, index + 3)?,
+        })
+    }
+
+    fn encode(&self, setup: &mut [u8], index: usize) {
+        setup[index] = self.speed.code();
+        setup[index + 1] = self.parity.code();
+        setup[index + 2] = self.char_size.code();
+        setup[index + 3] = self.interface.code();
+    }
+}
+
+/// Setup options only present in firmware version 2.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExtendedSetup {
+    /// Whether the firmware found the I/O board when it last probed
+    /// for it.
+    pub io_board: bool,
+    pub port_c: PortOptions,
+    pub port_d: PortOptions,
+    pub host_port: Port,
+    pub printer_port: Port,
+    pub aux1_port: Port,
+    pub aux2_port: Port,
+}
+
+/// The terminal's setup options.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Setup {
+    pub host_speed: Speed,
+    pub return_key: ReturnKey,
+    pub newline: Newline,
+    pub duplex: Duplex,
+    pub key_tone: bool,
+    pub background: Background,
+    pub pf_keys: [Vec<u8>; PF_KEY_COUNT],
+    /// Present when decoded for firmware version 2.
+    pub extended: Option<ExtendedSetup>,
+}
+
+impl Setup {
+    /// Decode the setup options from an image of the BBRAM window,
+    /// as laid out by the given firmware version.
+    pub fn decode(image: &[u8], version: u8) -> Result<Setup, SetupError> {
+        let setup = read_lane(image)?;
+
+        let computed = checksum(&setup);
+        let stored = u16::from(setup[CHECKSUM_HI]) << 8 | u16::from(setup[CHECKSUM_LO]);
+        if stored != computed {
+            return Err(SetupError::Checksum {
+                stored,
+                computed,
+            });
+        }
+
+        let mut pf_keys: [Vec<u8>; PF_KEY_COUNT] = Default::default();
+        for (i, key) in pf_keys.iter_mut().enumerate() {
+            let start = PF_KEYS + i * (PF_KEY_LEN + 1);
+            *key =
+                setup[start..start + PF_KEY_LEN].iter().copied().take_while(|&c| c != 0).collect();
+        }
+
+        let extended = match version {
+            1 => None,
+            _ => Some(ExtendedSetup {
+                io_board: setup[IO_BOARD] != 0,
+                port_c: PortOptions::decode(&setup, PORT_C_OPTIONS)?,
+                port_d: PortOptions::decode(&setup, PORT_D_OPTIONS)?,
+                host_port: Port::decode(&setup, HOST_PORT)?,
+                printer_port: Port::decode(&setup, PRINTER_PORT)?,
+                aux1_port: Port::decode(&setup, AUX1_PORT)?,
+                aux2_port: Port::decode(&setup, AUX2_PORT)?,
+            }),
+        };
+
+        Ok(Setup {
+            host_speed: Speed::decode(&setup, HOST_SPEED)?,
+            return_key: ReturnKey::decode(&setup, RETURN_KEY)?,
+            newline: Newline::decode(&setup, NEWLINE)?,
+            duplex: Duplex::decode(&setup, DUPLEX)?,
+            key_tone: setup[KEY_TONE] == 0,
+            background: Background::decode(&setup, BACKGROUND)?,
+            pf_keys,
+            extended,
+        })
+    }
+
+    /// Write these options into an image of the BBRAM window, and
+    /// update its checksum. Options this module does not know about
+    /// keep their current values.
+    pub fn encode(&self, image: &mut [u8]) -> Result<(), SetupError> {
+        let mut setup = read_lane(image)?;
+
+        setup[HOST_SPEED] = self.host_speed.code();
+        setup[RETURN_KEY] = self.return_key.code();
+        setup[NEWLINE] = self.newline.code();
+        setup[DUPLEX] = self.duplex.code();
+        setup[KEY_TONE] = if self.key_tone {
+            0
+        } else {
+            1
+        };
+        setup[BACKGROUND] = self.background.code();
+
+        for (i, key) in self.pf_keys.iter().enumerate() {
+            if key.len() > PF_KEY_LEN {
+                return Err(SetupError::PfKeyTooLong(i));
+            }
+            if key.contains(&0) {
+                return Err(SetupError::PfKeyContainsNul(i));
+            }
+            let start = PF_KEYS + i * (PF_KEY_LEN + 1);
+            let slot = &mut setup[start..=start + PF_KEY_LEN];
+            slot.fill(0);
+            slot[..key.len()].copy_from_slice(key);
+        }
+
+        if let Some(ext) = &self.extended {
+            setup[IO_BOARD] = ext.io_board as u8;
+            ext.port_c.encode(&mut setup, PORT_C_OPTIONS);
+            ext.port_d.encode(&mut setup, PORT_D_OPTIONS);
+            setup[HOST_PORT] = ext.host_port.code();
+            setup[PRINTER_PORT] = ext.printer_port.code();
+            setup[AUX1_PORT] = ext.aux1_port.code();
+            setup[AUX2_PORT] = ext.aux2_port.code();
+        }
+
+        write_lane(image, &setup);
+        update_checksum(image)
+    }
+
+    /// Build the image the firmware writes when it finds the BBRAM
+    /// corrupt: every option at its first choice, and for version 2,
+    /// the printer and auxiliary ports on port B.
+    pub fn factory_image(version: u8) -> Vec<u8> {
+        let mut setup = vec![0; SETUP_LEN];
+        setup[INITIALIZED] = 1;
+
+        if version != 1 {
+            setup[PRINTER_PORT] = Port::B.code();
+            setup[AUX1_PORT] = Port::B.code();
+            setup[AUX2_PORT] = Port::B.code();
+        }
+
+        let mut image = vec![0; BBRAM_SIZE];
+        write_lane(&mut image, &setup);
+        // The image is the right size, so this cannot fail
+        let _ = update_checksum(&mut image);
+        image
+    }
+}
+
+/// Compute the checksum over the setup bytes.
+fn checksum(setup: &[u8]) -> u16 {
+    setup[..CHECKSUM_HI].iter().fold(0u16, |sum, &b| sum.wrapping_add(u16::from(b)))
+}
+
+/// Recompute and store the checksum of an image of the BBRAM window.
+pub fn update_checksum(image: &mut [u8]) -> Result<(), SetupError> {
+    let mut setup = read_lane(image)?;
+    let sum = checksum(&setup);
+    setup[CHECKSUM_HI] = (sum >> 8) as u8;
+    setup[CHECKSUM_LO] = sum as u8;
+    write_lane(image, &setup);
+    Ok(())
+}
+
+/// Gather the setup bytes from the data lane of an image.
+fn read_lane(image: &[u8]) -> Result<Vec<u8>, SetupError> {
+    if image.len() != BBRAM_SIZE {
+        return Err(SetupError::Length(image.len()));
+    }
+    Ok(image.iter().skip(DATA_LANE).step_by(4).copied().collect())
+}
+
+fn write_lane(image: &mut [u8], setup: &[u8]) {
+    for (dest, b) in image.iter_mut().skip(DATA_LANE).step_by(4).zip(setup) {
+        *dest = *b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_factory_images() {
+        let v1 = Setup::decode(&Setup::factory_image(1), 1).unwrap();
+        assert_eq!(Speed::B1200, v1.host_speed);
+        assert_eq!(ReturnKey::Cr, v1.return_key);
+        assert_eq!(Duplex::Full, v1.duplex);
+        assert!(v1.key_tone);
+        assert_eq!(Background::Dark, v1.background);
+        assert!(v1.pf_keys.iter().all(|k| k.is_empty()));
+        assert_eq!(None, v1.extended);
+
+        let v2 = Setup::decode(&Setup::factory_image(2), 2).unwrap();
+        let ext = v2.extended.unwrap();
+        assert!(!ext.io_board);
+        assert_eq!(Port::A, ext.host_port);
+        assert_eq!(Port::B, ext.printer_port);
+        assert_eq!(Speed::B1200, ext.port_c.speed);
+    }
+
+    /// The image left by the firmware after initializing a blank BBRAM
+    /// on a terminal without an I/O board.
+    fn firmware_image(version: u8) -> Vec<u8> {
+        let mut image = vec![0; BBRAM_SIZE];
+        image[0x686] = 1;
+        if version == 1 {
+            image[0x1ffe] = 0x01;
+        } else {
+            image[0x6e6] = 3;
+            image[0x6ea] = 3;
+            image[0x6ee] = 3;
+            image[0x1ffe] = 0x0a;
+        }
+        image
+    }
+
+    #[test]
+    fn decodes_image_written_by_firmware() {
+        let setup = Setup::decode(&firmware_image(2), 2).unwrap();
+        assert_eq!(Port::B, setup.extended.unwrap().aux2_port);
+    }
+
+    #[test]
+    fn builds_the_image_the_firmware_writes() {
+        assert_eq!(firmware_image(1), Setup::factory_image(1));
+        assert_eq!(firmware_image(2), Setup::factory_image(2));
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut image = Setup::factory_image(1);
+        image[2] = 3;
+
+        assert_eq!(
+            Err(SetupError::Checksum {
+                stored: 1,
+                computed: 4
+            }),
+            Setup::decode(&image, 1)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut image = Setup::factory_image(1);
+        image[0x16] = 2;
+        update_checksum(&mut image).unwrap();
+
+        assert_eq!(
+            Err(SetupError::InvalidValue {
+                name: "background",
+                value: 2
+            }),
+            Setup::decode(&image, 1)
+        );
+    }
+
+    #[test]
+    fn encodes_edits_and_preserves_unknown_options() {
+        let mut image = Setup::factory_image(2);
+        image[0x68a] = 5;
+        update_checksum(&mut image).unwrap();
+
+        let mut setup = Setup::decode(&image, 2).unwrap();
+        setup.host_speed = Speed::B9600;
+        setup.background = Background::Light;
+        setup.key_tone = false;
+        setup.pf_keys[1] = b"ls -l\r".to_vec();
+        setup.extended.as_mut().unwrap().port_d.parity = Parity::Even;
+        setup.encode(&mut image).unwrap();
+
+        // Host speed, background and tone, on the data lane
+        assert_eq!(3, image[0x02]);
+        assert_eq!(1, image[0x16]);
+        assert_eq!(1, image[0x12]);
+        // f2 starts at index 57
+        assert_eq!(b'l', image[57 * 4 + 2]);
+        assert_eq!(5, image[0x68a]);
+
+        assert_eq!(setup, Setup::decode(&image, 2).unwrap());
+    }
+
+    #[test]
+    fn refuses_overlong_pf_keys() {
+        let mut image = Setup::factory_image(1);
+        let mut setup = Setup::decode(&image, 1).unwrap();
+        setup.pf_keys[7] = vec![b'x'; PF_KEY_LEN + 1];
+
+        assert_eq!(Err(SetupError::PfKeyTooLong(7)), setup.encode(&mut image));
+    }
+
+    #[test]
+    fn refuses_pf_keys_containing_nul() {
+        let mut image = Setup::factory_image(1);
+        let mut setup = Setup::decode(&image, 1).unwrap();
+        setup.pf_keys[2] = b"ls\0-l".to_vec();
+
+        assert_eq!(Err(SetupError::PfKeyContainsNul(2)), setup.encode(&mut image));
+    }
+}
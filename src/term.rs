@@ -0,0 +1,185 @@
+//! Rendering the display in an ordinary text terminal.
+//!
+//! The display can be drawn with Unicode braille characters, each of
+//! which shows a block of two by four dots, or as a sixel graphics
+//! stream for terminals that support it. Either may be scaled down:
+//! at a scale of `n`, each dot stands for a square of `n` by `n`
+//! pixels, and is drawn if any of them is lit, so that thin lines do
+//! not disappear.
+
+use crate::display::{HEIGHT, STRIDE, WIDTH};
+
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TextMode {
+    Braille,
+    Sixel,
+}
+
+/// Render a framebuffer as text. A scale of 0 is taken as 1.
+pub fn render(framebuffer: &[u8], inverted: bool, mode: TextMode, scale: usize) -> String {
+    let dots = Dots::new(framebuffer, inverted, scale.max(1));
+    match mode {
+        TextMode::Braille => braille(&dots),
+        TextMode::Sixel => sixel(&dots),
+    }
+}
+
+/// The display, scaled down to dots.
+struct Dots<'a> {
+    framebuffer: &'a [u8],
+    inverted: bool,
+    scale: usize,
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Dots<'a> {
+    fn new(framebuffer: &'a [u8], inverted: bool, scale: usize) -> Dots<'a> {
+        Dots {
+            framebuffer,
+            inverted,
+            scale,
+            width: (WIDTH + scale - 1) / scale,
+            height: (HEIGHT + scale - 1) / scale,
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> bool {
+        let byte = self.framebuffer.get(y * STRIDE + x / 8).copied().unwrap_or(0);
+        (byte & (0x80 >> (x % 8)) != 0) != self.inverted
+    }
+
+    /// True if the dot is drawn. Dots outside the display never are.
+    fn get(&self, x: usize, y: usize) -> bool {
+        if x >= self.width || y >= self.height {
+            return false;
+        }
+        let xs = x * self.scale..((x + 1) * self.scale).min(WIDTH);
+        let ys = y * self.scale..((y + 1) * self.scale).min(HEIGHT);
+        ys.into_iter().any(|py| xs.clone().any(|px| self.pixel(px, py)))
+    }
+}
+
+/// Braille dot bits, indexed by row and then column within a cell.
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+fn braille(dots: &Dots) -> String {
+    let columns = (dots.width + 1) / 2;
+    let rows = (dots.height + 3) / 4;
+    let mut out = String::with_capacity(rows * (columns * 3 + 1));
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let mut bits = 0;
+            for (dy, line) in BRAILLE_DOTS.iter().enumerate() {
+                for (dx, bit) in line.iter().enumerate() {
+                    if dots.get(column * 2 + dx, row * 4 + dy) {
+                        bits |= bit;
+                    }
+                }
+            }
+            out.push(std::char::from_u32(0x2800 + bits).unwrap_or(' '));
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Append one color's sixels for a band, run length encoded.
+fn sixel_run(out: &mut String, sixels: &[u8]) {
+    let mut i = 0;
+    while i < sixels.len() {
+        let c = sixels[i];
+        let run = sixels[i..].iter().take_while(|s| **s == c).count();
+        let c = char::from(c + 0x3f);
+        if run > 3 {
+            let _ = write!(out, "!{}{}", run, c);
+        } else {
+            (0..run).for_each(|_| out.push(c));
+        }
+        i += run;
+    }
+}
+
+fn sixel(dots: &Dots) -> String {
+    let mut out = String::new();
+
+    // Square pixels, with a black and a white color register
+    let _ = write!(out, "\x1bP0;1;0q\"1;1;{};{}", dots.width, dots.height);
+    out.push_str("#0;2;0;0;0#1;2;100;100;100");
+
+    for band in 0..(dots.height + 5) / 6 {
+        let lit: Vec<u8> = (0..dots.width)
+            .map(|x| {
+                (0..6).fold(0, |bits, dy| {
+                    let y = band * 6 + dy;
+                    if dots.get(x, y) {
+                        bits | 1 << dy
+                    } else {
+                        bits
+                    }
+                })
+            })
+            .collect();
+        // Only the rows of the last band that are on the display
+        let rows = (dots.height - band * 6).min(6);
+        let unlit: Vec<u8> = lit.iter().map(|b| !b & ((1 << rows) - 1)).collect();
+
+        out.push_str("#0");
+        sixel_run(&mut out, &unlit);
+        out.push_str("$#1");
+        sixel_run(&mut out, &lit);
+        out.push('-');
+    }
+
+    out.push_str("\x1b\\");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::FRAMEBUFFER_LEN;
+
+    #[test]
+    fn renders_braille() {
+        let mut framebuffer = vec![0; FRAMEBUFFER_LEN];
+        framebuffer[0] = 0x80;
+        framebuffer[STRIDE * 3] = 0x40;
+
+        let text = render(&framebuffer, false, TextMode::Braille, 1);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(256, lines.len());
+        assert!(lines.iter().all(|l| l.chars().count() == 400));
+        assert_eq!(Some('\u{2881}'), lines[0].chars().next());
+        assert_eq!(Some('\u{2800}'), lines[0].chars().nth(1));
+
+        // Scaled down, and inverted
+        let text = render(&framebuffer, true, TextMode::Braille, 4);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(64, lines.len());
+        assert!(lines.iter().all(|l| l.chars().count() == 100));
+        assert_eq!(Some('\u{28ff}'), lines[0].chars().next());
+    }
+
+    #[test]
+    fn renders_sixel() {
+        let mut framebuffer = vec![0; FRAMEBUFFER_LEN];
+        framebuffer[STRIDE] = 0xc0;
+
+        let sixel = render(&framebuffer, false, TextMode::Sixel, 2);
+        assert!(sixel.starts_with("\x1bP0;1;0q\"1;1;400;512#0;2;0;0;0#1;2;100;100;100"));
+        assert!(sixel.ends_with("-\x1b\\"));
+        assert_eq!(86, sixel.matches('-').count());
+
+        // The pixels on the second scanline fall in the first dot,
+        // which is the lowest bit of the first sixel
+        let first = sixel.find("100;100;100").unwrap() + 11;
+        assert!(sixel[first..].starts_with("#0}!399~$#1@!399?-"));
+        // The last band has two rows
+        assert!(sixel.ends_with("#0!400B$#1!400?-\x1b\\"));
+    }
+}
@@ -0,0 +1,231 @@
+//! Reading text off the screen.
+//!
+//! The firmware draws text with a fixed width font of 9 by 14 pixel
+//! glyphs, which it keeps in ROM as a Blit `Font`: a bitmap strip of
+//! all the glyphs side by side, and a table giving each glyph's
+//! position in the strip and the rows it covers. Text is found by
+//! comparing every 9 by 14 window of the framebuffer against the
+//! printable glyphs, drawn either normally or in reverse video, so it
+//! is found wherever it was drawn, including inside windows that are
+//! not aligned to any grid.
+//!
+//! Glyphs found side by side on the same scanline are joined into
+//! lines, with the spaces between them filled in.
+
+use crate::display::{HEIGHT, STRIDE, WIDTH};
+use crate::rom_hi::{HI_ROM_V1, HI_ROM_V2};
+use crate::rom_lo::{LO_ROM_V1, LO_ROM_V2};
+
+use std::collections::HashMap;
+
+pub const GLYPH_WIDTH: usize = 9;
+pub const GLYPH_HEIGHT: usize = 14;
+
+const GLYPH_BITS: u32 = (GLYPH_WIDTH * GLYPH_HEIGHT) as u32;
+const GLYPH_MASK: u128 = (1 << GLYPH_BITS) - 1;
+const ROW_MASK: u16 = (1 << GLYPH_WIDTH) - 1;
+
+/// Addresses of the `Font` structure in the version 1 and version 2
+/// ROMs.
+const FONT_V1: usize = 0xae08;
+const FONT_V2: usize = 0x10180;
+/// Size of each `Fontchar` in the table that follows the `Font`.
+const FONTCHAR_LEN: usize = 8;
+
+/// A run of text found on the screen.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TextLine {
+    /// Pixel position of the top left corner of the first glyph.
+    pub x: usize,
+    pub y: usize,
+    pub text: String,
+}
+
+/// The glyphs of the firmware's font, keyed by their pixels.
+pub struct Font {
+    glyphs: HashMap<u128, char>,
+}
+
+impl Font {
+    /// The built-in font of the given firmware version.
+    pub fn builtin(version: u8) -> Font {
+        let (lo, hi, font): (&[u8], &[u8], usize) = match version {
+            1 => (&LO_ROM_V1, &HI_ROM_V1, FONT_V1),
+            _ => (&LO_ROM_V2, &HI_ROM_V2, FONT_V2),
+        };
+
+        // The ROM as the CPU sees it, with the high ROM after the low
+        let byte = |address: usize| match address.checked_sub(lo.len()) {
+            Some(address) => hi[address],
+            None => lo[address],
+        };
+        let word = |address: usize| (0..4).fold(0, |w, i| w << 8 | byte(address + i) as usize);
+
+        // The Font points at a Bitmap, which gives the strip's address
+        // and its width in 32-bit words.
+        let bitmap = word(font + 8);
+        let strip = word(bitmap);
+        let stride = word(bitmap + 4) * 4;
+        let bit = |x: usize, y: usize| byte(strip + y * stride + x / 8) & (0x80 >> (x % 8)) != 0;
+
+        let mut glyphs = HashMap::new();
+        for c in b'!'..=b'~' {
+            let info = font + 12 + usize::from(c) * FONTCHAR_LEN;
+            let left = usize::from(byte(info)) << 8 | usize::from(byte(info + 1));
+            let rows = usize::from(byte(info + 2))..usize::from(byte(info + 3));
+            let mut key = 0u128;
+            for y in 0..GLYPH_HEIGHT {
+                for x in left..left + GLYPH_WIDTH {
+                    key = key << 1 | (rows.contains(&y) && bit(x, y)) as u128;
+                }
+            }
+            glyphs.entry(key).or_insert(char::from(c));
+        }
+
+        Font {
+            glyphs,
+        }
+    }
+
+    /// The glyph drawn with exactly these pixels, normally or in
+    /// reverse video.
+    fn recognize(&self, key: u128) -> Option<char> {
+        self.glyphs.get(&key).or_else(|| self.glyphs.get(&(!key & GLYPH_MASK))).copied()
+    }
+
+    /// Find all the text on the screen, from top to bottom and left to
+    /// right.
+    pub fn read(&self, framebuffer: &[u8]) -> Vec<TextLine> {
+        let pixel = |x: usize, y: usize| {
+            let byte = framebuffer.get(y * STRIDE + x / 8).copied().unwrap_or(0);
+            byte & (0x80 >> (x % 8)) != 0
+        };
+
+        // The row of a glyph at every position on the screen
+        let columns = WIDTH - GLYPH_WIDTH + 1;
+        let mut rows = vec![0u16; columns * HEIGHT];
+        for y in 0..HEIGHT {
+            let mut row = 0u16;
+            for x in 0..WIDTH {
+                row = (row << 1 | pixel(x, y) as u16) & ROW_MASK;
+                if x + 1 >= GLYPH_WIDTH {
+                    rows[y * columns + x + 1 - GLYPH_WIDTH] = row;
+                }
+            }
+        }
+
+        // Glyphs may not overlap; the first found, scanning from the
+        // top left, wins.
+        let mut taken = vec![false; WIDTH * HEIGHT];
+        let mut found: Vec<(usize, usize, char)> = vec![];
+        for y in 0..=HEIGHT - GLYPH_HEIGHT {
+            for x in 0..columns {
+                if taken[y * WIDTH + x] {
+                    continue;
+                }
+                let key = (0..GLYPH_HEIGHT).fold(0u128, |key, dy| {
+                    key << GLYPH_WIDTH | u128::from(rows[(y + dy) * columns + x])
+                });
+                if key == 0 || key == GLYPH_MASK {
+                    continue;
+                }
+                let c = match self.recognize(key) {
+                    Some(c) => c,
+                    None => continue,
+                };
+                let overlaps = (y..y + GLYPH_HEIGHT)
+                    .any(|ty| (x..x + GLYPH_WIDTH).any(|tx| taken[ty * WIDTH + tx]));
+                if overlaps {
+                    continue;
+                }
+                for ty in y..y + GLYPH_HEIGHT {
+                    for tx in x..x + GLYPH_WIDTH {
+                        taken[ty * WIDTH + tx] = true;
+                    }
+                }
+                found.push((x, y, c));
+            }
+        }
+
+        found.sort_by_key(|(x, y, _)| (*y, *x));
+
+        let mut lines: Vec<TextLine> = vec![];
+        for (x, y, c) in found {
+            if let Some(line) = lines.last_mut() {
+                let end = line.x + line.text.len() * GLYPH_WIDTH;
+                if line.y == y && x >= end && (x - end) % GLYPH_WIDTH == 0 {
+                    (0..(x - end) / GLYPH_WIDTH).for_each(|_| line.text.push(' '));
+                    line.text.push(c);
+                    continue;
+                }
+            }
+            lines.push(TextLine {
+                x,
+                y,
+                text: c.to_string(),
+            });
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::FRAMEBUFFER_LEN;
+
+    /// Draw a string with the font's glyphs, as the firmware would.
+    fn draw(font: &Font, framebuffer: &mut [u8], x: usize, y: usize, text: &str, reverse: bool) {
+        for (i, c) in text.chars().enumerate() {
+            let key = font.glyphs.iter().find(|(_, g)| **g == c).map(|(key, _)| *key).unwrap_or(0);
+            let key = if reverse {
+                !key & GLYPH_MASK
+            } else {
+                key
+            };
+            for dy in 0..GLYPH_HEIGHT {
+                for dx in 0..GLYPH_WIDTH {
+                    let bit = GLYPH_BITS as usize - 1 - (dy * GLYPH_WIDTH + dx);
+                    if key >> bit & 1 != 0 {
+                        let (px, py) = (x + i * GLYPH_WIDTH + dx, y + dy);
+                        framebuffer[py * STRIDE + px / 8] |= 0x80 >> (px % 8);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn loads_both_fonts() {
+        for version in [1, 2].iter() {
+            let font = Font::builtin(*version);
+            assert_eq!(94, font.glyphs.len());
+        }
+    }
+
+    #[test]
+    fn reads_text() {
+        let font = Font::builtin(2);
+        let mut framebuffer = vec![0; FRAMEBUFFER_LEN];
+        draw(&font, &mut framebuffer, 3, 3, "Hello,", false);
+        draw(&font, &mut framebuffer, 3 + 7 * GLYPH_WIDTH, 3, "world!", false);
+        draw(&font, &mut framebuffer, 101, 500, "OK", true);
+
+        assert_eq!(
+            vec![
+                TextLine {
+                    x: 3,
+                    y: 3,
+                    text: "Hello, world!".to_string(),
+                },
+                TextLine {
+                    x: 101,
+                    y: 500,
+                    text: "OK".to_string(),
+                },
+            ],
+            font.read(&framebuffer)
+        );
+    }
+}
@@ -4,6 +4,7 @@ const FIFO_LEN: usize = 3;
 
 /// A simple circular buffer with three slots, used as a
 /// DUART character FIFO
+#[derive(Clone)]
 pub struct FifoQueue {
     buf: [u8; 3],
     read_ptr: usize,
@@ -0,0 +1,772 @@
+//! A VNC (RFB) server, serving the display to any VNC client.
+//!
+//! The server speaks protocol versions 3.3, 3.7 and 3.8, without
+//! authentication, and sends framebuffer updates in the raw encoding
+//! in whatever true color pixel format the client asks for. Frames
+//! are read through a `FrameReader`, so serving them never stops the
+//! emulator. Only the region that changed since the last update to a
+//! client is sent to it.
+//!
+//! Key events are translated to X11 keysym names and pressed through
+//! the emulator's keyboard layout. Pointer events move the mouse, and
+//! pressing and releasing the left, middle and right buttons presses
+//! and releases mouse buttons 0, 1 and 2.
+//!
+//! Each client is served by a pair of threads: one reads and parses
+//! client messages, and the other applies them and sends updates.
+
+use crate::display::{changed_region, FrameReader, FRAMEBUFFER_LEN, HEIGHT, STRIDE, WIDTH};
+use crate::dmd::{Dmd, DMD, ERROR, SUCCESS};
+use crate::keyboard::Modifiers;
+
+use libc::c_int;
+use log::{debug, error, info};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often to check for new frames and for the server stopping.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const DESKTOP_NAME: &[u8] = b"AT&T DMD 5620";
+
+// Client to server message types
+const SET_PIXEL_FORMAT: u8 = 0;
+const SET_ENCODINGS: u8 = 2;
+const FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const KEY_EVENT: u8 = 4;
+const POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+const SECURITY_NONE: u8 = 1;
+const ENCODING_RAW: i32 = 0;
+
+// X11 keysyms of the modifier keys
+const XK_SHIFT_L: u32 = 0xffe1;
+const XK_SHIFT_R: u32 = 0xffe2;
+const XK_CONTROL_L: u32 = 0xffe3;
+const XK_CONTROL_R: u32 = 0xffe4;
+
+const XK_F1: u32 = 0xffbe;
+const XK_F12: u32 = 0xffc9;
+const XK_KP_0: u32 = 0xffb0;
+const XK_KP_9: u32 = 0xffb9;
+
+/// Names of the X11 keysyms, other than characters and function
+/// keys, that the standard keyboard layout binds.
+const KEYSYM_NAMES: [(u32, &str); 19] = [
+    (0xff08, "BackSpace"),
+    (0xff09, "Tab"),
+    (0xff0a, "Linefeed"),
+    (0xff0b, "Clear"),
+    (0xff0d, "Return"),
+    (0xff13, "Pause"),
+    (0xff1b, "Escape"),
+    (0xff50, "Home"),
+    (0xff51, "Left"),
+    (0xff52, "Up"),
+    (0xff53, "Right"),
+    (0xff54, "Down"),
+    (0xff57, "End"),
+    (0xff6b, "Break"),
+    (0xff8d, "KP_Enter"),
+    (0xffad, "KP_Subtract"),
+    (0xffae, "KP_Decimal"),
+    (0xffff, "Delete"),
+    (0x20, "space"),
+];
+
+/// The mouse buttons reported in a pointer event's button mask.
+const MOUSE_BUTTONS: u8 = 3;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_color: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// The format offered to clients: 32 bits, 8 bits per channel.
+    fn default() -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: 32,
+            depth: 24,
+            big_endian: false,
+            true_color: true,
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+        }
+    }
+
+    fn decode(b: &[u8]) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: b[0],
+            depth: b[1],
+            big_endian: b[2] != 0,
+            true_color: b[3] != 0,
+            red_max: u16::from_be_bytes([b[4], b[5]]),
+            green_max: u16::from_be_bytes([b[6], b[7]]),
+            blue_max: u16::from_be_bytes([b[8], b[9]]),
+            red_shift: b[10],
+            green_shift: b[11],
+            blue_shift: b[12],
+        }
+    }
+
+    fn encode(&self) -> [u8; 16] {
+        let mut b = [0; 16];
+        b[0] = self.bits_per_pixel;
+        b[1] = self.depth;
+        b[2] = self.big_endian as u8;
+        b[3] = self.true_color as u8;
+        b[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        b[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        b[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        b[10] = self.red_shift;
+        b[11] = self.green_shift;
+        b[12] = self.blue_shift;
+        b
+    }
+
+    fn is_supported(&self) -> bool {
+        self.true_color && [8, 16, 32].contains(&self.bits_per_pixel)
+    }
+
+    /// The bytes of a white pixel. A black pixel is all zero.
+    fn white(&self) -> Vec<u8> {
+        let value = u32::from(self.red_max) << self.red_shift
+            | u32::from(self.green_max) << self.green_shift
+            | u32::from(self.blue_max) << self.blue_shift;
+        let len = usize::from(self.bits_per_pixel / 8);
+        if self.big_endian {
+            value.to_be_bytes()[4 - len..].to_vec()
+        } else {
+            value.to_le_bytes()[..len].to_vec()
+        }
+    }
+}
+
+/// A parsed client message.
+enum Message {
+    SetPixelFormat(PixelFormat),
+    UpdateRequest {
+        incremental: bool,
+        x: Range<usize>,
+        y: Range<usize>,
+    },
+    Key {
+        down: bool,
+        keysym: u32,
+    },
+    Pointer {
+        buttons: u8,
+        x: u16,
+        y: u16,
+    },
+}
+
+/// The name of an X11 keysym, as sent by RFB clients, for looking
+/// up in the keyboard layout. Printable characters are named by
+/// themselves.
+fn keysym_name(keysym: u32) -> Option<String> {
+    if let Some((_, name)) = KEYSYM_NAMES.iter().find(|(k, _)| *k == keysym) {
+        return Some(name.to_string());
+    }
+    match keysym {
+        0x21..=0x7e => Some(char::from(keysym as u8).to_string()),
+        XK_F1..=XK_F12 => Some(format!("F{}", keysym - XK_F1 + 1)),
+        XK_KP_0..=XK_KP_9 => Some(format!("KP_{}", keysym - XK_KP_0)),
+        _ => None,
+    }
+}
+
+pub struct VncServer {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl VncServer {
+    /// Listen for clients on the given address, serving the display
+    /// of `dmd`, which may be `&DMD` or an `Arc<Mutex<Dmd>>`.
+    pub fn start<A, D>(addr: A, dmd: D) -> io::Result<VncServer>
+    where
+        A: ToSocketAddrs,
+        D: Deref<Target = Mutex<Dmd>> + Clone + Send + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+
+        let frames = match dmd.lock() {
+            Ok(dmd) => dmd.frame_reader(),
+            Err(poisoned) => poisoned.into_inner().frame_reader(),
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let listener_stop = Arc::clone(&stop);
+        let listener = thread::spawn(move || {
+            while !listener_stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        info!("VNC client connected from {}", peer);
+                        let mut client = Client::new(dmd.clone(), frames.clone());
+                        let stop = Arc::clone(&listener_stop);
+                        thread::spawn(move || {
+                            if let Err(e) = client.serve(stream, &stop) {
+                                debug!("VNC client {} disconnected: {}", peer, e);
+                            }
+                        });
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                    Err(e) => {
+                        error!("VNC server stopped: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        info!("VNC server listening on {}", addr);
+
+        Ok(VncServer {
+            addr,
+            stop,
+            listener: Some(listener),
+        })
+    }
+
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for VncServer {
+    /// Stop listening, and disconnect all clients.
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+    }
+}
+
+/// What a client has been sent of the screen, so that only what it
+/// has not seen is sent to it.
+struct SentScreen {
+    // The screen as the client holds it, with white pixels set.
+    pixels: Vec<u8>,
+    // Set when the client asks for the whole of a region, changed or
+    // not.
+    full: bool,
+    // The last frame the client holds all of.
+    sequence: u64,
+}
+
+impl SentScreen {
+    fn new() -> SentScreen {
+        SentScreen {
+            pixels: vec![0; FRAMEBUFFER_LEN],
+            full: false,
+            sequence: u64::MAX,
+        }
+    }
+
+    /// Send the whole of the next region requested.
+    fn request_full(&mut self) {
+        self.full = true;
+    }
+
+    fn up_to_date(&self, sequence: u64) -> bool {
+        !self.full && sequence == self.sequence
+    }
+
+    /// Find what is to be sent of `screen`, the frame numbered
+    /// `sequence`, within the requested region, and take it as sent.
+    /// Anything changed outside the region is left to send later.
+    fn take_update(
+        &mut self,
+        screen: &[u8],
+        sequence: u64,
+        x: Range<usize>,
+        y: Range<usize>,
+    ) -> Option<(Range<usize>, Range<usize>)> {
+        if self.up_to_date(sequence) {
+            return None;
+        }
+
+        let region = if self.full {
+            self.full = false;
+            Some((x, y))
+        } else {
+            changed_region(&self.pixels, screen).map(|(columns, rows)| {
+                (
+                    x.start.max(columns.start * 8)..x.end.min(columns.end * 8),
+                    y.start.max(rows.start)..y.end.min(rows.end),
+                )
+            })
+        };
+        let region = region.filter(|(x, y)| x.start < x.end && y.start < y.end);
+
+        if let Some((x, y)) = &region {
+            for row in y.clone() {
+                for col in x.clone() {
+                    let i = row * STRIDE + col / 8;
+                    let bit = 0x80 >> (col % 8);
+                    self.pixels[i] = (self.pixels[i] & !bit) | (screen[i] & bit);
+                }
+            }
+        }
+        if changed_region(&self.pixels, screen).is_none() {
+            self.sequence = sequence;
+        }
+        region
+    }
+}
+
+struct Client<D> {
+    dmd: D,
+    frames: FrameReader,
+    format: PixelFormat,
+    sent: SentScreen,
+    modifiers: Modifiers,
+    buttons: u8,
+}
+
+impl<D: Deref<Target = Mutex<Dmd>>> Client<D> {
+    fn new(dmd: D, frames: FrameReader) -> Client<D> {
+        Client {
+            dmd,
+            frames,
+            format: PixelFormat::default(),
+            sent: SentScreen::new(),
+            modifiers: Modifiers::NONE,
+            buttons: 0,
+        }
+    }
+
+    fn serve(&mut self, mut stream: TcpStream, stop: &AtomicBool) -> io::Result<()> {
+        // Accepted sockets may inherit the listener's non-blocking mode
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        self.handshake(&mut stream)?;
+
+        let (tx, rx) = mpsc::channel();
+        let mut reader = stream.try_clone()?;
+        thread::spawn(move || {
+            while let Ok(message) = read_message(&mut reader) {
+                if tx.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let result = self.run(&mut stream, &rx, stop);
+        // Unblock the reading thread
+        let _ = stream.shutdown(Shutdown::Both);
+        result
+    }
+
+    fn handshake(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        stream.write_all(b"RFB 003.008\n")?;
+        let mut version = [0; 12];
+        stream.read_exact(&mut version)?;
+        let minor = match &version[..] {
+            b"RFB 003.003\n" => 3,
+            b"RFB 003.007\n" => 7,
+            _ if version.starts_with(b"RFB 003.") => 8,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an RFB client")),
+        };
+
+        if minor == 3 {
+            stream.write_all(&u32::from(SECURITY_NONE).to_be_bytes())?;
+        } else {
+            stream.write_all(&[1, SECURITY_NONE])?;
+            let mut security = [0; 1];
+            stream.read_exact(&mut security)?;
+            if minor == 8 {
+                stream.write_all(&0u32.to_be_bytes())?;
+            }
+        }
+
+        // ClientInit, whose shared flag we ignore: all clients share
+        let mut shared = [0; 1];
+        stream.read_exact(&mut shared)?;
+
+        let mut init = Vec::with_capacity(24 + DESKTOP_NAME.len());
+        init.extend_from_slice(&(WIDTH as u16).to_be_bytes());
+        init.extend_from_slice(&(HEIGHT as u16).to_be_bytes());
+        init.extend_from_slice(&self.format.encode());
+        init.extend_from_slice(&(DESKTOP_NAME.len() as u32).to_be_bytes());
+        init.extend_from_slice(DESKTOP_NAME);
+        stream.write_all(&init)
+    }
+
+    fn run(
+        &mut self,
+        stream: &mut TcpStream,
+        rx: &Receiver<Message>,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        // The region of the outstanding update request, if any
+        let mut requested: Option<(Range<usize>, Range<usize>)> = None;
+
+        while !stop.load(Ordering::Relaxed) {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(Message::SetPixelFormat(format)) => {
+                    if !format.is_supported() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Unsupported pixel format {:?}", format),
+                        ));
+                    }
+                    self.format = format;
+                }
+                Ok(Message::UpdateRequest {
+                    incremental,
+                    x,
+                    y,
+                }) => {
+                    if !incremental {
+                        self.sent.request_full();
+                    }
+                    requested = Some((x, y));
+                }
+                Ok(Message::Key {
+                    down,
+                    keysym,
+                }) => self.key(down, keysym),
+                Ok(Message::Pointer {
+                    buttons,
+                    x,
+                    y,
+                }) => self.pointer(buttons, x, y),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Closed"))
+                }
+            }
+
+            if let Some((x, y)) = &requested {
+                if self.update(stream, x.clone(), y.clone())? {
+                    requested = None;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send whatever the client has not seen within the requested
+    /// region, if anything. Returns true if an update was sent.
+    fn update(
+        &mut self,
+        stream: &mut TcpStream,
+        x: Range<usize>,
+        y: Range<usize>,
+    ) -> io::Result<bool> {
+        let frame = self.frames.latest();
+        if self.sent.up_to_date(frame.sequence) {
+            return Ok(false);
+        }
+
+        let mask = if frame.inverted {
+            0xff
+        } else {
+            0
+        };
+        let screen: Vec<u8> = frame.data.iter().map(|b| b ^ mask).collect();
+
+        match self.sent.take_update(&screen, frame.sequence, x, y) {
+            Some((x, y)) => {
+                stream.write_all(&self.encode_update(&screen, x, y))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Encode a framebuffer update of a single raw rectangle.
+    fn encode_update(&self, screen: &[u8], x: Range<usize>, y: Range<usize>) -> Vec<u8> {
+        let white = self.format.white();
+        let black = vec![0; white.len()];
+
+        let mut out = Vec::with_capacity(16 + x.len() * y.len() * white.len());
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        for v in [x.start, y.start, x.len(), y.len()].iter() {
+            out.extend_from_slice(&(*v as u16).to_be_bytes());
+        }
+        out.extend_from_slice(&ENCODING_RAW.to_be_bytes());
+
+        for row in y {
+            for col in x.clone() {
+                let byte = screen[row * STRIDE + col / 8];
+                if byte & (0x80 >> (col % 8)) != 0 {
+                    out.extend_from_slice(&white);
+                } else {
+                    out.extend_from_slice(&black);
+                }
+            }
+        }
+        out
+    }
+
+    fn key(&mut self, down: bool, keysym: u32) {
+        match keysym {
+            XK_SHIFT_L | XK_SHIFT_R => self.modifiers.shift = down,
+            XK_CONTROL_L | XK_CONTROL_R => self.modifiers.control = down,
+            _ if down => {
+                if let (Some(name), Ok(mut dmd)) = (keysym_name(keysym), self.dmd.lock()) {
+                    dmd.key_press(&name, self.modifiers);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pointer(&mut self, buttons: u8, x: u16, y: u16) {
+        let mut dmd = match self.dmd.lock() {
+            Ok(dmd) => dmd,
+            Err(_) => return,
+        };
+
+        dmd.mouse_move_to(i32::from(x), i32::from(y));
+
+        for button in 0..MOUSE_BUTTONS {
+            let bit = 1 << button;
+            if buttons & bit != 0 && self.buttons & bit == 0 {
+                dmd.mouse_down(button);
+            } else if buttons & bit == 0 && self.buttons & bit != 0 {
+                dmd.mouse_up(button);
+            }
+        }
+        self.buttons = buttons;
+    }
+}
+
+/// Read a client message, skipping the ones we do not act on.
+fn read_message<R: Read>(r: &mut R) -> io::Result<Message> {
+    loop {
+        let mut kind = [0; 1];
+        r.read_exact(&mut kind)?;
+
+        match kind[0] {
+            SET_PIXEL_FORMAT => {
+                let mut b = [0; 19];
+                r.read_exact(&mut b)?;
+                return Ok(Message::SetPixelFormat(PixelFormat::decode(&b[3..])));
+            }
+            SET_ENCODINGS => {
+                let mut b = [0; 3];
+                r.read_exact(&mut b)?;
+                let count = u16::from_be_bytes([b[1], b[2]]) as usize;
+                io::copy(&mut r.take(count as u64 * 4), &mut io::sink())?;
+            }
+            FRAMEBUFFER_UPDATE_REQUEST => {
+                let mut b = [0; 9];
+                r.read_exact(&mut b)?;
+                let field = |i: usize| usize::from(u16::from_be_bytes([b[i], b[i + 1]]));
+                let (x, y) = (field(1).min(WIDTH), field(3).min(HEIGHT));
+                return Ok(Message::UpdateRequest {
+                    incremental: b[0] != 0,
+                    x: x..(x + field(5)).min(WIDTH),
+                    y: y..(y + field(7)).min(HEIGHT),
+                });
+            }
+            KEY_EVENT => {
+                let mut b = [0; 7];
+                r.read_exact(&mut b)?;
+                return Ok(Message::Key {
+                    down: b[0] != 0,
+                    keysym: u32::from_be_bytes([b[3], b[4], b[5], b[6]]),
+                });
+            }
+            POINTER_EVENT => {
+                let mut b = [0; 5];
+                r.read_exact(&mut b)?;
+                return Ok(Message::Pointer {
+                    buttons: b[0],
+                    x: u16::from_be_bytes([b[1], b[2]]),
+                    y: u16::from_be_bytes([b[3], b[4]]),
+                });
+            }
+            CLIENT_CUT_TEXT => {
+                let mut b = [0; 7];
+                r.read_exact(&mut b)?;
+                let len = u32::from_be_bytes([b[3], b[4], b[5], b[6]]);
+                io::copy(&mut r.take(u64::from(len)), &mut io::sink())?;
+            }
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown RFB message type {}", kind),
+                ))
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref SERVER: Mutex<Option<VncServer>> = Mutex::new(None);
+}
+
+/// Start serving the display over VNC on the given local TCP port,
+/// replacing any server already running.
+#[no_mangle]
+fn dmd_start_vnc(port: u16) -> c_int {
+    let mut server = match SERVER.lock() {
+        Ok(server) => server,
+        Err(_) => return ERROR,
+    };
+
+    // Release the port before binding it again
+    server.take();
+
+    match VncServer::start(("127.0.0.1", port), &*DMD) {
+        Ok(started) => {
+            *server = Some(started);
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[no_mangle]
+fn dmd_stop_vnc() -> c_int {
+    match SERVER.lock() {
+        Ok(mut server) => {
+            server.take();
+            SUCCESS
+        }
+        Err(_) => ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::Layout;
+
+    fn read_bytes(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn translates_keysyms() {
+        let layout = Layout::default();
+        let keycode = |keysym: u32, control: bool| {
+            let modifiers = Modifiers {
+                control,
+                ..Modifiers::NONE
+            };
+            keysym_name(keysym).and_then(|name| layout.keycode(&name, modifiers))
+        };
+
+        assert_eq!(Some(b'a'), keycode(u32::from(b'a'), false));
+        assert_eq!(Some(b'A'), keycode(u32::from(b'A'), false));
+        assert_eq!(Some(b' '), keycode(u32::from(b' '), false));
+        assert_eq!(Some(0x03), keycode(u32::from(b'c'), true));
+        assert_eq!(Some(b'1'), keycode(u32::from(b'1'), true));
+        assert_eq!(Some(0x0d), keycode(0xff0d, false));
+        assert_eq!(Some(0xe8), keycode(XK_F1, false));
+        assert_eq!(Some(0xef), keycode(XK_F1 + 7, false));
+        assert_eq!(Some(0xae), keycode(XK_F1 + 8, false));
+        assert_eq!(Some(0xc1), keycode(0xff52, false));
+        assert_eq!(Some(0xd2), keycode(XK_KP_0 + 7, false));
+        assert_eq!(None, keycode(XK_CONTROL_L, false));
+    }
+
+    #[test]
+    fn serves_the_framebuffer() {
+        let dmd = Arc::new(Mutex::new(Dmd::new()));
+        let server = VncServer::start("127.0.0.1:0", Arc::clone(&dmd)).unwrap();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+
+        assert_eq!(b"RFB 003.008\n".to_vec(), read_bytes(&mut stream, 12));
+        stream.write_all(b"RFB 003.008\n").unwrap();
+        assert_eq!(vec![1, SECURITY_NONE], read_bytes(&mut stream, 2));
+        stream.write_all(&[SECURITY_NONE]).unwrap();
+        assert_eq!(vec![0, 0, 0, 0], read_bytes(&mut stream, 4));
+        stream.write_all(&[1]).unwrap();
+
+        let init = read_bytes(&mut stream, 24);
+        assert_eq!(&[0x03, 0x20, 0x04, 0x00], &init[0..4]);
+        assert_eq!(PixelFormat::default(), PixelFormat::decode(&init[4..20]));
+        assert_eq!(DESKTOP_NAME.to_vec(), read_bytes(&mut stream, DESKTOP_NAME.len()));
+
+        // 8 bit BGR233
+        let format = [SET_PIXEL_FORMAT, 0, 0, 0, 8, 8, 0, 1, 0, 7, 0, 7, 0, 3, 0, 3, 6, 0, 0, 0];
+        stream.write_all(&format).unwrap();
+        stream.write_all(&[FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 4, 0, 1, 0, 16, 0, 2]).unwrap();
+
+        let update = read_bytes(&mut stream, 16);
+        assert_eq!(&[0, 0, 0, 1], &update[0..4]);
+        assert_eq!(&[0, 4, 0, 1, 0, 16, 0, 2], &update[4..12]);
+        assert_eq!(&ENCODING_RAW.to_be_bytes(), &update[12..16]);
+        assert_eq!(vec![0; 32], read_bytes(&mut stream, 32));
+
+        drop(server);
+        let mut rest = vec![];
+        assert_eq!(0, stream.read_to_end(&mut rest).unwrap());
+    }
+
+    #[test]
+    fn sends_changes_outside_the_requested_region_later() {
+        let mut sent = SentScreen::new();
+        let mut screen = vec![0; FRAMEBUFFER_LEN];
+
+        sent.request_full();
+        assert_eq!(Some((0..WIDTH, 0..HEIGHT)), sent.take_update(&screen, 1, 0..WIDTH, 0..HEIGHT));
+        assert_eq!(None, sent.take_update(&screen, 1, 0..WIDTH, 0..HEIGHT));
+
+        // A change across the first 64 pixels of a scanline, of which
+        // the first request covers only half
+        screen[10 * STRIDE..10 * STRIDE + 8].fill(0xff);
+        assert_eq!(Some((0..32, 10..11)), sent.take_update(&screen, 2, 0..32, 0..HEIGHT));
+        assert_eq!(None, sent.take_update(&screen, 2, 0..32, 0..HEIGHT));
+        assert_eq!(Some((32..64, 10..11)), sent.take_update(&screen, 2, 0..WIDTH, 0..HEIGHT));
+        assert_eq!(None, sent.take_update(&screen, 2, 0..WIDTH, 0..HEIGHT));
+
+        // A change wholly outside the requested region
+        screen[500 * STRIDE] = 0x80;
+        assert_eq!(None, sent.take_update(&screen, 3, 0..WIDTH, 0..100));
+        assert_eq!(Some((0..8, 500..501)), sent.take_update(&screen, 3, 0..WIDTH, 0..HEIGHT));
+    }
+
+    #[test]
+    fn encodes_pixels_in_the_client_format() {
+        let mut format = PixelFormat::default();
+        assert_eq!(vec![0xff, 0xff, 0xff, 0], format.white());
+
+        format.bits_per_pixel = 16;
+        format.big_endian = true;
+        format.red_max = 31;
+        format.green_max = 63;
+        format.blue_max = 31;
+        format.red_shift = 11;
+        format.green_shift = 5;
+        assert_eq!(vec![0xff, 0xff], format.white());
+    }
+}